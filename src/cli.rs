@@ -0,0 +1,467 @@
+//! Command-line options
+//!
+//! The initramfs `init` passes options straight through; keep parsing dependency-free.
+//! Flags fill an [`InstallConfig`] layer that overrides the `--config` file.
+
+use crate::config::InstallConfig;
+use crate::platform::command::RetryPolicy;
+use crate::platform::disks::parse_size;
+use crate::platform::systemd::{validate_target_name, validate_unit_name};
+use anyhow::{Result, anyhow};
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const USAGE: &str = "\
+Usage: truthdb-installer [OPTIONS]
+
+Options:
+  --config <FILE>         Load settings from a TOML (or .json) file; flags override it
+  --unattended <FILE>     Like --config, but never prompt: install, then reboot (or exit 1)
+  --disk <DEVICE>         Install to this disk (must be eligible), e.g. /dev/sda
+  --hostname <NAME>       Hostname of the installed system (default: truthdb01)
+  --force                 Allow the chosen disk (--disk or config) below the size floor
+  --allow-removable       Also offer removable disks (e.g. a USB SSD) as install targets
+  --min-disk-size <SIZE>  Size floor for eligible disks, e.g. 4G or 512M (default: 8G)
+  --auto-largest          Without --disk, pick the largest eligible disk if several qualify
+  --command-retries <N>   Tries for mount/umount/partprobe when the device is busy (default: 4)
+  --command-timeout <SECS>
+                          Kill a mount/umount/partprobe try after SECS (default: 60)
+  --verify-disk <DEVICE>  Check an existing install on DEVICE (read-only) and exit
+  --image <FILE>          Install into a loopback image file instead of a disk (testing)
+  --image-size <MIB>      Create/resize the --image file as a sparse file of this size
+  --payload-url <URL>     Download the rootfs payload over HTTP(S) instead of the ISO copy
+  --payload-sha256 <HEX>  Expected SHA-256 of the downloaded payload
+  --status-port <PORT>    Serve install progress as JSON over HTTP on PORT
+  --layout <plain|lvm>    Root directly on the partition, or on LVM (VG truthdb, LV root)
+  --discoverable-root     Type root with the DPS x86-64 root GUID (systemd auto-discovery)
+  --preserve-home         Reinstall over the existing ESP+root, keeping the /home partition
+  --root-size <MIB>       Cap the root partition (MiB); the rest stays unallocated
+  --swap-size <MIB>       Add a swap partition of this size (MiB) between the ESP and root
+  --parallel-mkfs         Format the ESP and root partitions concurrently
+  --esp-mkfs-opts <OPTS>  Extra mkfs.vfat options (space-separated)
+  --esp-label <LABEL>     FAT volume label of the ESP (default: EFI, max 11 chars)
+  --esp-volume-id <HEX>   FAT volume ID of the ESP (8 hex digits)
+  --root-label <LABEL>    Filesystem label of root (default: the hostname if given, else root)
+  --root-mkfs-opts <OPTS> Extra root mkfs options, e.g. \"-O ^has_journal\"
+  --bootloader <systemd-boot|grub>
+                          Bootloader for the installed system (default: systemd-boot)
+  --zram <SIZE>           Compressed swap in RAM, e.g. 50% or 2G (needs zram-generator)
+  --password-hash <HASH>  crypt(3) hash for the initial user (and root) password
+  --password-scheme <sha512|yescrypt>
+                          How a plaintext password is hashed (default: sha512)
+  --root-access <locked|password|unchanged>
+                          Root account policy for the installed system (default: password)
+  --passwordless-sudo     Let the initial user sudo without a password
+  --ip-family <v4|v6|dual>
+                          Address families configured via DHCP/SLAAC (default: dual)
+  --console-font <FONT>   Console font for the installed system, e.g. ter-v16n
+  --console-keymap <MAP>  Console keymap for the installed system, e.g. de
+  --default-target <TARGET>
+                          systemd default target, e.g. graphical.target or multi-user.target
+  --debs <DIR>            Install every .deb in DIR into the target (offline, via dpkg)
+  --regenerate-initramfs  Rebuild the target's initramfs (update-initramfs or dracut)
+  --network-backend <networkd|networkmanager>
+                          First-boot network service (default: NetworkManager if present)
+  --enable-unit <UNIT>    Enable a systemd unit in the installed system (repeatable)
+  --disable-unit <UNIT>   Disable a systemd unit in the installed system (repeatable)
+  --mask-unit <UNIT>      Mask a systemd unit in the installed system (repeatable)
+  -h, --help              Print this help";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    pub config_path: Option<PathBuf>,
+    /// Run without any prompts (the config came from `--unattended`).
+    pub unattended: bool,
+    /// Settings given as flags; these win over the config file.
+    pub overrides: InstallConfig,
+    pub help: bool,
+    /// Relax disk safety checks for an explicit `--disk`.
+    pub force: bool,
+    /// With several eligible disks, take the largest instead of refusing.
+    pub auto_largest: bool,
+    /// Install into this image file (attached as a loop device) instead of a disk.
+    pub image: Option<PathBuf>,
+    pub image_size_mib: Option<u64>,
+    /// Only verify the install already on this disk; nothing is written.
+    pub verify_disk: Option<PathBuf>,
+    /// Serve install progress as JSON on this TCP port.
+    pub status_port: Option<u16>,
+    /// Offer removable disks too.
+    pub allow_removable: bool,
+    /// Override the 8 GiB floor for eligible disks.
+    pub min_disk_size_bytes: Option<u64>,
+    /// Total tries for tools that race with udev (see [`RetryPolicy::RACY`]).
+    pub command_retries: Option<u32>,
+    /// Per-try time limit for those tools.
+    pub command_timeout_secs: Option<u64>,
+}
+
+impl Options {
+    /// [`RetryPolicy::RACY`] with the `--command-retries` / `--command-timeout` overrides.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let racy = RetryPolicy::RACY;
+        RetryPolicy {
+            attempts: self.command_retries.unwrap_or(racy.attempts),
+            timeout: self.command_timeout_secs.map(Duration::from_secs).or(racy.timeout),
+            ..racy
+        }
+    }
+}
+
+/// Parse arguments (without the program name). Both `--flag value` and `--flag=value` work.
+pub fn parse_args<I>(args: I) -> Result<Options>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut opts = Options::default();
+    let overrides = &mut opts.overrides;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg, None),
+        };
+        let mut value = || -> Result<String> {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| anyhow!("Missing value for {flag}"))
+        };
+
+        match flag.as_str() {
+            "--config" | "--unattended" => {
+                if opts.config_path.is_some() {
+                    return Err(anyhow!("Only one of --config and --unattended may be given"));
+                }
+                opts.config_path = Some(PathBuf::from(value()?));
+                opts.unattended = flag == "--unattended";
+            }
+            "--disk" => overrides.disk = Some(PathBuf::from(value()?)),
+            "--hostname" => overrides.hostname = Some(value()?),
+            "--verify-disk" => opts.verify_disk = Some(PathBuf::from(value()?)),
+            "--image" => opts.image = Some(PathBuf::from(value()?)),
+            "--image-size" => {
+                let raw = value()?;
+                let mib = raw.parse().map_err(|_| anyhow!("Invalid --image-size '{raw}' (MiB)"))?;
+                opts.image_size_mib = Some(mib);
+            }
+            "--payload-url" => overrides.payload_url = Some(value()?),
+            "--payload-sha256" => overrides.payload_sha256 = Some(value()?),
+            "--status-port" => {
+                let raw = value()?;
+                let port = raw
+                    .parse()
+                    .ok()
+                    .filter(|&port| port != 0)
+                    .ok_or_else(|| anyhow!("Invalid --status-port '{raw}'"))?;
+                opts.status_port = Some(port);
+            }
+            "--layout" => overrides.layout = Some(value()?.parse()?),
+            "--discoverable-root" => overrides.discoverable_root = Some(true),
+            "--preserve-home" => overrides.preserve_home = Some(true),
+            "--root-size" => {
+                let raw = value()?;
+                let mib = raw.parse().map_err(|_| anyhow!("Invalid --root-size '{raw}' (MiB)"))?;
+                overrides.root_size_mib = Some(mib);
+            }
+            "--swap-size" => {
+                let raw = value()?;
+                let mib = raw.parse().map_err(|_| anyhow!("Invalid --swap-size '{raw}' (MiB)"))?;
+                overrides.swap_size_mib = Some(mib);
+            }
+            "--parallel-mkfs" => overrides.parallel_mkfs = Some(true),
+            "--esp-mkfs-opts" => overrides.esp_mkfs_opts = Some(split_opts(&value()?)),
+            "--esp-label" => overrides.esp_label = Some(value()?),
+            "--esp-volume-id" => overrides.esp_volume_id = Some(value()?),
+            "--root-label" => overrides.root_label = Some(value()?),
+            "--root-mkfs-opts" => overrides.root_mkfs_opts = Some(split_opts(&value()?)),
+            "--bootloader" => overrides.bootloader = Some(value()?.parse()?),
+            "--zram" => overrides.zram = Some(value()?.parse()?),
+            "--password-hash" => overrides.password_hash = Some(value()?),
+            "--password-scheme" => overrides.password_scheme = Some(value()?.parse()?),
+            "--root-access" => overrides.root_access = Some(value()?.parse()?),
+            "--passwordless-sudo" => overrides.passwordless_sudo = Some(true),
+            "--ip-family" => overrides.ip_family = Some(value()?.parse()?),
+            "--console-font" => overrides.console_font = Some(value()?),
+            "--console-keymap" => overrides.console_keymap = Some(value()?),
+            "--default-target" => {
+                let target = value()?;
+                validate_target_name(&target)?;
+                overrides.default_target = Some(target);
+            }
+            "--debs" => overrides.debs = Some(PathBuf::from(value()?)),
+            "--regenerate-initramfs" => overrides.regenerate_initramfs = Some(true),
+            "--network-backend" => overrides.network_backend = Some(value()?.parse()?),
+            "--enable-unit" => overrides.enable_units.push(parse_unit(&value()?)?),
+            "--disable-unit" => overrides.disable_units.push(parse_unit(&value()?)?),
+            "--mask-unit" => overrides.mask_units.push(parse_unit(&value()?)?),
+            "--min-disk-size" => opts.min_disk_size_bytes = Some(parse_size(&value()?)?),
+            "--command-retries" => {
+                let raw = value()?;
+                let tries = raw
+                    .parse()
+                    .ok()
+                    .filter(|&tries| tries != 0)
+                    .ok_or_else(|| anyhow!("Invalid --command-retries '{raw}'"))?;
+                opts.command_retries = Some(tries);
+            }
+            "--command-timeout" => {
+                let raw = value()?;
+                let secs = raw
+                    .parse()
+                    .ok()
+                    .filter(|&secs| secs != 0)
+                    .ok_or_else(|| anyhow!("Invalid --command-timeout '{raw}' (seconds)"))?;
+                opts.command_timeout_secs = Some(secs);
+            }
+            "--allow-removable" => opts.allow_removable = true,
+            "--force" => opts.force = true,
+            "--auto-largest" => opts.auto_largest = true,
+            "-h" | "--help" => opts.help = true,
+            other => return Err(anyhow!("Unknown argument: {other}\n\n{USAGE}")),
+        }
+    }
+
+    Ok(opts)
+}
+
+fn split_opts(value: &str) -> Vec<String> {
+    value.split_whitespace().map(str::to_string).collect()
+}
+
+fn parse_unit(value: &str) -> Result<String> {
+    validate_unit_name(value)?;
+    Ok(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RootPolicy;
+    use crate::platform::crypt::PasswordScheme;
+    use crate::platform::install::{Bootloader, IpFamily, NetworkBackend};
+    use crate::platform::lvm::Layout;
+
+    fn parse(args: &[&str]) -> Result<Options> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn defaults_without_arguments() {
+        let opts = parse(&[]).unwrap();
+        assert_eq!(opts, Options::default());
+        assert_eq!(opts.overrides, InstallConfig::default());
+    }
+
+    #[test]
+    fn unattended_takes_the_config_file() {
+        let opts = parse(&["--unattended", "/media/install.toml"]).unwrap();
+        assert!(opts.unattended);
+        assert_eq!(opts.config_path, Some(PathBuf::from("/media/install.toml")));
+        assert!(!parse(&["--config", "a.toml"]).unwrap().unattended);
+        assert!(parse(&["--config", "a.toml", "--unattended", "b.toml"]).is_err());
+        assert!(parse(&["--unattended"]).is_err());
+    }
+
+    #[test]
+    fn root_access_accepts_both_value_forms() {
+        assert_eq!(
+            parse(&["--root-access", "locked"]).unwrap().overrides.root_access,
+            Some(RootPolicy::Locked)
+        );
+        assert_eq!(
+            parse(&["--root-access=unchanged"]).unwrap().overrides.root_access,
+            Some(RootPolicy::Unchanged)
+        );
+    }
+
+    #[test]
+    fn passwordless_sudo_flag() {
+        assert_eq!(
+            parse(&["--passwordless-sudo"]).unwrap().overrides.passwordless_sudo,
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn password_scheme_flag() {
+        assert_eq!(
+            parse(&["--password-scheme", "yescrypt"]).unwrap().overrides.password_scheme,
+            Some(PasswordScheme::Yescrypt)
+        );
+        assert!(parse(&["--password-scheme", "md5"]).is_err());
+    }
+
+    #[test]
+    fn unit_flags_are_repeatable() {
+        let opts = parse(&[
+            "--enable-unit",
+            "cockpit.socket",
+            "--enable-unit=ssh.service",
+            "--mask-unit",
+            "systemd-networkd-wait-online.service",
+        ])
+        .unwrap();
+        assert_eq!(opts.overrides.enable_units, vec!["cockpit.socket", "ssh.service"]);
+        assert!(opts.overrides.disable_units.is_empty());
+        assert_eq!(opts.overrides.mask_units, vec!["systemd-networkd-wait-online.service"]);
+        assert!(parse(&["--disable-unit", "../evil"]).is_err());
+    }
+
+    #[test]
+    fn ip_family_values() {
+        assert_eq!(parse(&["--ip-family", "v6"]).unwrap().overrides.ip_family, Some(IpFamily::V6));
+        assert!(parse(&["--ip-family", "v5"]).is_err());
+    }
+
+    #[test]
+    fn root_size_is_mib() {
+        assert_eq!(parse(&["--root-size=20480"]).unwrap().overrides.root_size_mib, Some(20480));
+        assert!(parse(&["--root-size", "20G"]).is_err());
+        assert_eq!(parse(&["--swap-size", "4096"]).unwrap().overrides.swap_size_mib, Some(4096));
+        assert!(parse(&["--swap-size", "4G"]).is_err());
+    }
+
+    #[test]
+    fn bootloader_values() {
+        assert_eq!(
+            parse(&["--bootloader", "grub"]).unwrap().overrides.bootloader,
+            Some(Bootloader::Grub)
+        );
+        assert!(parse(&["--bootloader", "lilo"]).is_err());
+    }
+
+    #[test]
+    fn regenerate_initramfs_flag() {
+        assert_eq!(
+            parse(&["--regenerate-initramfs"]).unwrap().overrides.regenerate_initramfs,
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn image_flags() {
+        let opts = parse(&["--image", "/tmp/disk.img", "--image-size=8192"]).unwrap();
+        assert_eq!(opts.image, Some(PathBuf::from("/tmp/disk.img")));
+        assert_eq!(opts.image_size_mib, Some(8192));
+        assert!(parse(&["--image-size", "8G"]).is_err());
+    }
+
+    #[test]
+    fn verify_disk_flag() {
+        assert_eq!(
+            parse(&["--verify-disk", "/dev/sda"]).unwrap().verify_disk,
+            Some(PathBuf::from("/dev/sda"))
+        );
+    }
+
+    #[test]
+    fn layout_values() {
+        assert_eq!(parse(&["--layout", "lvm"]).unwrap().overrides.layout, Some(Layout::Lvm));
+        assert!(parse(&["--layout", "raid"]).is_err());
+        assert_eq!(
+            parse(&["--discoverable-root"]).unwrap().overrides.discoverable_root,
+            Some(true)
+        );
+        assert_eq!(parse(&["--preserve-home"]).unwrap().overrides.preserve_home, Some(true));
+    }
+
+    #[test]
+    fn payload_url_flags() {
+        let opts =
+            parse(&["--payload-url", "http://10.0.0.1/p.tar.zst", "--payload-sha256=abc"]).unwrap();
+        assert_eq!(opts.overrides.payload_url.as_deref(), Some("http://10.0.0.1/p.tar.zst"));
+        assert_eq!(opts.overrides.payload_sha256.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn status_port() {
+        assert_eq!(parse(&["--status-port", "8080"]).unwrap().status_port, Some(8080));
+        assert!(parse(&["--status-port", "0"]).is_err());
+        assert!(parse(&["--status-port", "70000"]).is_err());
+    }
+
+    #[test]
+    fn console_flags() {
+        let opts = parse(&["--console-font=ter-v16n", "--console-keymap", "de"]).unwrap();
+        assert_eq!(opts.overrides.console_font.as_deref(), Some("ter-v16n"));
+        assert_eq!(opts.overrides.console_keymap.as_deref(), Some("de"));
+    }
+
+    #[test]
+    fn min_disk_size_flag() {
+        assert_eq!(parse(&["--min-disk-size", "4G"]).unwrap().min_disk_size_bytes, Some(4 << 30));
+        assert_eq!(parse(&["--min-disk-size=1048576"]).unwrap().min_disk_size_bytes, Some(1 << 20));
+        assert!(parse(&["--min-disk-size", "big"]).is_err());
+        assert!(parse(&["--allow-removable"]).unwrap().allow_removable);
+    }
+
+    #[test]
+    fn command_retry_flags() {
+        assert_eq!(parse(&[]).unwrap().retry_policy(), RetryPolicy::RACY);
+        let policy =
+            parse(&["--command-retries", "8", "--command-timeout=5"]).unwrap().retry_policy();
+        assert_eq!(policy.attempts, 8);
+        assert_eq!(policy.timeout, Some(Duration::from_secs(5)));
+        assert_eq!(policy.backoff, RetryPolicy::RACY.backoff);
+        assert!(parse(&["--command-retries", "0"]).is_err());
+        assert!(parse(&["--command-timeout", "soon"]).is_err());
+    }
+
+    #[test]
+    fn force_flag() {
+        assert!(parse(&["--force", "--disk", "/dev/vda"]).unwrap().force);
+        assert!(!parse(&[]).unwrap().force);
+        assert!(parse(&["--auto-largest"]).unwrap().auto_largest);
+    }
+
+    #[test]
+    fn default_target_must_be_a_target() {
+        assert_eq!(
+            parse(&["--default-target", "graphical.target"]).unwrap().overrides.default_target,
+            Some("graphical.target".to_string())
+        );
+        assert!(parse(&["--default-target", "graphical"]).is_err());
+    }
+
+    #[test]
+    fn network_backend_values() {
+        assert_eq!(
+            parse(&["--network-backend=networkmanager"]).unwrap().overrides.network_backend,
+            Some(NetworkBackend::NetworkManager)
+        );
+        assert!(parse(&["--network-backend", "connman"]).is_err());
+    }
+
+    #[test]
+    fn mkfs_opts_are_split_on_whitespace() {
+        let opts = parse(&["--root-mkfs-opts", "-O ^has_journal  -i 65536"]).unwrap();
+        assert_eq!(
+            opts.overrides.root_mkfs_opts,
+            Some(vec!["-O".into(), "^has_journal".into(), "-i".into(), "65536".into()])
+        );
+
+        let opts = parse(&["--esp-label", "TRUTHDB", "--esp-volume-id=1234abcd"]).unwrap();
+        assert_eq!(opts.overrides.esp_label.as_deref(), Some("TRUTHDB"));
+        assert_eq!(opts.overrides.esp_volume_id.as_deref(), Some("1234abcd"));
+        let opts = parse(&["--root-label", "truthdb-b"]).unwrap();
+        assert_eq!(opts.overrides.root_label.as_deref(), Some("truthdb-b"));
+    }
+
+    #[test]
+    fn config_and_overrides() {
+        let opts = parse(&["--config", "/etc/truthdb.toml", "--hostname", "db01"]).unwrap();
+        assert_eq!(opts.config_path, Some(PathBuf::from("/etc/truthdb.toml")));
+        assert_eq!(opts.overrides.hostname.as_deref(), Some("db01"));
+    }
+
+    #[test]
+    fn invalid_arguments_are_errors() {
+        assert!(parse(&["--root-access", "maybe"]).is_err());
+        assert!(parse(&["--root-access"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+    }
+}
@@ -0,0 +1,746 @@
+//! Install configuration
+//!
+//! Settings are layered: built-in defaults < `--config` file (TOML or JSON) < CLI flags.
+//! Everything is validated up front so a typo fails before the disk is touched.
+
+use crate::platform::crypt::{PasswordScheme, validate_crypt_hash};
+use crate::platform::install::{
+    Bootloader, Credential, DEFAULT_PASSWORD, DEFAULT_USERNAME, FormatOptions, IpFamily,
+    NetworkBackend, RootAccess, RootFs, UserSetup, ZramSize,
+};
+use crate::platform::lvm::Layout;
+use crate::platform::os_release::OsReleaseConfig;
+use crate::platform::partition::PartitionPlan;
+use crate::platform::payload::{validate_sha256, validate_url};
+use crate::platform::systemd::{UnitOverrides, validate_target_name, validate_unit_name};
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const DEFAULT_HOSTNAME: &str = "truthdb01";
+
+/// User-facing choice for the root account (the password itself comes from the user setup).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RootPolicy {
+    Password,
+    Locked,
+    Unchanged,
+}
+
+impl FromStr for RootPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "password" => Ok(RootPolicy::Password),
+            "locked" => Ok(RootPolicy::Locked),
+            "unchanged" => Ok(RootPolicy::Unchanged),
+            other => Err(anyhow!(
+                "Invalid root access '{other}' (expected locked, password, or unchanged)"
+            )),
+        }
+    }
+}
+
+/// One configuration layer. Unset fields fall through to the layer below.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InstallConfig {
+    pub hostname: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Pre-hashed crypt(3) password; mutually exclusive with `password`.
+    pub password_hash: Option<String>,
+    /// Scheme for hashing a plaintext `password` (sha512 or yescrypt).
+    pub password_scheme: Option<PasswordScheme>,
+    pub root_access: Option<RootPolicy>,
+    pub passwordless_sudo: Option<bool>,
+    /// Target disk, e.g. `/dev/sda`. Without it the single eligible disk is used.
+    pub disk: Option<PathBuf>,
+    /// Download the rootfs payload from this HTTP(S) URL instead of the install media.
+    pub payload_url: Option<String>,
+    /// Expected SHA-256 (hex) of the downloaded payload.
+    pub payload_sha256: Option<String>,
+    pub filesystem: Option<RootFs>,
+    /// `lvm` puts root on an LV instead of directly on the partition.
+    pub layout: Option<Layout>,
+    /// Type the root partition with the Discoverable Partitions root GUID.
+    pub discoverable_root: Option<bool>,
+    /// Reinstall over the existing ESP+root partitions and keep the `/home` partition.
+    pub preserve_home: Option<bool>,
+    /// Root partition size in MiB; unset uses the rest of the disk.
+    pub root_size_mib: Option<u64>,
+    /// Swap partition size in MiB; unset creates no swap partition.
+    pub swap_size_mib: Option<u64>,
+    pub bootloader: Option<Bootloader>,
+    /// Run mkfs for the ESP and root concurrently.
+    pub parallel_mkfs: Option<bool>,
+    /// Extra `mkfs.vfat` arguments, e.g. `["-s", "1"]`.
+    pub esp_mkfs_opts: Option<Vec<String>>,
+    /// ESP FAT volume label (default `EFI`).
+    pub esp_label: Option<String>,
+    /// Root filesystem label; defaults to the hostname when one is set, else `root`.
+    pub root_label: Option<String>,
+    /// ESP FAT volume ID, 8 hex digits (e.g. for reproducible images).
+    pub esp_volume_id: Option<String>,
+    /// Extra root mkfs arguments, e.g. `["-O", "^has_journal"]`.
+    pub root_mkfs_opts: Option<Vec<String>>,
+    /// zram swap size, e.g. `50%` or `2G`; unset means no zram.
+    pub zram: Option<ZramSize>,
+    pub timezone: Option<String>,
+    pub locale: Option<String>,
+    /// Console font for the installed system's TTYs, e.g. `ter-v16n`.
+    pub console_font: Option<String>,
+    /// Console keymap, e.g. `de`; checked against the payload's keymaps when it has any.
+    pub console_keymap: Option<String>,
+    pub ip_family: Option<IpFamily>,
+    /// Unset picks NetworkManager if the payload has it, else systemd-networkd.
+    pub network_backend: Option<NetworkBackend>,
+    pub ssh_authorized_key: Option<String>,
+    /// systemd default target, e.g. `graphical.target`; unset keeps the payload's.
+    pub default_target: Option<String>,
+    /// Directory of extra `.deb` packages to install offline.
+    pub debs: Option<PathBuf>,
+    /// Rebuild the target's initramfs after configuration (update-initramfs or dracut).
+    pub regenerate_initramfs: Option<bool>,
+    /// `/etc/os-release` branding; only the fields set here are replaced.
+    pub os_release: Option<OsReleaseConfig>,
+    pub enable_units: Vec<String>,
+    pub disable_units: Vec<String>,
+    pub mask_units: Vec<String>,
+}
+
+/// Fully resolved settings for one install.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallSettings {
+    pub hostname: String,
+    pub users: UserSetup,
+    pub disk: Option<PathBuf>,
+    pub payload_url: Option<String>,
+    pub payload_sha256: Option<String>,
+    pub format: FormatOptions,
+    pub layout: Layout,
+    pub discoverable_root: bool,
+    pub preserve_home: bool,
+    pub root_size_mib: Option<u64>,
+    pub swap_size_mib: Option<u64>,
+    pub bootloader: Bootloader,
+    pub zram: Option<ZramSize>,
+    pub timezone: Option<String>,
+    pub locale: Option<String>,
+    pub console_font: Option<String>,
+    pub console_keymap: Option<String>,
+    pub ip_family: IpFamily,
+    pub network_backend: Option<NetworkBackend>,
+    pub ssh_authorized_key: Option<String>,
+    pub default_target: Option<String>,
+    pub debs: Option<PathBuf>,
+    pub regenerate_initramfs: bool,
+    pub os_release: Option<OsReleaseConfig>,
+    pub units: UnitOverrides,
+}
+
+impl InstallSettings {
+    /// The decisions that shape the install, one per line, for review before the disk is wiped.
+    pub fn summary(&self) -> Vec<String> {
+        let root_size = match self.root_size_mib {
+            Some(mib) => format!("{mib} MiB"),
+            None => "rest of disk".to_string(),
+        };
+        let lvm = match self.layout {
+            Layout::Plain if self.discoverable_root => ", DPS root type",
+            Layout::Plain => "",
+            Layout::Lvm => ", on LVM truthdb/root",
+        };
+        let zram = match self.zram {
+            Some(ZramSize::Percent(pct)) => Some(format!("zram, {pct}% of RAM")),
+            Some(ZramSize::Mib(mib)) => Some(format!("zram, {mib} MiB")),
+            None => None,
+        };
+        let swap_partition = self.swap_size_mib.map(|mib| format!("{mib} MiB partition"));
+        let swap = match (zram, swap_partition) {
+            (Some(zram), Some(partition)) => format!("{zram} + {partition}"),
+            (Some(only), None) | (None, Some(only)) => only,
+            (None, None) => "none".to_string(),
+        };
+        let root = match &self.users.root_access {
+            RootAccess::Password(_) => "same password",
+            RootAccess::Locked => "locked",
+            RootAccess::Unchanged => "unchanged",
+        };
+        let sudo = if self.users.passwordless_sudo { "passwordless sudo" } else { "sudo" };
+        let family = match self.ip_family {
+            IpFamily::V4 => "IPv4",
+            IpFamily::V6 => "IPv6",
+            IpFamily::Dual => "IPv4+IPv6",
+        };
+        let backend = self.network_backend.map_or("auto-detected", NetworkBackend::name);
+
+        let partitions = if self.preserve_home {
+            format!(
+                "Partitions: existing ESP (FAT32) + root ({}{lvm}) reformatted, /home kept",
+                self.format.root_fs.fstype()
+            )
+        } else {
+            let swap_part =
+                self.swap_size_mib.map(|mib| format!(" + swap {mib} MiB")).unwrap_or_default();
+            format!(
+                "Partitions: ESP {} MiB (FAT32){swap_part} + root {root_size} ({}{lvm})",
+                PartitionPlan::default().esp_size_mib,
+                self.format.root_fs.fstype()
+            )
+        };
+
+        vec![
+            partitions,
+            format!("Swap: {swap}"),
+            format!("Hostname: {}", self.hostname),
+            format!("User: {} ({sudo}); root: {root}", self.users.username),
+            format!("Network: DHCP {family} via {backend}"),
+            format!("Bootloader: {}", self.bootloader.name()),
+            format!("Payload: {}", self.payload_url.as_deref().unwrap_or("install media")),
+        ]
+    }
+}
+
+impl InstallConfig {
+    /// Load a config file; `.json` files are parsed as JSON, anything else as TOML.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        let is_json = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        Self::parse(&contents, is_json)
+            .with_context(|| format!("Failed to parse config {}", path.display()))
+    }
+
+    pub fn parse(contents: &str, is_json: bool) -> Result<Self> {
+        if is_json { Ok(serde_json::from_str(contents)?) } else { Ok(toml::from_str(contents)?) }
+    }
+
+    /// Layer `over` on top of `self`: values set in `over` win, unit lists are appended.
+    pub fn merge(self, over: InstallConfig) -> InstallConfig {
+        let concat = |mut base: Vec<String>, extra: Vec<String>| {
+            base.extend(extra);
+            base
+        };
+        // A password from the upper layer replaces the lower one in either form.
+        let (password, password_hash) = if over.password.is_some() || over.password_hash.is_some() {
+            (over.password, over.password_hash)
+        } else {
+            (self.password, self.password_hash)
+        };
+        InstallConfig {
+            hostname: over.hostname.or(self.hostname),
+            username: over.username.or(self.username),
+            password,
+            password_hash,
+            root_access: over.root_access.or(self.root_access),
+            passwordless_sudo: over.passwordless_sudo.or(self.passwordless_sudo),
+            password_scheme: over.password_scheme.or(self.password_scheme),
+            disk: over.disk.or(self.disk),
+            payload_url: over.payload_url.or(self.payload_url),
+            payload_sha256: over.payload_sha256.or(self.payload_sha256),
+            filesystem: over.filesystem.or(self.filesystem),
+            layout: over.layout.or(self.layout),
+            discoverable_root: over.discoverable_root.or(self.discoverable_root),
+            preserve_home: over.preserve_home.or(self.preserve_home),
+            root_size_mib: over.root_size_mib.or(self.root_size_mib),
+            swap_size_mib: over.swap_size_mib.or(self.swap_size_mib),
+            bootloader: over.bootloader.or(self.bootloader),
+            parallel_mkfs: over.parallel_mkfs.or(self.parallel_mkfs),
+            esp_mkfs_opts: over.esp_mkfs_opts.or(self.esp_mkfs_opts),
+            esp_label: over.esp_label.or(self.esp_label),
+            root_label: over.root_label.or(self.root_label),
+            esp_volume_id: over.esp_volume_id.or(self.esp_volume_id),
+            root_mkfs_opts: over.root_mkfs_opts.or(self.root_mkfs_opts),
+            zram: over.zram.or(self.zram),
+            timezone: over.timezone.or(self.timezone),
+            locale: over.locale.or(self.locale),
+            console_font: over.console_font.or(self.console_font),
+            console_keymap: over.console_keymap.or(self.console_keymap),
+            ip_family: over.ip_family.or(self.ip_family),
+            network_backend: over.network_backend.or(self.network_backend),
+            ssh_authorized_key: over.ssh_authorized_key.or(self.ssh_authorized_key),
+            default_target: over.default_target.or(self.default_target),
+            debs: over.debs.or(self.debs),
+            regenerate_initramfs: over.regenerate_initramfs.or(self.regenerate_initramfs),
+            os_release: over.os_release.or(self.os_release),
+            enable_units: concat(self.enable_units, over.enable_units),
+            disable_units: concat(self.disable_units, over.disable_units),
+            mask_units: concat(self.mask_units, over.mask_units),
+        }
+    }
+
+    /// Apply defaults and validate every value.
+    pub fn resolve(self) -> Result<InstallSettings> {
+        let root_fs = self.filesystem.unwrap_or_default();
+        let root_label = self
+            .root_label
+            .or_else(|| self.hostname.as_deref().map(|h| root_fs.label_for_hostname(h)));
+        let hostname = self.hostname.unwrap_or_else(|| DEFAULT_HOSTNAME.to_string());
+        validate_hostname(&hostname)?;
+
+        let username = self.username.unwrap_or_else(|| DEFAULT_USERNAME.to_string());
+        validate_username(&username)?;
+
+        let password = match (self.password, self.password_hash) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!("Set either password or password_hash, not both"));
+            }
+            (Some(password), None) => {
+                validate_password(&password)?;
+                Credential::Plain(password)
+            }
+            (None, Some(hash)) => {
+                validate_crypt_hash(&hash)?;
+                Credential::Hashed(hash)
+            }
+            (None, None) => Credential::Plain(DEFAULT_PASSWORD.to_string()),
+        };
+
+        let root_access = match self.root_access.unwrap_or(RootPolicy::Password) {
+            RootPolicy::Password => RootAccess::Password(password.clone()),
+            RootPolicy::Locked => RootAccess::Locked,
+            RootPolicy::Unchanged => RootAccess::Unchanged,
+        };
+
+        if let Some(disk) = &self.disk
+            && !disk.starts_with("/dev/")
+        {
+            return Err(anyhow!("Invalid disk '{}' (expected a /dev path)", disk.display()));
+        }
+        if let Some(url) = &self.payload_url {
+            validate_url(url)?;
+        }
+        if let Some(hex) = &self.payload_sha256 {
+            if self.payload_url.is_none() {
+                return Err(anyhow!("payload_sha256 requires payload_url"));
+            }
+            validate_sha256(hex)?;
+        }
+        let format = FormatOptions {
+            root_fs,
+            parallel: self.parallel_mkfs.unwrap_or(false),
+            esp_mkfs_opts: self.esp_mkfs_opts.unwrap_or_default(),
+            root_mkfs_opts: self.root_mkfs_opts.unwrap_or_default(),
+            esp_label: self.esp_label,
+            esp_volume_id: self.esp_volume_id,
+            root_label,
+        };
+        format.validate()?;
+
+        let layout = self.layout.unwrap_or_default();
+        let discoverable_root = self.discoverable_root.unwrap_or(false);
+        if discoverable_root && layout == Layout::Lvm {
+            return Err(anyhow!("discoverable_root applies to a plain root partition, not LVM"));
+        }
+        let preserve_home = self.preserve_home.unwrap_or(false);
+        if preserve_home && layout == Layout::Lvm {
+            return Err(anyhow!(
+                "preserve_home reuses plain partitions; it does not work with LVM"
+            ));
+        }
+        if preserve_home && self.root_size_mib.is_some() {
+            return Err(anyhow!(
+                "root_size_mib has no effect with preserve_home (nothing is repartitioned)"
+            ));
+        }
+        if preserve_home && self.swap_size_mib.is_some() {
+            return Err(anyhow!(
+                "swap_size_mib has no effect with preserve_home (nothing is repartitioned)"
+            ));
+        }
+        if self.root_size_mib == Some(0) {
+            return Err(anyhow!("Invalid root_size_mib 0 (omit it to use the whole disk)"));
+        }
+        if self.swap_size_mib == Some(0) {
+            return Err(anyhow!("Invalid swap_size_mib 0 (omit it for no swap partition)"));
+        }
+        if let Some(timezone) = &self.timezone {
+            validate_timezone(timezone)?;
+        }
+        if let Some(locale) = &self.locale {
+            validate_locale(locale)?;
+        }
+        for (what, name) in [("console font", &self.console_font), ("keymap", &self.console_keymap)]
+        {
+            if let Some(name) = name {
+                validate_console_name(what, name)?;
+            }
+        }
+        if let Some(key) = &self.ssh_authorized_key {
+            validate_ssh_key(key)?;
+        }
+        if let Some(target) = &self.default_target {
+            validate_target_name(target)?;
+        }
+        if let Some(debs) = &self.debs
+            && !debs.is_absolute()
+        {
+            return Err(anyhow!(
+                "Invalid debs directory '{}' (expected an absolute path)",
+                debs.display()
+            ));
+        }
+        if let Some(os_release) = &self.os_release {
+            os_release.validate()?;
+        }
+        for unit in self.enable_units.iter().chain(&self.disable_units).chain(&self.mask_units) {
+            validate_unit_name(unit)?;
+        }
+
+        Ok(InstallSettings {
+            hostname,
+            users: UserSetup {
+                username,
+                password,
+                root_access,
+                passwordless_sudo: self.passwordless_sudo.unwrap_or(false),
+                password_scheme: self.password_scheme.unwrap_or_default(),
+            },
+            disk: self.disk,
+            payload_url: self.payload_url,
+            payload_sha256: self.payload_sha256,
+            format,
+            layout,
+            discoverable_root,
+            preserve_home,
+            root_size_mib: self.root_size_mib,
+            swap_size_mib: self.swap_size_mib,
+            bootloader: self.bootloader.unwrap_or_default(),
+            zram: self.zram,
+            timezone: self.timezone,
+            locale: self.locale,
+            console_font: self.console_font,
+            console_keymap: self.console_keymap,
+            ip_family: self.ip_family.unwrap_or_default(),
+            network_backend: self.network_backend,
+            ssh_authorized_key: self.ssh_authorized_key,
+            default_target: self.default_target,
+            debs: self.debs,
+            regenerate_initramfs: self.regenerate_initramfs.unwrap_or(false),
+            os_release: self.os_release,
+            units: UnitOverrides {
+                enable: self.enable_units,
+                disable: self.disable_units,
+                mask: self.mask_units,
+            },
+        })
+    }
+}
+
+pub fn validate_hostname(hostname: &str) -> Result<()> {
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if hostname.len() > 253 || !hostname.split('.').all(valid_label) {
+        return Err(anyhow!("Invalid hostname '{hostname}'"));
+    }
+    Ok(())
+}
+
+pub fn validate_username(username: &str) -> Result<()> {
+    // Debian's default NAME_REGEX.
+    let mut chars = username.chars();
+    let valid = username.len() <= 32
+        && chars.next().is_some_and(|c| c.is_ascii_lowercase())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid || username == "root" {
+        return Err(anyhow!("Invalid username '{username}'"));
+    }
+    Ok(())
+}
+
+fn validate_password(password: &str) -> Result<()> {
+    // chpasswd reads `user:password` lines.
+    if password.is_empty() || password.contains(['\n', '\r']) {
+        return Err(anyhow!("Password must be non-empty and a single line"));
+    }
+    Ok(())
+}
+
+fn validate_timezone(timezone: &str) -> Result<()> {
+    let valid = !timezone.is_empty()
+        && !timezone.starts_with('/')
+        && !timezone.split('/').any(|part| part.is_empty() || part == "..")
+        && timezone.chars().all(|c| c.is_ascii_alphanumeric() || "/_+-".contains(c));
+    if !valid {
+        return Err(anyhow!("Invalid timezone '{timezone}'"));
+    }
+    Ok(())
+}
+
+fn validate_locale(locale: &str) -> Result<()> {
+    let valid = !locale.is_empty()
+        && locale.chars().all(|c| c.is_ascii_alphanumeric() || "_.@-".contains(c));
+    if !valid {
+        return Err(anyhow!("Invalid locale '{locale}'"));
+    }
+    Ok(())
+}
+
+fn validate_console_name(what: &str, name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c));
+    if !valid {
+        return Err(anyhow!("Invalid {what} '{name}'"));
+    }
+    Ok(())
+}
+
+fn validate_ssh_key(key: &str) -> Result<()> {
+    let key = key.trim();
+    let known_type = ["ssh-", "ecdsa-", "sk-"].iter().any(|prefix| key.starts_with(prefix));
+    if !known_type || key.split_whitespace().count() < 2 || key.contains('\n') {
+        return Err(anyhow!("Invalid SSH public key (expected a single authorized_keys line)"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_toml() {
+        let config = InstallConfig::parse(
+            r#"
+hostname = "db07"
+username = "ops"
+password = "hunter2"
+root_access = "locked"
+disk = "/dev/nvme0n1"
+payload_url = "https://mirror.example.com/payload.tar.zst"
+filesystem = "ext4"
+layout = "lvm"
+root_mkfs_opts = ["-O", "^has_journal"]
+root_size_mib = 20480
+swap_size_mib = 4096
+bootloader = "grub"
+zram = "50%"
+timezone = "Europe/Oslo"
+locale = "en_US.UTF-8"
+console_keymap = "de"
+ip_family = "v6"
+network_backend = "networkmanager"
+ssh_authorized_key = "ssh-ed25519 AAAAC3Nza ops@laptop"
+default_target = "graphical.target"
+regenerate_initramfs = true
+enable_units = ["ssh.service"]
+
+[os_release]
+name = "TruthDB"
+id = "truthdb"
+"#,
+            false,
+        )
+        .unwrap();
+        assert_eq!(config.hostname.as_deref(), Some("db07"));
+        assert_eq!(config.root_access, Some(RootPolicy::Locked));
+        assert_eq!(config.ip_family, Some(IpFamily::V6));
+        assert_eq!(config.network_backend, Some(NetworkBackend::NetworkManager));
+        assert_eq!(config.disk, Some(PathBuf::from("/dev/nvme0n1")));
+        assert_eq!(config.enable_units, vec!["ssh.service"]);
+
+        let settings = config.resolve().unwrap();
+        assert_eq!(settings.users.username, "ops");
+        assert_eq!(settings.users.root_access, RootAccess::Locked);
+        assert_eq!(settings.root_size_mib, Some(20480));
+        assert_eq!(settings.swap_size_mib, Some(4096));
+        assert_eq!(settings.format.root_mkfs_opts, vec!["-O", "^has_journal"]);
+        assert_eq!(settings.bootloader, Bootloader::Grub);
+        assert_eq!(settings.layout, Layout::Lvm);
+        assert_eq!(
+            settings.payload_url.as_deref(),
+            Some("https://mirror.example.com/payload.tar.zst")
+        );
+        assert_eq!(settings.console_keymap.as_deref(), Some("de"));
+        assert_eq!(settings.zram, Some(ZramSize::Percent(50)));
+        assert_eq!(settings.default_target.as_deref(), Some("graphical.target"));
+        assert!(settings.regenerate_initramfs);
+        assert_eq!(settings.os_release.and_then(|os| os.id).as_deref(), Some("truthdb"));
+    }
+
+    #[test]
+    fn summary_lists_each_decision() {
+        let settings = InstallConfig {
+            hostname: Some("db07".into()),
+            root_size_mib: Some(20480),
+            layout: Some(Layout::Lvm),
+            zram: Some(ZramSize::Percent(50)),
+            root_access: Some(RootPolicy::Locked),
+            ip_family: Some(IpFamily::V4),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap();
+        assert_eq!(
+            settings.summary(),
+            vec![
+                "Partitions: ESP 512 MiB (FAT32) + root 20480 MiB (ext4, on LVM truthdb/root)",
+                "Swap: zram, 50% of RAM",
+                "Hostname: db07",
+                "User: truthdb (sudo); root: locked",
+                "Network: DHCP IPv4 via auto-detected",
+                "Bootloader: systemd-boot",
+                "Payload: install media",
+            ]
+        );
+        assert_eq!(settings.format.root_label(), "db07");
+
+        let with_swap = InstallConfig { swap_size_mib: Some(4096), ..Default::default() };
+        let summary = with_swap.resolve().unwrap().summary();
+        assert_eq!(
+            summary[0],
+            "Partitions: ESP 512 MiB (FAT32) + swap 4096 MiB + root rest of disk (ext4)"
+        );
+        assert_eq!(summary[1], "Swap: 4096 MiB partition");
+    }
+
+    #[test]
+    fn parses_json() {
+        let config =
+            InstallConfig::parse(r#"{"hostname": "db08", "passwordless_sudo": true}"#, true)
+                .unwrap();
+        assert_eq!(config.hostname.as_deref(), Some("db08"));
+        assert_eq!(config.passwordless_sudo, Some(true));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(InstallConfig::parse("hostnme = \"typo\"", false).is_err());
+        assert!(InstallConfig::parse("zram = \"lots\"", false).is_err());
+    }
+
+    #[test]
+    fn precedence_is_defaults_then_file_then_cli() {
+        let file = InstallConfig {
+            hostname: Some("from-file".to_string()),
+            timezone: Some("UTC".to_string()),
+            enable_units: vec!["a.service".to_string()],
+            ..InstallConfig::default()
+        };
+        let cli = InstallConfig {
+            hostname: Some("from-cli".to_string()),
+            enable_units: vec!["b.service".to_string()],
+            ..InstallConfig::default()
+        };
+
+        let settings = file.merge(cli).resolve().unwrap();
+        assert_eq!(settings.hostname, "from-cli");
+        assert_eq!(settings.timezone.as_deref(), Some("UTC"));
+        assert_eq!(settings.ip_family, IpFamily::Dual);
+        assert_eq!(settings.users.username, DEFAULT_USERNAME);
+        assert_eq!(settings.units.enable, vec!["a.service", "b.service"]);
+    }
+
+    #[test]
+    fn password_hash_is_passed_through() {
+        let config = InstallConfig {
+            password_hash: Some("$6$salt$hash".to_string()),
+            ..InstallConfig::default()
+        };
+        let settings = config.resolve().unwrap();
+        assert_eq!(settings.users.password, Credential::Hashed("$6$salt$hash".to_string()));
+        assert_eq!(
+            settings.users.root_access,
+            RootAccess::Password(Credential::Hashed("$6$salt$hash".to_string()))
+        );
+    }
+
+    #[test]
+    fn password_scheme_defaults_to_sha512() {
+        assert_eq!(
+            InstallConfig::default().resolve().unwrap().users.password_scheme,
+            PasswordScheme::Sha512
+        );
+        let config = InstallConfig::parse("password_scheme = \"yescrypt\"\n", false).unwrap();
+        assert_eq!(config.resolve().unwrap().users.password_scheme, PasswordScheme::Yescrypt);
+    }
+
+    #[test]
+    fn cli_password_hash_replaces_file_password() {
+        let file = InstallConfig { password: Some("plain".to_string()), ..Default::default() };
+        let cli =
+            InstallConfig { password_hash: Some("$6$salt$hash".to_string()), ..Default::default() };
+        let merged = file.merge(cli);
+        assert_eq!(merged.password, None);
+        assert!(merged.resolve().is_ok());
+
+        let both = InstallConfig {
+            password: Some("plain".to_string()),
+            password_hash: Some("$6$salt$hash".to_string()),
+            ..Default::default()
+        };
+        assert!(both.resolve().is_err());
+    }
+
+    #[test]
+    fn defaults_resolve() {
+        let settings = InstallConfig::default().resolve().unwrap();
+        assert_eq!(settings.hostname, DEFAULT_HOSTNAME);
+        assert_eq!(settings.users, UserSetup::default());
+        assert_eq!(settings.disk, None);
+        assert_eq!(settings.format.root_label(), "root");
+    }
+
+    #[test]
+    fn invalid_values_fail_validation() {
+        let bad = [
+            InstallConfig { hostname: Some("-bad".into()), ..Default::default() },
+            InstallConfig { username: Some("Root".into()), ..Default::default() },
+            InstallConfig { password: Some(String::new()), ..Default::default() },
+            InstallConfig { password_hash: Some("123456".into()), ..Default::default() },
+            InstallConfig { root_size_mib: Some(0), ..Default::default() },
+            InstallConfig { swap_size_mib: Some(0), ..Default::default() },
+            InstallConfig { default_target: Some("ssh.service".into()), ..Default::default() },
+            InstallConfig { debs: Some("debs".into()), ..Default::default() },
+            InstallConfig { root_mkfs_opts: Some(vec!["-L".into()]), ..Default::default() },
+            InstallConfig { esp_volume_id: Some("xyz".into()), ..Default::default() },
+            InstallConfig { disk: Some("sda".into()), ..Default::default() },
+            InstallConfig { payload_url: Some("/payload/x.tar.zst".into()), ..Default::default() },
+            InstallConfig { payload_sha256: Some("a".repeat(64)), ..Default::default() },
+            InstallConfig {
+                layout: Some(Layout::Lvm),
+                discoverable_root: Some(true),
+                ..Default::default()
+            },
+            InstallConfig {
+                layout: Some(Layout::Lvm),
+                preserve_home: Some(true),
+                ..Default::default()
+            },
+            InstallConfig {
+                preserve_home: Some(true),
+                root_size_mib: Some(20480),
+                ..Default::default()
+            },
+            InstallConfig {
+                preserve_home: Some(true),
+                swap_size_mib: Some(4096),
+                ..Default::default()
+            },
+            InstallConfig { timezone: Some("../../etc/shadow".into()), ..Default::default() },
+            InstallConfig { ssh_authorized_key: Some("not a key".into()), ..Default::default() },
+            InstallConfig { console_keymap: Some("../us".into()), ..Default::default() },
+            InstallConfig { mask_units: vec!["nope".into()], ..Default::default() },
+            InstallConfig {
+                os_release: Some(OsReleaseConfig {
+                    id: Some("TruthDB".into()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        ];
+        for config in bad {
+            assert!(config.clone().resolve().is_err(), "{config:?} should be rejected");
+        }
+    }
+}
@@ -0,0 +1,415 @@
+//! Console output helpers
+//!
+//! All user-facing output goes to stdout (single channel); these helpers keep multi-line
+//! messages readable on an 80-column console.
+
+use crate::platform::resume::InstallStep;
+use crate::progress::ProgressReporter;
+use anyhow::{Result, anyhow};
+use std::io::{IsTerminal, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Console width assumed when wrapping (the initramfs console is usually 80x25).
+pub const WIDTH: usize = 80;
+
+const SPINNER_FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+const SPINNER_INTERVAL: Duration = Duration::from_millis(150);
+/// Cells in a progress bar; with the prefix and percentage it fits well inside [`WIDTH`].
+pub const PROGRESS_BAR_CELLS: usize = 40;
+
+/// Block until `rx` delivers a result, redrawing `[..] <label> <spinner>` in place meanwhile
+/// so long-running steps don't look frozen.
+pub fn wait_with_spinner<T>(label: &str, rx: Receiver<Result<T>>) -> Result<T> {
+    let started = Instant::now();
+    let mut frame = 0;
+    let result = loop {
+        let elapsed = format_elapsed(started.elapsed());
+        print!("\r[..] {label} {} {elapsed}", spinner_frame(frame));
+        let _ = std::io::stdout().flush();
+        match rx.recv_timeout(SPINNER_INTERVAL) {
+            Ok(result) => break result,
+            Err(RecvTimeoutError::Timeout) => frame += 1,
+            Err(RecvTimeoutError::Disconnected) => {
+                break Err(anyhow!("{label}: worker exited without a result"));
+            }
+        }
+    };
+    // Finish the spinner line so the following [OK]/[ERR] starts on a fresh one.
+    println!("\r[..] {label} ({})", format_elapsed(started.elapsed()));
+    let _ = std::io::stdout().flush();
+    result
+}
+
+/// `MM:SS` (or `H:MM:SS` past an hour) for progress output.
+pub fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes:02}:{seconds:02}")
+    }
+}
+
+/// Wall-clock time per install phase, for spotting which phase is slow on which hardware.
+#[derive(Debug, Default)]
+pub struct StepTimings<'a> {
+    current: Option<(InstallStep, Instant)>,
+    done: Vec<(InstallStep, Duration)>,
+    reporter: Option<&'a dyn ProgressReporter>,
+}
+
+impl<'a> StepTimings<'a> {
+    /// Also announce each phase to `reporter`.
+    pub fn reporting_to(reporter: &'a dyn ProgressReporter) -> Self {
+        Self { reporter: Some(reporter), ..Self::default() }
+    }
+
+    /// Start timing `phase`, closing the previous one.
+    pub fn begin(&mut self, phase: InstallStep) {
+        self.finish();
+        self.current = Some((phase, Instant::now()));
+        if let Some(reporter) = self.reporter {
+            reporter.step(phase);
+        }
+    }
+
+    /// Close the running phase, if any.
+    pub fn finish(&mut self) {
+        if let Some((phase, started)) = self.current.take() {
+            self.done.push((phase, started.elapsed()));
+        }
+    }
+
+    /// `phase 12.3s` entries, in the order the phases ran.
+    pub fn summary_lines(&self) -> Vec<String> {
+        self.done
+            .iter()
+            .map(|(phase, took)| format!("{phase:<10} {:>7.1}s", took.as_secs_f64()))
+            .collect()
+    }
+}
+
+/// `[##########----------]  50%`, with `cells` cells between the brackets.
+pub fn progress_bar(percent: u8, cells: usize) -> String {
+    let percent = usize::from(percent.min(100));
+    let filled = cells * percent / 100;
+    format!("[{}{}] {percent:>3}%", "#".repeat(filled), "-".repeat(cells - filled))
+}
+
+fn spinner_frame(frame: usize) -> char {
+    SPINNER_FRAMES[frame % SPINNER_FRAMES.len()]
+}
+
+/// Items the operator picks from by typing their number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Menu<T> {
+    items: Vec<T>,
+}
+
+impl<T> Menu<T> {
+    pub fn new(items: Vec<T>) -> Self {
+        Self { items }
+    }
+
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// `1) <label>` for each item, numbered from one.
+    pub fn lines(&self, label: impl Fn(&T) -> String) -> Vec<String> {
+        self.items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| format!("{}) {}", i + 1, label(item)))
+            .collect()
+    }
+
+    /// The item whose number was typed, if the answer names one.
+    pub fn choose(mut self, answer: &str) -> Option<T> {
+        let number: usize = answer.trim().parse().ok()?;
+        (1..=self.items.len()).contains(&number).then(|| self.items.swap_remove(number - 1))
+    }
+}
+
+/// Whether a prompt answer is the word `yes`. Destructive steps ask for it spelled out so a
+/// stray ENTER can't start them.
+pub fn is_confirmation(answer: &str) -> bool {
+    answer.trim().eq_ignore_ascii_case("yes")
+}
+
+/// The value typed at a field prompt: a blank answer keeps `default`, anything else must pass
+/// `validate`.
+pub fn field_value(
+    answer: &str,
+    default: &str,
+    validate: impl Fn(&str) -> Result<()>,
+) -> Result<String> {
+    let value = answer.trim();
+    if value.is_empty() {
+        return Ok(default.to_string());
+    }
+    validate(value)?;
+    Ok(value.to_string())
+}
+
+/// Print an error and every cause beneath it, one per line, wrapped to the console width.
+pub fn print_error(err: &anyhow::Error) {
+    for line in error_chain_lines(err, WIDTH) {
+        println!("{}", styled(&line));
+    }
+    let _ = std::io::stdout().flush();
+}
+
+/// What a status line reports; decides its prefix and colour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// A step finished (`[OK]`, green).
+    Ok,
+    /// Something the operator should notice or answer (`[!!]`, yellow).
+    Warn,
+    /// A failure (`[ERR]`, red).
+    Err,
+}
+
+impl Status {
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Status::Ok => "[OK]",
+            Status::Warn => "[!!]",
+            Status::Err => "[ERR]",
+        }
+    }
+}
+
+/// Print a status line, its prefix coloured like [`styled`] does.
+pub fn status(kind: Status, msg: &str) {
+    println!("{}", styled(&status_line(kind, msg)));
+}
+
+fn status_line(kind: Status, msg: &str) -> String {
+    format!("{} {msg}", kind.prefix())
+}
+
+/// Colour the status prefix of `line` (`[OK]` green, `[ERR]` red, `[!!]` yellow) when stdout
+/// is a terminal and `NO_COLOR` is unset; otherwise return it unchanged.
+pub fn styled(line: &str) -> String {
+    let enabled = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    style_line(line, enabled)
+}
+
+fn style_line(line: &str, enabled: bool) -> String {
+    const COLORS: [(Status, &str); 3] =
+        [(Status::Ok, "32"), (Status::Err, "31"), (Status::Warn, "33")];
+    let found = COLORS.iter().find(|(kind, _)| line.starts_with(kind.prefix()));
+    match found {
+        Some((kind, color)) if enabled => {
+            let prefix = kind.prefix();
+            format!("\x1b[{color}m{prefix}\x1b[0m{}", &line[prefix.len()..])
+        }
+        _ => line.to_string(),
+    }
+}
+
+/// Render `err`'s context hierarchy: the outermost message first, then each cause indented
+/// beneath it (`Failed to mount ESP` -> `mount failed: ...` -> `No such device`).
+pub fn error_chain_lines(err: &anyhow::Error, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (depth, cause) in err.chain().enumerate() {
+        let (first, rest) =
+            if depth == 0 { ("[ERR] ", "[ERR]   ") } else { ("[ERR]   -> ", "[ERR]      ") };
+        let wrapped = wrap(&cause.to_string(), width.saturating_sub(first.len()).max(20));
+        for (i, part) in wrapped.into_iter().enumerate() {
+            let prefix = if i == 0 { first } else { rest };
+            lines.push(format!("{prefix}{part}"));
+        }
+    }
+    lines
+}
+
+/// Greedy word wrap; words longer than `width` are split.
+pub fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for raw_line in text.lines() {
+        let mut current = String::new();
+        for word in raw_line.split_whitespace() {
+            let mut word = word;
+            while word.chars().count() > width {
+                if !current.is_empty() {
+                    lines.push(std::mem::take(&mut current));
+                }
+                let split = word.char_indices().nth(width).map_or(word.len(), |(i, _)| i);
+                lines.push(word[..split].to_string());
+                word = &word[split..];
+            }
+            if word.is_empty() {
+                continue;
+            }
+            let needed = if current.is_empty() { 0 } else { 1 } + word.chars().count();
+            if current.chars().count() + needed > width {
+                lines.push(std::mem::take(&mut current));
+            }
+            if !current.is_empty() {
+                current.push(' ');
+            }
+            current.push_str(word);
+        }
+        if !current.is_empty() {
+            lines.push(current);
+        }
+    }
+    if lines.is_empty() {
+        lines.push(String::new());
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use std::sync::mpsc;
+
+    #[test]
+    fn error_chain_renders_each_cause_on_its_own_line() {
+        let err = Err::<(), _>(anyhow!("No such device"))
+            .context("mount failed")
+            .context("Failed to mount ESP")
+            .unwrap_err();
+        assert_eq!(
+            error_chain_lines(&err, WIDTH),
+            vec![
+                "[ERR] Failed to mount ESP",
+                "[ERR]   -> mount failed",
+                "[ERR]   -> No such device",
+            ]
+        );
+    }
+
+    #[test]
+    fn long_causes_are_wrapped() {
+        let err = anyhow!("tar failed: stderr='zstd: error 70 : Write error : cannot write block'");
+        let lines = error_chain_lines(&err, 40);
+        assert_eq!(
+            lines,
+            vec![
+                "[ERR] tar failed: stderr='zstd: error 70",
+                "[ERR]   : Write error : cannot write",
+                "[ERR]   block'",
+            ]
+        );
+    }
+
+    #[test]
+    fn status_prefixes_are_coloured() {
+        assert_eq!(
+            style_line("[ERR] Disk selection failed", true),
+            "\x1b[31m[ERR]\x1b[0m Disk selection failed"
+        );
+        assert_eq!(
+            style_line("[OK] Installer finished", true),
+            "\x1b[32m[OK]\x1b[0m Installer finished"
+        );
+        assert_eq!(style_line("[!!] Press ENTER", true), "\x1b[33m[!!]\x1b[0m Press ENTER");
+        assert_eq!(style_line("[..] Syncing disks", true), "[..] Syncing disks");
+        assert_eq!(style_line("[ERR] plain", false), "[ERR] plain");
+    }
+
+    #[test]
+    fn status_lines_carry_their_prefix() {
+        assert_eq!(status_line(Status::Ok, "Disks synced"), "[OK] Disks synced");
+        assert_eq!(status_line(Status::Warn, "  FORMATTED: sda1"), "[!!]   FORMATTED: sda1");
+        assert_eq!(
+            style_line(&status_line(Status::Warn, "Press ENTER"), true),
+            "\x1b[33m[!!]\x1b[0m Press ENTER"
+        );
+    }
+
+    #[test]
+    fn wrap_splits_overlong_words() {
+        assert_eq!(wrap("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        assert_eq!(wrap("", 4), vec![""]);
+    }
+
+    #[test]
+    fn menu_numbers_items_and_picks_by_number() {
+        let menu = Menu::new(vec!["sda", "sdb", "nvme0n1"]);
+        assert_eq!(
+            menu.lines(|name| format!("/dev/{name}")),
+            vec!["1) /dev/sda", "2) /dev/sdb", "3) /dev/nvme0n1"]
+        );
+        assert_eq!(menu.clone().choose(" 3\n"), Some("nvme0n1"));
+        assert_eq!(menu.clone().choose("1"), Some("sda"));
+        for answer in ["0", "4", "", "sda", "-1"] {
+            assert_eq!(menu.clone().choose(answer), None, "{answer:?}");
+        }
+    }
+
+    #[test]
+    fn only_yes_confirms() {
+        assert!(is_confirmation("yes\n"));
+        assert!(is_confirmation(" YES "));
+        for answer in ["", "\n", "y", "q", "yes please", "no"] {
+            assert!(!is_confirmation(answer), "{answer:?}");
+        }
+    }
+
+    #[test]
+    fn field_value_defaults_and_validates() {
+        let no_spaces =
+            |value: &str| if value.contains(' ') { Err(anyhow!("no spaces")) } else { Ok(()) };
+        assert_eq!(field_value("\n", "truthdb01", no_spaces).unwrap(), "truthdb01");
+        assert_eq!(field_value("  db07\n", "truthdb01", no_spaces).unwrap(), "db07");
+        assert!(field_value("db 07\n", "truthdb01", no_spaces).is_err());
+    }
+
+    #[test]
+    fn progress_bar_fills_proportionally() {
+        assert_eq!(progress_bar(0, 10), "[----------]   0%");
+        assert_eq!(progress_bar(42, 10), "[####------]  42%");
+        assert_eq!(progress_bar(100, 10), "[##########] 100%");
+        assert_eq!(progress_bar(250, 4), "[####] 100%");
+    }
+
+    #[test]
+    fn spinner_cycles_through_frames() {
+        let frames: String = (0..6).map(spinner_frame).collect();
+        assert_eq!(frames, "|/-\\|/");
+    }
+
+    #[test]
+    fn step_timings_close_previous_phase() {
+        let mut timings = StepTimings::default();
+        timings.begin(InstallStep::Partition);
+        timings.begin(InstallStep::Format);
+        timings.finish();
+        timings.finish();
+        let lines = timings.summary_lines();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("partition"));
+        assert!(lines[1].starts_with("format"));
+        assert!(lines[1].ends_with('s'));
+    }
+
+    #[test]
+    fn elapsed_is_minutes_and_seconds() {
+        assert_eq!(format_elapsed(Duration::from_secs(134)), "02:14");
+        assert_eq!(format_elapsed(Duration::from_millis(999)), "00:00");
+        assert_eq!(format_elapsed(Duration::from_secs(3725)), "1:02:05");
+    }
+
+    #[test]
+    fn wait_with_spinner_returns_worker_result() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(400));
+            tx.send(Ok(7)).unwrap();
+        });
+        assert_eq!(wait_with_spinner("Working", rx).unwrap(), 7);
+
+        let (tx, rx) = mpsc::channel::<Result<()>>();
+        drop(tx);
+        assert!(wait_with_spinner("Working", rx).is_err());
+    }
+}
@@ -4,213 +4,896 @@
 //! - Output: stdout only (single channel)
 //! - Input: stdin only (blocking prompts)
 
+mod cli;
+mod config;
+mod console;
 mod platform;
+mod progress;
+mod status;
 
-use anyhow::Result;
+use anyhow::{Context, Result, anyhow};
+use console::Status;
+use platform::resume::{InstallStep, Progress};
+use progress::ProgressReporter;
+use status::{State, StatusBoard};
 use std::io::{BufRead, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 fn main() {
     if let Err(e) = run() {
         // Keep output on the same channel.
-        println!("[ERR] Fatal error: {e:#}");
-        let _ = std::io::stdout().flush();
+        console::print_error(&e.context("Fatal error"));
         std::process::exit(1);
     }
 }
 
 fn run() -> Result<()> {
+    let opts = cli::parse_args(std::env::args().skip(1))?;
+    if opts.help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+    platform::command::RetryPolicy::set_racy(opts.retry_policy());
+    if let Some(disk) = &opts.verify_disk {
+        return verify_installed_disk(disk);
+    }
+
+    let file_config = match &opts.config_path {
+        Some(path) => config::InstallConfig::load(path)?,
+        None => config::InstallConfig::default(),
+    };
+    let config = file_config.merge(opts.overrides);
+    // Only ask for what neither the config file nor a flag decided.
+    let ask_hostname = !opts.unattended && config.hostname.is_none();
+    let ask_username = !opts.unattended && config.username.is_none();
+    let label_follows_prompt = ask_hostname && config.root_label.is_none();
+    let mut settings = config.resolve().context("Invalid configuration")?;
+    if opts.force && settings.disk.is_none() {
+        return Err(anyhow!("--force requires an explicit disk (--disk or `disk` in the config)"));
+    }
+    if opts.auto_largest && (settings.disk.is_some() || opts.image.is_some()) {
+        return Err(anyhow!("--auto-largest cannot be combined with a target disk or --image"));
+    }
+    if opts.image.is_some() && settings.disk.is_some() {
+        return Err(anyhow!("--image cannot be combined with a target disk"));
+    }
+    if opts.image_size_mib.is_some() && opts.image.is_none() {
+        return Err(anyhow!("--image-size requires --image"));
+    }
+    if opts.unattended {
+        match &settings.disk {
+            Some(disk) if !disk.exists() => {
+                return Err(anyhow!("--unattended: disk {} does not exist", disk.display()));
+            }
+            None if opts.image.is_none() && !opts.auto_largest => {
+                return Err(anyhow!(
+                    "--unattended needs `disk` in the config file (or --image / --auto-largest)"
+                ));
+            }
+            _ => {}
+        }
+    }
+
     println!("TruthDB Installer starting...");
+    console::status(
+        Status::Ok,
+        &format!(
+            "Firmware: {}",
+            platform::efi::firmware_summary(
+                platform::efi::booted_uefi(),
+                platform::efi::secure_boot_enabled()
+            )
+        ),
+    );
+    if platform::efi::booted_uefi() && !platform::efi::efivars_writable() {
+        console::status(
+            Status::Warn,
+            "NVRAM (efivarfs) is not writable: no boot entry can be registered;",
+        );
+        console::status(
+            Status::Warn,
+            "the install relies on the fallback loader EFI/BOOT/BOOTX64.EFI",
+        );
+    }
+    if let Some(info) = platform::dmi::firmware_info() {
+        console::status(Status::Ok, &format!("Machine: {}", info.summary()));
+    }
     let _ = std::io::stdout().flush();
 
+    let status = StatusBoard::default();
+    let status_server = match opts.status_port {
+        Some(port) => start_status_server(port, &status),
+        None => None,
+    };
+
     let mut had_error = false;
 
-    println!("[..] Enumerating eligible disks");
-    let _ = std::io::stdout().flush();
-    let target_disk = match platform::disks::DiskScanner::new_default().choose_single_target_disk()
-    {
-        Ok(disk) => {
-            println!("[OK] Target disk: {} ({} bytes)", disk.dev_path.display(), disk.size_bytes);
-            Some(disk)
+    if opts.force {
+        let warning = "--force: disk size floor is NOT enforced for the requested disk";
+        console::status(Status::Warn, warning);
+        // Also on the serial console / in dmesg, where a forced automated install is reviewed.
+        if let Some(kmsg) = progress::KmsgReporter::open(Path::new(progress::KMSG_PATH)) {
+            kmsg.message(&format!("WARNING: {warning}"));
         }
-        Err(e) => {
-            println!("[ERR] Disk selection failed: {e:#}");
-            had_error = true;
-            None
+    }
+    let image = match &opts.image {
+        Some(path) => {
+            println!("[..] Attaching image {}", path.display());
+            let _ = std::io::stdout().flush();
+            let image = platform::image::attach_image(path, opts.image_size_mib)
+                .context("Image setup failed")?;
+            console::status(Status::Ok, &format!("Image attached as {}", image.dev_path.display()));
+            Some(image)
         }
+        None => None,
     };
-    let _ = std::io::stdout().flush();
 
-    'install: {
-        let Some(disk) = target_disk else {
-            break 'install;
+    status.set_state(State::SelectingDisk);
+    println!("[..] Enumerating eligible disks");
+    let _ = std::io::stdout().flush();
+    let scanner = platform::disks::DiskScanner::new_default()
+        .with_min_size_bytes(
+            opts.min_disk_size_bytes.unwrap_or(platform::disks::DEFAULT_MIN_SIZE_BYTES),
+        )
+        .with_allow_removable(opts.allow_removable)
+        .with_cache_ttl(std::time::Duration::from_millis(500));
+    let target_disk = loop {
+        let chosen = match (&image, &settings.disk) {
+            (Some(image), _) => scanner.image_disk(&image.dev_path),
+            (None, Some(dev_path)) => scanner.choose_disk(dev_path, opts.force),
+            (None, None) if opts.auto_largest => scanner.choose_largest_disk().inspect(|disk| {
+                let eligible = scanner.eligible_disks().map(|disks| disks.len()).unwrap_or(1);
+                let picked = format!(
+                    "{} ({})",
+                    disk.dev_path.display(),
+                    platform::disks::human_size(disk.size_bytes)
+                );
+                console::status(
+                    Status::Ok,
+                    &format!(
+                        "--auto-largest: picked {picked}, the largest of {eligible} eligible disk(s)"
+                    ),
+                );
+            }),
+            (None, None) => match scanner.eligible_disks() {
+                Ok(disks) if disks.len() > 1 => select_disk(disks),
+                _ => scanner.choose_single_target_disk(),
+            },
         };
-
-        prompt_enter(&format!(
-            "[!!] About to PARTITION+FORMAT this disk: {}\n[!!] Press ENTER to continue",
-            disk.dev_path.display()
-        ))?;
-
-        let payload_path = Path::new("/payload/debian-minbase-amd64-bookworm.tar.zst");
-        println!("[..] Checking Debian rootfs payload");
-        if !payload_path.exists() {
-            println!("[ERR] Missing rootfs payload: {}", payload_path.display());
-            had_error = true;
-            break 'install;
+        match chosen {
+            Ok(disk) => {
+                console::status(
+                    Status::Ok,
+                    &format!(
+                        "Target disk: {} ({} bytes, {})",
+                        disk.dev_path.display(),
+                        disk.size_bytes,
+                        disk.transport
+                    ),
+                );
+                status.update(|status| {
+                    status.state = State::Confirming;
+                    status.disk = Some(disk.dev_path.display().to_string());
+                });
+                break Some(disk);
+            }
+            Err(e) => {
+                let e = e.context("Disk selection failed");
+                console::print_error(&e);
+                if opts.unattended || !offer_rescan(&scanner)? {
+                    status.update(|status| {
+                        status.state = State::Failed;
+                        status.error = Some(format!("{e:#}"));
+                    });
+                    had_error = true;
+                    break None;
+                }
+                scanner.invalidate();
+                println!("[..] Re-scanning disks");
+            }
         }
-        println!("[OK] Rootfs payload present");
-        let _ = std::io::stdout().flush();
+    };
+    let _ = std::io::stdout().flush();
 
-        println!("[..] Wiping disk signatures (wipefs)");
-        let _ = std::io::stdout().flush();
-        if let Err(e) = platform::partition::wipefs_all(&disk.dev_path) {
-            println!("[ERR] wipefs failed: {e:#}");
-            had_error = true;
-            break 'install;
+    if ask_hostname && target_disk.is_some() {
+        settings.hostname =
+            prompt_field("Hostname", &settings.hostname, config::validate_hostname)?;
+        if label_follows_prompt {
+            let root_fs = settings.format.root_fs;
+            settings.format.root_label = Some(root_fs.label_for_hostname(&settings.hostname));
         }
-        println!("[OK] Signatures wiped");
+    }
+    if ask_username && target_disk.is_some() {
+        settings.users.username =
+            prompt_field("Username", &settings.users.username, config::validate_username)?;
+    }
 
-        println!("[..] Partitioning disk (GPT: ESP+root)");
-        let _ = std::io::stdout().flush();
-        if let Err(e) = platform::partition::partition_gpt_esp_root(
-            &disk.dev_path,
-            platform::partition::PartitionPlan::default(),
-        ) {
-            println!("[ERR] Partitioning failed: {e:#}");
-            had_error = true;
-            break 'install;
-        }
-        println!("[OK] Disk partitioned");
-
-        let (esp, root) =
-            match platform::partition::expected_esp_and_root_partitions(&disk.dev_path) {
-                Ok(paths) => paths,
+    let (target_disk, home) = match target_disk {
+        Some(disk) if settings.preserve_home => {
+            match platform::home::find_home(&disk.dev_path).and_then(|home| {
+                home.ok_or_else(|| {
+                    anyhow!(
+                        "--preserve-home: no /home partition found on {}",
+                        disk.dev_path.display()
+                    )
+                })
+            }) {
+                Ok(home) => (Some(disk), Some(home)),
                 Err(e) => {
-                    println!("[ERR] Could not compute partition paths: {e:#}");
+                    console::print_error(&e);
+                    status.update(|status| {
+                        status.state = State::Failed;
+                        status.error = Some(format!("{e:#}"));
+                    });
                     had_error = true;
-                    break 'install;
+                    (None, None)
                 }
-            };
-        println!("[OK] ESP partition: {}", esp.display());
-        println!("[OK] Root partition: {}", root.display());
+            }
+        }
+        other => (other, None),
+    };
 
-        println!("[..] Formatting partitions (vfat+ext4)");
-        let _ = std::io::stdout().flush();
-        if let Err(e) = platform::install::format_partitions(&esp, &root) {
-            println!("[ERR] Formatting failed: {e:#}");
-            had_error = true;
-            break 'install;
+    let target_disk = match target_disk {
+        Some(disk) if !confirm_install(&disk, &settings, home.as_ref(), opts.unattended)? => {
+            platform::install::cancel_install(&platform::install::MountPlan::default().target_root)
+                .context("Cancel cleanup failed")?;
+            console::status(
+                Status::Warn,
+                &format!("Installation cancelled; {} was not modified", disk.dev_path.display()),
+            );
+            status.set_state(State::Cancelled);
+            None
         }
-        println!("[OK] Partitions formatted");
+        other => other,
+    };
 
-        println!("[..] Mounting target filesystem");
+    if let Some(disk) = target_disk {
+        let mut progress = Progress::default();
+        loop {
+            status.update(|status| {
+                status.state = State::Installing;
+                status.attempt += 1;
+                status.error = None;
+            });
+            let started = std::time::Instant::now();
+            let outcome = install_to_disk(
+                &settings,
+                &disk,
+                home.as_ref(),
+                image.is_none(),
+                opts.unattended,
+                &mut progress,
+                &status,
+            );
+            println!("[..] Elapsed: {}", console::format_elapsed(started.elapsed()));
+            match outcome {
+                Ok(()) => {
+                    status.update(|status| {
+                        status.state = State::Complete;
+                        status.step = None;
+                    });
+                    console::status(Status::Ok, "Install complete (reboot and remove ISO)");
+                    break;
+                }
+                Err(e) => {
+                    status.update(|status| {
+                        status.state = State::Failed;
+                        status.error = Some(format!("{e:#}"));
+                    });
+                    console::print_error(&e);
+                    if opts.unattended {
+                        had_error = true;
+                        break;
+                    }
+                    let answer = prompt_line(&format!(
+                        "[!!] Type R and press ENTER to retry the install on {}, or press ENTER to stop",
+                        disk.dev_path.display()
+                    ))?;
+                    if !answer.trim().eq_ignore_ascii_case("r") {
+                        had_error = true;
+                        break;
+                    }
+                    println!("[..] Retrying install from the beginning");
+                }
+            }
+        }
         let _ = std::io::stdout().flush();
-        let mount_plan = platform::install::MountPlan::default();
-        if let Err(e) = platform::install::mount_partitions(&esp, &root, &mount_plan) {
-            println!("[ERR] Mount failed: {e:#}");
-            had_error = true;
-            break 'install;
+    }
+
+    if had_error {
+        console::status(Status::Err, "Installer encountered an error");
+    } else {
+        console::status(Status::Ok, "Installer finished");
+    }
+    let _ = std::io::stdout().flush();
+
+    // Image installs run on a host that must keep running (CI, a dev VM); no reboot.
+    if let Some(image) = image {
+        image.detach().context("Image cleanup failed")?;
+        console::status(Status::Ok, "Image detached");
+        if had_error {
+            return Err(anyhow!("Install to image failed"));
         }
-        println!("[OK] Mounted root at {}", mount_plan.target_root.display());
+        return Ok(());
+    }
 
-        println!("[..] Extracting Debian rootfs payload");
-        let _ = std::io::stdout().flush();
-        if let Err(e) =
-            platform::install::extract_rootfs_payload(payload_path, &mount_plan.target_root)
-        {
-            println!("[ERR] Extract failed: {e:#}");
-            had_error = true;
-            let _ = platform::install::unmount_target(&mount_plan);
-            break 'install;
+    if opts.unattended {
+        if had_error {
+            return Err(anyhow!("Unattended install failed"));
         }
-        println!("[OK] Rootfs extracted");
+        println!("[..] Unattended install: rebooting");
+    } else {
+        prompt_enter("[!!] Press ENTER to reboot")?;
+    }
+    drop(status_server);
+    reboot_best_effort();
 
-        println!("[..] Setting hostname to truthdb01");
-        let _ = std::io::stdout().flush();
-        if let Err(e) = platform::install::configure_hostname(&mount_plan, "truthdb01") {
-            println!("[ERR] Hostname setup failed: {e:#}");
-            had_error = true;
-            let _ = platform::install::unmount_target(&mount_plan);
-            break 'install;
+    Ok(())
+}
+
+/// Run the whole destructive install against `disk`.
+///
+/// Every step is safe to repeat, so a failed attempt can simply be retried from the top: the
+/// mount guard tears down whatever was mounted when this returns early, and `progress` lets a
+/// retry skip partitioning/formatting the previous attempt already finished. With `home` set the
+/// partition table is kept and that partition is only mounted, never formatted.
+/// `register_nvram` is off for image installs so the host's boot entries are left alone, and
+/// `unattended` turns questions into their safe answer (stop).
+fn install_to_disk(
+    settings: &config::InstallSettings,
+    disk: &platform::disks::Disk,
+    home: Option<&platform::home::HomePartition>,
+    register_nvram: bool,
+    unattended: bool,
+    progress: &mut Progress,
+    status: &StatusBoard,
+) -> Result<()> {
+    // Nobody may be watching an unattended install; mirror its progress to the kernel log.
+    let kmsg =
+        unattended.then(|| progress::KmsgReporter::open(Path::new(progress::KMSG_PATH))).flatten();
+    let mut reporters: Vec<&dyn ProgressReporter> = vec![&progress::ConsoleReporter, status];
+    if let Some(kmsg) = &kmsg {
+        reporters.push(kmsg);
+    }
+    let reporter = progress::Fanout(reporters);
+    let payload_path = match &settings.payload_url {
+        Some(url) => {
+            download_payload(url, settings.payload_sha256.as_deref()).context("Download failed")?
+        }
+        None => {
+            let payload_path = PathBuf::from(platform::payload::LOCAL_PAYLOAD);
+            reporter.message("Checking Debian rootfs payload");
+            if !payload_path.exists() {
+                return Err(anyhow!("Missing rootfs payload: {}", payload_path.display()));
+            }
+            console::status(Status::Ok, "Rootfs payload present");
+            payload_path
         }
-        println!("[OK] Hostname configured");
+    };
 
-        println!("[..] Creating initial user (truthdb) + setting passwords");
-        let _ = std::io::stdout().flush();
-        if let Err(e) = platform::install::configure_initial_users(&mount_plan) {
-            println!("[ERR] User setup failed: {e:#}");
-            had_error = true;
-            let _ = platform::install::unmount_target(&mount_plan);
-            break 'install;
+    let partition_plan = platform::partition::PartitionPlan {
+        root_size_mib: settings.root_size_mib,
+        lvm: settings.layout == platform::lvm::Layout::Lvm,
+        discoverable_root: settings.discoverable_root,
+        swap_size_mib: settings.swap_size_mib,
+        ..platform::partition::PartitionPlan::default()
+    };
+    partition_plan.check_fits(disk.size_bytes).context("Partition layout rejected")?;
+    settings.format.root_fs.check_mkfs_available()?;
+
+    let platform::partition::PartitionDevices { esp, root, swap } =
+        platform::partition::expected_partitions(&disk.dev_path, &partition_plan)
+            .context("Could not compute partition paths")?;
+    if partition_plan.lvm {
+        platform::lvm::check_group_name_free(&root)?;
+    }
+
+    // An LVM layout is always rebuilt: its volume group was torn down with the last attempt.
+    let (esp_probe, root_probe) =
+        if progress.was_completed(InstallStep::Partition) && !partition_plan.lvm {
+            (platform::resume::Probe::of(&esp), platform::resume::Probe::of(&root))
+        } else {
+            Default::default()
+        };
+    let is_done = |step: InstallStep| {
+        progress.was_completed(step)
+            && step.is_complete(&partition_plan, &settings.format, &esp_probe, &root_probe)
+    };
+    let skip_partition = !partition_plan.lvm && is_done(InstallStep::Partition);
+    let skip_format = skip_partition && is_done(InstallStep::Format);
+
+    let mut timings = console::StepTimings::reporting_to(&reporter);
+    timings.begin(InstallStep::Partition);
+    if let Some(home) = home {
+        platform::home::check_reusable(
+            &partition_plan,
+            &platform::resume::Probe::of(&esp),
+            &platform::resume::Probe::of(&root),
+        )
+        .context("--preserve-home needs the existing ESP+root layout")?;
+        console::status(
+            Status::Ok,
+            &format!("Keeping the partition table (preserving {})", home.dev.display()),
+        );
+    } else if skip_partition {
+        console::status(Status::Ok, "Disk already partitioned by the previous attempt (skipping)");
+    } else {
+        progress.forget(InstallStep::Partition);
+        progress.forget(InstallStep::Format);
+        if partition_plan.lvm {
+            // A volume group left active by an earlier attempt keeps the disk busy.
+            let _ = platform::lvm::deactivate(&platform::command::SystemRunner);
         }
-        println!("[OK] User/password configured");
+        console::wait_with_spinner(
+            "Wiping disk signatures (wipefs)",
+            platform::partition::spawn_wipefs_all(&disk.dev_path),
+        )
+        .with_context(|| format!("wipefs failed for {}", disk.dev_path.display()))?;
+        platform::partition::zap_gpt(&disk.dev_path)
+            .context("Failed to clear the old partition tables")?;
+        console::status(Status::Ok, "Signatures and old partition tables wiped");
 
-        println!("[..] Enabling DHCP networking (systemd-networkd)");
-        let _ = std::io::stdout().flush();
-        if let Err(e) = platform::install::configure_first_boot_dhcp(&mount_plan) {
-            println!("[ERR] Networking setup failed: {e:#}");
-            had_error = true;
-            let _ = platform::install::unmount_target(&mount_plan);
-            break 'install;
+        reporter.message("Partitioning disk (GPT: ESP+root)");
+        platform::partition::partition_gpt_esp_root(&disk.dev_path, partition_plan)
+            .context("Partitioning failed")?;
+        progress.completed(InstallStep::Partition);
+        console::status(Status::Ok, "Disk partitioned");
+    }
+    console::status(Status::Ok, &format!("ESP partition: {}", esp.display()));
+    console::status(Status::Ok, &format!("Root partition: {}", root.display()));
+    if let Some(swap) = &swap {
+        console::status(Status::Ok, &format!("Swap partition: {}", swap.display()));
+    }
+
+    let root = if partition_plan.lvm {
+        reporter.message(&format!("Creating LVM volume group {}", platform::lvm::VOLUME_GROUP));
+        let volume = platform::lvm::create_root_volume(&platform::command::SystemRunner, &root)
+            .context("LVM setup failed")?;
+        console::status(Status::Ok, &format!("Root volume: {}", volume.display()));
+        volume
+    } else {
+        root
+    };
+
+    timings.begin(InstallStep::Format);
+    if skip_format {
+        console::status(
+            Status::Ok,
+            "Partitions already formatted by the previous attempt (skipping)",
+        );
+    } else {
+        reporter
+            .message(&format!("Formatting partitions (vfat+{})", settings.format.root_fs.fstype()));
+        platform::install::format_partitions(
+            &platform::command::SystemRunner,
+            &esp,
+            &root,
+            &settings.format,
+        )
+        .context("Formatting failed")?;
+        if let Some(swap) = &swap {
+            platform::install::format_swap(&platform::command::SystemRunner, swap)
+                .context("Formatting swap failed")?;
         }
-        println!("[OK] Networking configured (DHCP on boot)");
+        progress.completed(InstallStep::Format);
+        console::status(Status::Ok, "Partitions formatted");
+    }
 
-        println!("[..] Installing bootloader (systemd-boot)");
-        let _ = std::io::stdout().flush();
-        if let Err(e) =
-            platform::install::configure_boot_systemd_boot(&disk.dev_path, &esp, &root, &mount_plan)
+    reporter.message("Mounting target filesystem");
+    let mount_plan = platform::install::MountPlan {
+        root_fs: settings.format.root_fs,
+        ..platform::install::MountPlan::default()
+    };
+    let mut mounts =
+        platform::install::mount_partitions(&esp, &root, &mount_plan).context("Mount failed")?;
+    console::status(Status::Ok, &format!("Mounted root at {}", mount_plan.target_root.display()));
+
+    let leftovers = platform::install::leftover_entries(&mount_plan)
+        .context("Could not inspect the mounted root")?;
+    if !leftovers.is_empty() {
+        let shown: Vec<_> = leftovers.iter().take(5).map(|p| p.display().to_string()).collect();
+        console::status(
+            Status::Warn,
+            &format!("The freshly formatted root is not empty: {}", shown.join(", ")),
+        );
+        if unattended
+            || !prompt_line(
+                "[!!] Type Y and press ENTER to extract over it, or press ENTER to stop",
+            )?
+            .trim()
+            .eq_ignore_ascii_case("y")
         {
-            println!("[ERR] Boot config failed: {e:#}");
-            had_error = true;
-            let _ = platform::install::unmount_target(&mount_plan);
-            break 'install;
+            return Err(anyhow!("Target root {} is not empty", mount_plan.target_root.display()));
         }
-        println!("[OK] Boot configured");
+    }
 
-        println!("[..] Syncing disks");
-        let _ = std::io::stdout().flush();
-        if let Err(e) = platform::install::sync_disks() {
-            println!("[ERR] Sync failed: {e:#}");
-            had_error = true;
-            let _ = platform::install::unmount_target(&mount_plan);
-            break 'install;
-        }
-        println!("[OK] Disks synced");
+    timings.begin(InstallStep::Extract);
+    // From here on the filesystems hold data, so a retry must format them again.
+    progress.forget(InstallStep::Format);
+    reporter.message("Extracting Debian rootfs payload");
+    platform::install::extract_rootfs_payload(&payload_path, &mount_plan.target_root, &reporter)
+        .context("Extract failed")?;
+    console::status(Status::Ok, "Rootfs extracted");
+    if let Some(home) = home {
+        reporter.message(&format!("Mounting preserved /home ({})", home.dev.display()));
+        platform::home::mount_home(&mut mounts, home, &mount_plan)
+            .context("Mounting /home failed")?;
+        console::status(Status::Ok, "/home mounted");
+    }
+    if partition_plan.lvm {
+        platform::lvm::check_target_support(&mount_plan).context("LVM layout unsupported")?;
+    }
 
-        println!("[..] Unmounting target");
-        let _ = std::io::stdout().flush();
-        if let Err(e) = platform::install::unmount_target(&mount_plan) {
-            println!("[ERR] Unmount failed: {e:#}");
-            had_error = true;
-            break 'install;
+    timings.begin(InstallStep::Configure);
+    reporter.message("Mounting /proc, /sys, /dev into target");
+    platform::install::bind_mount_pseudo_filesystems(&mut mounts, &mount_plan.target_root)
+        .context("Pseudo filesystem mounts failed")?;
+    console::status(Status::Ok, "Pseudo filesystems mounted");
+
+    reporter.message(&format!("Setting hostname to {}", settings.hostname));
+    platform::install::configure_hostname(&mount_plan, &settings.hostname)
+        .context("Hostname setup failed")?;
+    console::status(Status::Ok, "Hostname configured");
+
+    if let Some(os_release) = &settings.os_release {
+        reporter.message("Writing os-release branding");
+        platform::os_release::configure_os_release(&mount_plan, os_release)
+            .context("os-release branding failed")?;
+        console::status(Status::Ok, "os-release updated");
+    }
+
+    reporter.message(&format!(
+        "Creating initial user ({}) + setting passwords",
+        settings.users.username
+    ));
+    platform::install::configure_initial_users(
+        &platform::command::SystemRunner,
+        &mount_plan,
+        &settings.users,
+    )
+    .context("User setup failed")?;
+    console::status(Status::Ok, "User/password configured");
+
+    if let Some(key) = &settings.ssh_authorized_key {
+        reporter.message(&format!("Installing SSH key for {}", settings.users.username));
+        platform::install::install_authorized_key(&mount_plan, &settings.users.username, key)
+            .context("SSH key setup failed")?;
+        console::status(Status::Ok, "SSH key installed");
+    }
+
+    if let Some(timezone) = &settings.timezone {
+        reporter.message(&format!("Setting timezone to {timezone}"));
+        platform::install::configure_timezone(&mount_plan, timezone)
+            .context("Timezone setup failed")?;
+        console::status(Status::Ok, "Timezone configured");
+    }
+
+    if let Some(locale) = &settings.locale {
+        reporter.message(&format!("Setting locale to {locale}"));
+        platform::install::configure_locale(&platform::command::SystemRunner, &mount_plan, locale)
+            .context("Locale setup failed")?;
+        console::status(Status::Ok, "Locale configured");
+    }
+
+    if settings.console_font.is_some() || settings.console_keymap.is_some() {
+        reporter.message("Configuring console font/keymap");
+        platform::install::configure_console(
+            &mount_plan,
+            settings.console_font.as_deref(),
+            settings.console_keymap.as_deref(),
+        )
+        .context("Console setup failed")?;
+        console::status(Status::Ok, "Console configured (/etc/vconsole.conf)");
+    }
+
+    let network_backend =
+        platform::install::select_network_backend(&mount_plan, settings.network_backend)
+            .context("Networking setup failed")?;
+    reporter.message(&format!("Enabling DHCP networking ({})", network_backend.name()));
+    platform::install::configure_first_boot_dhcp(&mount_plan, settings.ip_family, network_backend)
+        .context("Networking setup failed")?;
+    console::status(Status::Ok, "Networking configured (DHCP on boot)");
+
+    if let Some(debs) = &settings.debs {
+        reporter.message(&format!("Installing local packages from {}", debs.display()));
+        platform::install::install_local_debs(&platform::command::SystemRunner, &mount_plan, debs)
+            .context("Local package install failed")?;
+        console::status(Status::Ok, "Local packages installed");
+    }
+
+    if let Some(size) = settings.zram {
+        reporter.message("Configuring zram swap");
+        platform::install::configure_zram(&mount_plan, size).context("zram setup failed")?;
+        console::status(Status::Ok, "zram swap configured");
+    }
+
+    if let Some(target) = &settings.default_target {
+        reporter.message(&format!("Setting default target to {target}"));
+        platform::systemd::set_default_target(&mount_plan, target)
+            .context("Default target setup failed")?;
+        console::status(Status::Ok, "Default target set");
+    }
+
+    if !settings.units.is_empty() {
+        reporter.message("Applying systemd unit overrides");
+        platform::systemd::apply_unit_overrides(&mount_plan, &settings.units)
+            .context("Unit overrides failed")?;
+        console::status(Status::Ok, "Unit overrides applied");
+    }
+
+    if settings.regenerate_initramfs {
+        reporter.message("Regenerating initramfs");
+        platform::install::regenerate_initramfs(&platform::command::SystemRunner, &mount_plan)
+            .context("initramfs regeneration failed")?;
+        console::status(Status::Ok, "initramfs regenerated");
+    }
+
+    timings.begin(InstallStep::Boot);
+    match settings.bootloader {
+        platform::install::Bootloader::SystemdBoot => {
+            reporter.message("Installing bootloader (systemd-boot)");
+            platform::install::configure_boot_systemd_boot(
+                &disk.dev_path,
+                &esp,
+                &root,
+                &mount_plan,
+                register_nvram,
+            )
         }
-        println!("[OK] Unmounted target");
-        println!("[OK] Install complete (reboot and remove ISO)");
-        let _ = std::io::stdout().flush();
+        platform::install::Bootloader::Grub => {
+            reporter.message("Installing bootloader (GRUB)");
+            platform::install::configure_boot_grub(
+                &platform::command::SystemRunner,
+                &esp,
+                &root,
+                &mount_plan,
+                register_nvram,
+            )
+        }
+    }
+    .context("Boot config failed")?;
+    if !register_nvram {
+        // No NVRAM entry was written (image installs): the removable path is the only way in.
+        platform::install::check_fallback_loader(&mount_plan.target_efi)
+            .context("Image would not boot")?;
+    }
+    console::status(Status::Ok, "Boot configured");
+    if let Some(home) = home {
+        platform::home::add_fstab_entry(&mount_plan, home).context("fstab /home entry failed")?;
+        console::status(Status::Ok, "/home added to /etc/fstab");
+    }
+    if let Some(swap) = &swap {
+        platform::install::add_swap_fstab_entry(&mount_plan, swap)
+            .context("fstab swap entry failed")?;
+        console::status(Status::Ok, "Swap partition added to /etc/fstab");
     }
 
-    if had_error {
-        println!("[ERR] Installer encountered an error");
-    } else {
-        println!("[OK] Installer finished");
+    timings.begin(InstallStep::Finalize);
+    reporter.message("Syncing disks");
+    platform::install::sync_disks().context("Sync failed")?;
+    console::status(Status::Ok, "Disks synced");
+
+    reporter.message("Verifying installed system");
+    let root_uuid = platform::install::blkid_uuid(&root).context("Failed to get root UUID")?;
+    let esp_uuid = platform::install::blkid_uuid(&esp).context("Failed to get ESP UUID")?;
+    platform::install::final_verify(&mount_plan, settings.bootloader, &root_uuid, &esp_uuid)
+        .context("Final verification failed")?;
+    console::status(Status::Ok, "Installed system verified");
+
+    reporter.message("Unmounting target");
+    mounts.unmount_all().context("Unmount failed")?;
+    console::status(Status::Ok, "Unmounted target");
+    if partition_plan.lvm {
+        platform::lvm::deactivate(&platform::command::SystemRunner)
+            .context("Deactivating the volume group failed")?;
+        console::status(Status::Ok, "Volume group deactivated");
     }
-    let _ = std::io::stdout().flush();
 
-    prompt_enter("[!!] Press ENTER to reboot")?;
-    reboot_best_effort();
+    timings.finish();
+    for line in timings.summary_lines() {
+        println!("[..] Step time: {line}");
+    }
+    let _ = std::io::stdout().flush();
 
     Ok(())
 }
 
+/// Start the `--status-port` endpoint. Monitoring is optional, so problems only warn.
+fn start_status_server(port: u16, status: &StatusBoard) -> Option<status::StatusServer> {
+    if !status::network_link_up(Path::new("/sys/class/net")) {
+        console::status(
+            Status::Warn,
+            &format!("No network link is up; status endpoint on port {port} disabled"),
+        );
+        return None;
+    }
+    match status::serve(port, status.clone()) {
+        Ok(server) => {
+            console::status(Status::Ok, &format!("Serving install status on port {port}"));
+            Some(server)
+        }
+        Err(e) => {
+            eprintln!("WARN: status endpoint unavailable: {e:#}");
+            None
+        }
+    }
+}
+
+/// Fetch the payload into RAM (once; a retried install reuses it) and check its SHA-256.
+fn download_payload(url: &str, sha256: Option<&str>) -> Result<PathBuf> {
+    let dest = PathBuf::from(platform::payload::DOWNLOAD_PATH);
+    if dest.exists() {
+        console::status(Status::Ok, "Rootfs payload already downloaded");
+        return Ok(dest);
+    }
+
+    println!("[..] Downloading rootfs payload from {url}");
+    let rx = platform::payload::fetch_payload(url, &dest)?;
+    console::wait_with_spinner("Downloading", rx)?;
+    if let Some(sha256) = sha256 {
+        println!("[..] Verifying payload checksum");
+        platform::payload::verify_sha256(&dest, sha256)?;
+    }
+    let size_mib = std::fs::metadata(&dest).map(|m| m.len() / (1024 * 1024)).unwrap_or(0);
+    console::status(Status::Ok, &format!("Rootfs payload downloaded ({size_mib} MiB)"));
+    Ok(dest)
+}
+
+/// `--verify-disk`: re-run the post-install checks against a disk that is already installed,
+/// mounting it read-only. Nothing on the disk is changed.
+fn verify_installed_disk(disk: &Path) -> Result<()> {
+    println!("[..] Verifying existing install on {} (read-only)", disk.display());
+    let (esp, root) = platform::partition::expected_esp_and_root_partitions(disk)
+        .context("Could not compute partition paths")?;
+    let runner = platform::command::SystemRunner;
+    let lvm = platform::resume::Probe::of(&root).get("TYPE") == Some(platform::lvm::PV_TYPE);
+    if !lvm {
+        return verify_installed_root(disk, &esp, &root);
+    }
+
+    println!(
+        "[..] {} is an LVM physical volume; activating {} read-only",
+        root.display(),
+        platform::lvm::VOLUME_GROUP
+    );
+    let root = platform::lvm::activate_read_only(&runner).context("LVM activation failed")?;
+    let result = verify_installed_root(disk, &esp, &root);
+    if let Err(e) = platform::lvm::deactivate(&runner) {
+        eprintln!("WARN: could not deactivate {}: {e:#}", platform::lvm::VOLUME_GROUP);
+    }
+    result
+}
+
+fn verify_installed_root(disk: &Path, esp: &Path, root: &Path) -> Result<()> {
+    let root_uuid = platform::install::blkid_uuid(root).context("Failed to get root UUID")?;
+    let esp_uuid = platform::install::blkid_uuid(esp).context("Failed to get ESP UUID")?;
+
+    let plan = platform::install::MountPlan {
+        root_fs: platform::install::installed_root_fs(root)?,
+        ..platform::install::MountPlan::default()
+    };
+    let mut mounts =
+        platform::install::mount_installed_read_only(esp, root, &plan).context("Mount failed")?;
+    let result = platform::install::detect_bootloader(&plan)
+        .ok_or_else(|| anyhow!("No systemd-boot entry or GRUB loader found on the ESP"))
+        .and_then(|bootloader| {
+            console::status(Status::Ok, &format!("Bootloader: {}", bootloader.name()));
+            platform::install::final_verify(&plan, bootloader, &root_uuid, &esp_uuid)
+        });
+    mounts.unmount_all().context("Unmount failed")?;
+
+    match result {
+        Ok(()) => {
+            console::status(Status::Ok, &format!("PASS: {} looks bootable", disk.display()));
+            Ok(())
+        }
+        Err(e) => {
+            console::print_error(&e);
+            Err(anyhow!("FAIL: {} did not pass verification", disk.display()))
+        }
+    }
+}
+
+/// Several disks qualify and none was named: let the operator pick one.
+fn select_disk(disks: Vec<platform::disks::Disk>) -> Result<platform::disks::Disk> {
+    let menu = console::Menu::new(disks);
+    println!("[..] Several disks are eligible:");
+    for line in menu.lines(platform::disks::Disk::menu_label) {
+        println!("[..]   {line}");
+    }
+    let answer = prompt_line(&format!(
+        "[!!] Type the number of the disk to install to (1-{}) and press ENTER",
+        menu.items().len()
+    ))?;
+    menu.choose(&answer).ok_or_else(|| anyhow!("No disk selected ('{}')", answer.trim()))
+}
+
+/// Explain why no disk was picked and ask whether to scan again (a disk may be attached late).
+fn offer_rescan(scanner: &platform::disks::DiskScanner) -> Result<bool> {
+    if let Ok(scanned) = scanner.scan_cached()
+        && scanned.iter().all(|(_, reason)| reason.is_some())
+    {
+        for line in platform::disks::explain_rejections(&scanned, scanner.min_size_bytes()) {
+            console::status(Status::Warn, &line);
+        }
+    }
+
+    let answer = prompt_line("[!!] Type R and press ENTER to re-scan, or press ENTER to give up")?;
+    Ok(answer.trim().eq_ignore_ascii_case("r"))
+}
+
+/// Show the install plan; last chance before anything is written. `false` when the operator
+/// cancels. Unattended installs only show it: the config file is the confirmation.
+fn confirm_install(
+    disk: &platform::disks::Disk,
+    settings: &config::InstallSettings,
+    home: Option<&platform::home::HomePartition>,
+    unattended: bool,
+) -> Result<bool> {
+    println!("[..] Install plan:");
+    println!(
+        "[..]   Target: {} ({}, {}{})",
+        disk.dev_path.display(),
+        platform::disks::human_size(disk.size_bytes),
+        disk.transport,
+        disk.model.as_deref().map(|m| format!(", {m}")).unwrap_or_default()
+    );
+    for line in settings.summary() {
+        println!("[..]   {line}");
+    }
+    if home.is_none()
+        && let Some(warning) = disk.existing_data_warning()
+    {
+        console::status(Status::Warn, &format!("  {warning}"));
+    }
+    if let Some(home) = home {
+        // Keeping data is the whole point here, so spell out what survives and what doesn't.
+        let (esp, root) = platform::partition::expected_esp_and_root_partitions(&disk.dev_path)?;
+        console::status(
+            Status::Warn,
+            &format!("  FORMATTED: {} (ESP), {} (root)", esp.display(), root.display()),
+        );
+        console::status(Status::Ok, &format!("  PRESERVED: {} -> /home", home.describe()));
+    }
+    if unattended {
+        console::status(Status::Warn, "--unattended: proceeding without confirmation");
+        return Ok(true);
+    }
+    let action = if home.is_some() { "reformat ESP+root (keeping /home) on" } else { "ERASE" };
+    let answer = prompt_line(&format!(
+        "[!!] About to {action} {}\n[!!] Type yes and press ENTER to continue; anything else cancels",
+        disk.dev_path.display()
+    ))?;
+    Ok(console::is_confirmation(&answer))
+}
+
+/// Ask for a setting, offering `default` on ENTER and asking again until the answer is valid.
+fn prompt_field(label: &str, default: &str, validate: fn(&str) -> Result<()>) -> Result<String> {
+    loop {
+        let answer = prompt_line(&format!("[..] {label} (ENTER for {default}):"))?;
+        match console::field_value(&answer, default, validate) {
+            Ok(value) => return Ok(value),
+            Err(e) => console::print_error(&e),
+        }
+    }
+}
+
 fn prompt_enter(message: &str) -> Result<()> {
-    println!("{message}");
+    prompt_line(message)?;
+    Ok(())
+}
+
+fn prompt_line(message: &str) -> Result<String> {
+    for line in message.lines() {
+        println!("{}", console::styled(line));
+    }
     let _ = std::io::stdout().flush();
 
     let mut line = String::new();
     let mut stdin = std::io::stdin().lock();
     let _ = stdin.read_line(&mut line)?;
-    Ok(())
+    Ok(line)
 }
 
 fn reboot_best_effort() {
@@ -0,0 +1,393 @@
+//! External command execution
+//!
+//! The installer drives system tools directly (no shell). Steps that need to be unit-tested
+//! take a `&dyn CommandRunner` so tests can record the exact invocations instead of running them.
+
+use anyhow::{Context, Result, anyhow};
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+const DEFAULT_PATH: &str = "/bin:/sbin:/usr/bin:/usr/sbin";
+
+/// Error text (from the tool's stderr) that means "try again shortly": the device is held
+/// by someone else for a moment, or udev has not created the node yet.
+const TRANSIENT_PATTERNS: [&str; 6] = [
+    "target is busy",
+    "Device or resource busy",
+    "special device",
+    "No such device or address",
+    // partprobe, while something still has a partition open.
+    "unable to inform the kernel",
+    // A tool killed by the policy's timeout (say, mount stuck on a device udev is still
+    // setting up) gets another try.
+    "timed out after",
+];
+
+/// The policy for racy tools, when `--command-retries` / `--command-timeout` changed it.
+static RACY_OVERRIDE: OnceLock<RetryPolicy> = OnceLock::new();
+
+/// How often to retry a command that failed transiently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total tries, including the first.
+    pub attempts: u32,
+    /// Delay before the second try; it grows linearly after that.
+    pub backoff: Duration,
+    /// Kill a try that runs longer than this; `None` waits for it however long it takes.
+    pub timeout: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// Default for tools that race with udev or short-lived openers (`mount`, `umount`,
+    /// `partprobe`).
+    pub const RACY: RetryPolicy = RetryPolicy {
+        attempts: 4,
+        backoff: Duration::from_millis(250),
+        timeout: Some(Duration::from_secs(60)),
+    };
+
+    /// The policy the install uses for racy tools: [`RetryPolicy::RACY`] unless
+    /// [`RetryPolicy::set_racy`] replaced it at startup.
+    pub fn racy() -> RetryPolicy {
+        RACY_OVERRIDE.get().copied().unwrap_or(RetryPolicy::RACY)
+    }
+
+    /// Replace the policy for racy tools (from the command line). Only the first call counts.
+    pub fn set_racy(policy: RetryPolicy) {
+        let _ = RACY_OVERRIDE.set(policy);
+    }
+}
+
+/// Whether a failure looks transient rather than a genuine error such as a bad argument.
+pub fn is_transient_failure(err: &anyhow::Error) -> bool {
+    let text = format!("{err:#}");
+    TRANSIENT_PATTERNS.iter().any(|pattern| text.contains(pattern))
+}
+
+pub trait CommandRunner: Send + Sync {
+    /// Run `program` with `args`, failing unless it exits successfully.
+    fn run(&self, program: &str, args: &[&str]) -> Result<()>;
+
+    /// Like [`CommandRunner::run`], but writes `input` to the program's stdin.
+    fn run_with_input(&self, program: &str, args: &[&str], input: &str) -> Result<()>;
+
+    /// Like [`CommandRunner::run`], but kill the program once it has run for `timeout`.
+    fn run_with_timeout(&self, program: &str, args: &[&str], timeout: Duration) -> Result<()> {
+        let _ = timeout;
+        self.run(program, args)
+    }
+
+    /// Like [`CommandRunner::run`], but retry transient failures (see [`is_transient_failure`])
+    /// as `policy` allows, each try bounded by the policy's timeout. Other failures are
+    /// returned immediately.
+    fn run_with_policy(&self, program: &str, args: &[&str], policy: RetryPolicy) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            let result = match policy.timeout {
+                Some(timeout) => self.run_with_timeout(program, args, timeout),
+                None => self.run(program, args),
+            };
+            match result {
+                Err(e) if attempt < policy.attempts && is_transient_failure(&e) => {
+                    std::thread::sleep(policy.backoff * attempt);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Runs commands on the host with a fixed `PATH`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> Result<()> {
+        run(program, args)
+    }
+
+    fn run_with_timeout(&self, program: &str, args: &[&str], timeout: Duration) -> Result<()> {
+        run_with_timeout(program, args, timeout)
+    }
+
+    fn run_with_input(&self, program: &str, args: &[&str], input: &str) -> Result<()> {
+        let mut child = command(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to spawn {program}"))?;
+
+        {
+            let stdin = child
+                .stdin
+                .as_mut()
+                .ok_or_else(|| anyhow!("Failed to open stdin for {program}"))?;
+            stdin
+                .write_all(input.as_bytes())
+                .with_context(|| format!("Failed to write stdin for {program}"))?;
+        }
+
+        let output =
+            child.wait_with_output().with_context(|| format!("Failed to wait for {program}"))?;
+        if output.status.success() {
+            return Ok(());
+        }
+
+        Err(anyhow!(
+            "{program} failed: stdout='{}' stderr='{}'",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+}
+
+pub fn run(program: &str, args: &[&str]) -> Result<()> {
+    let output = command(program)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .with_context(|| format!("Failed to execute {program}"))?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    Err(anyhow!(
+        "{program} failed: stdout='{}' stderr='{}'",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    ))
+}
+
+/// Like [`run`], but kill `program` and fail once it has run for `timeout`.
+pub fn run_with_timeout(program: &str, args: &[&str], timeout: Duration) -> Result<()> {
+    let mut child = command(program)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to spawn {program}"))?;
+    // Drain the pipes while waiting so a chatty tool cannot block on a full pipe.
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) =
+            child.try_wait().with_context(|| format!("Failed to wait for {program}"))?
+        {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!("{program} timed out after {}s", timeout.as_secs_f32()));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+
+    if status.success() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "{program} failed: stdout='{}' stderr='{}'",
+        stdout.join().unwrap_or_default(),
+        stderr.join().unwrap_or_default()
+    ))
+}
+
+fn drain(pipe: Option<impl Read + Send + 'static>) -> std::thread::JoinHandle<String> {
+    std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    })
+}
+
+/// Like [`run`], but return stdout on success.
+pub fn run_output(program: &str, args: &[&str]) -> Result<String> {
+    let output = command(program)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .with_context(|| format!("Failed to execute {program}"))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if output.status.success() {
+        return Ok(stdout);
+    }
+
+    Err(anyhow!(
+        "{program} failed: stdout='{}' stderr='{}'",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    ))
+}
+
+/// Run `program` on a background thread; the result arrives on the returned channel.
+///
+/// Lets the caller keep the console alive (see `console::wait_with_spinner`) while a
+/// disk-bound tool runs.
+pub fn run_async(program: &str, args: &[&str]) -> Receiver<Result<()>> {
+    let program = program.to_string();
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let _ = tx.send(run(&program, &args));
+    });
+    rx
+}
+
+pub fn command(program: &str) -> Command {
+    let mut cmd = Command::new(program);
+    cmd.env("PATH", DEFAULT_PATH);
+    cmd
+}
+
+/// Whether `program` is an executable on the installer's PATH. Unlike [`command_exists`] it
+/// does not run the tool, so it also works for tools without `--version` (mkfs.xfs, mke2fs).
+pub fn program_on_path(program: &str) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    DEFAULT_PATH.split(':').any(|dir| {
+        std::fs::metadata(std::path::Path::new(dir).join(program))
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    })
+}
+
+pub fn command_exists(program: &str) -> bool {
+    command(program)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records every invocation (as a space-joined command line) and succeeds, unless the
+    /// program was marked with [`RecordingRunner::failing_on`] or
+    /// [`RecordingRunner::failing_first`].
+    #[derive(Debug, Default)]
+    pub struct RecordingRunner {
+        calls: Mutex<Vec<String>>,
+        fail_program: Option<String>,
+        /// Program, remaining failures, and the stderr they report.
+        flaky: Option<(String, Mutex<u32>, String)>,
+    }
+
+    impl RecordingRunner {
+        pub fn failing_on(program: &str) -> Self {
+            Self { fail_program: Some(program.to_string()), ..Self::default() }
+        }
+
+        /// Fail the first `times` runs of `program` with `stderr`, then succeed.
+        pub fn failing_first(program: &str, times: u32, stderr: &str) -> Self {
+            Self {
+                flaky: Some((program.to_string(), Mutex::new(times), stderr.to_string())),
+                ..Self::default()
+            }
+        }
+
+        pub fn commands(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn record(&self, program: &str, args: &[&str], input: Option<&str>) -> Result<()> {
+            let mut line = program.to_string();
+            for arg in args {
+                line.push(' ');
+                line.push_str(arg);
+            }
+            if let Some(input) = input {
+                line.push_str(" < ");
+                line.push_str(input.trim_end());
+            }
+            self.calls.lock().unwrap().push(line);
+            if self.fail_program.as_deref() == Some(program) {
+                return Err(anyhow!("{program} failed"));
+            }
+            if let Some((flaky, remaining, stderr)) = &self.flaky {
+                let mut remaining = remaining.lock().unwrap();
+                if flaky == program && *remaining > 0 {
+                    *remaining -= 1;
+                    return Err(anyhow!("{program} failed: stdout='' stderr='{stderr}'"));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl CommandRunner for RecordingRunner {
+        fn run(&self, program: &str, args: &[&str]) -> Result<()> {
+            self.record(program, args, None)
+        }
+
+        fn run_with_input(&self, program: &str, args: &[&str], input: &str) -> Result<()> {
+            self.record(program, args, Some(input))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::RecordingRunner;
+    use super::*;
+
+    const QUICK: RetryPolicy = RetryPolicy { attempts: 3, backoff: Duration::ZERO, timeout: None };
+
+    #[test]
+    fn transient_failure_then_success_is_ok() {
+        let runner = RecordingRunner::failing_first("mount", 2, "mount: /mnt: target is busy.");
+        runner.run_with_policy("mount", &["/dev/vda2", "/mnt"], QUICK).unwrap();
+        assert_eq!(runner.commands().len(), 3);
+    }
+
+    #[test]
+    fn retries_stop_at_the_policy_limit() {
+        let runner = RecordingRunner::failing_first("umount", 5, "umount: /mnt: target is busy.");
+        let err = runner.run_with_policy("umount", &["/mnt"], QUICK).unwrap_err();
+        assert!(is_transient_failure(&err));
+        assert_eq!(runner.commands().len(), 3);
+    }
+
+    #[test]
+    fn permanent_failures_are_not_retried() {
+        let runner = RecordingRunner::failing_first(
+            "mount",
+            1,
+            "mount: /mnt: unknown filesystem type 'xfs'.",
+        );
+        assert!(
+            runner.run_with_policy("mount", &["-t", "xfs", "/dev/vda2", "/mnt"], QUICK).is_err()
+        );
+        assert_eq!(runner.commands().len(), 1);
+    }
+
+    #[test]
+    fn hung_commands_are_killed_at_the_timeout() {
+        let started = Instant::now();
+        let err = run_with_timeout("sleep", &["5"], Duration::from_millis(100)).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(is_transient_failure(&err), "{err:#}");
+        run_with_timeout("true", &[], Duration::from_secs(5)).unwrap();
+        let err = run_with_timeout("sh", &["-c", "echo oops >&2; exit 3"], Duration::from_secs(5))
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("stderr='oops"), "{err:#}");
+    }
+}
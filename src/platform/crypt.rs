@@ -0,0 +1,143 @@
+//! In-process password hashing
+//!
+//! Passwords are hashed before anything is handed to the target, so `chpasswd` only ever sees
+//! crypt(3) strings (`chpasswd -e`). SHA-512-crypt (`$6$`, the default) is understood by every
+//! glibc and Debian's PAM; yescrypt (`$y$`) is what Debian 11+ uses for new passwords. Both come
+//! from the RustCrypto `sha-crypt` and `yescrypt` crates.
+
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use sha_crypt::{PasswordHasher, ShaCrypt};
+use std::io::Read;
+use std::str::FromStr;
+use yescrypt::Yescrypt;
+
+/// The crypt(3) scheme plaintext passwords are hashed with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordScheme {
+    /// `$6$`: SHA-512-crypt, 5000 rounds.
+    #[default]
+    Sha512,
+    /// `$y$`: yescrypt with the reference defaults (16 MiB per hash).
+    Yescrypt,
+}
+
+impl PasswordScheme {
+    /// Random salt bytes per hash. SHA-crypt uses at most 16 salt characters, which is what
+    /// 12 bytes encode to.
+    fn salt_len(self) -> usize {
+        match self {
+            PasswordScheme::Sha512 => 12,
+            PasswordScheme::Yescrypt => 16,
+        }
+    }
+}
+
+impl FromStr for PasswordScheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha512" => Ok(PasswordScheme::Sha512),
+            "yescrypt" => Ok(PasswordScheme::Yescrypt),
+            other => {
+                Err(anyhow!("Invalid password scheme '{other}' (expected sha512 or yescrypt)"))
+            }
+        }
+    }
+}
+
+/// Hash `password` with `scheme` and a random salt.
+pub fn hash_password(password: &str, scheme: PasswordScheme) -> Result<String> {
+    let mut salt = vec![0u8; scheme.salt_len()];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut salt))
+        .context("Failed to read /dev/urandom for password salt")?;
+    let hash = match scheme {
+        PasswordScheme::Sha512 => {
+            ShaCrypt::SHA512.hash_password_with_salt(password.as_bytes(), &salt)
+        }
+        PasswordScheme::Yescrypt => {
+            Yescrypt::default().hash_password_with_salt(password.as_bytes(), &salt)
+        }
+    };
+    let hash = hash.map_err(|e| anyhow!("Password hashing failed: {e}"))?;
+    Ok(hash.as_str().to_string())
+}
+
+/// Check that `hash` looks like a crypt(3) string that `chpasswd -e` will accept verbatim.
+pub fn validate_crypt_hash(hash: &str) -> Result<()> {
+    let fields: Vec<&str> = hash.split('$').collect();
+    // "$id$[params$]salt$hash" splits into ["", id, ..., hash].
+    let valid = fields.len() >= 4
+        && fields[0].is_empty()
+        && !fields[1].is_empty()
+        && fields.last().is_some_and(|h| !h.is_empty())
+        && !hash.contains([':', '\n', '\r', ' ']);
+    if !valid {
+        return Err(anyhow!("Invalid password hash (expected a crypt(3) string like $6$salt$...)"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha_crypt::{PasswordHashRef, PasswordVerifier};
+
+    fn sha512_verifies(password: &str, hash: &str) -> bool {
+        let hash = PasswordHashRef::new(hash).unwrap();
+        ShaCrypt::SHA512.verify_password(password.as_bytes(), hash).is_ok()
+    }
+
+    fn yescrypt_verifies(password: &str, hash: &str) -> bool {
+        let hash = yescrypt::PasswordHashRef::new(hash).unwrap();
+        Yescrypt::default().verify_password(password.as_bytes(), hash).is_ok()
+    }
+
+    #[test]
+    fn matches_reference_vectors() {
+        // From the SHA-crypt specification.
+        let sha512 = [
+            "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1",
+            "$6$rounds=10000$saltstringsaltst$OW1/O6BYHV6BcXZu8QVeXbDWra3Oeqh0sbHbbMCVNSnCM/UrjmM0Dp8vOuZeHBy/YTBmSK6H9qs/y3RnOaw5v.",
+        ];
+        for hash in sha512 {
+            assert!(sha512_verifies("Hello world!", hash), "{hash}");
+            assert!(!sha512_verifies("Hello world?", hash), "{hash}");
+        }
+        // From the yescrypt reference implementation's TESTS-OK.
+        let yescrypt = "$y$j0/$LdJM$k7BXzSDuoGHW56SY3HxROCiA0gWRscZe2aA0q5oHPM0";
+        assert!(yescrypt_verifies("pleaseletmein", yescrypt));
+        assert!(!yescrypt_verifies("pleaseletmeout", yescrypt));
+    }
+
+    #[test]
+    fn random_salts_differ() {
+        let a = hash_password("123456", PasswordScheme::Sha512).unwrap();
+        let b = hash_password("123456", PasswordScheme::Sha512).unwrap();
+        assert!(a.starts_with("$6$"));
+        assert_ne!(a, b);
+        validate_crypt_hash(&a).unwrap();
+        assert!(sha512_verifies("123456", &a));
+    }
+
+    #[test]
+    fn yescrypt_hashes_verify() {
+        let hash = hash_password("123456", PasswordScheme::Yescrypt).unwrap();
+        assert!(hash.starts_with("$y$"), "{hash}");
+        validate_crypt_hash(&hash).unwrap();
+        assert!(yescrypt_verifies("123456", &hash));
+        assert_eq!("yescrypt".parse::<PasswordScheme>().unwrap(), PasswordScheme::Yescrypt);
+        assert!("md5".parse::<PasswordScheme>().is_err());
+    }
+
+    #[test]
+    fn hash_validation() {
+        assert!(validate_crypt_hash("$y$j9T$abc$def").is_ok());
+        assert!(validate_crypt_hash("plaintext").is_err());
+        assert!(validate_crypt_hash("$6$salt$").is_err());
+        assert!(validate_crypt_hash("$6$sa:lt$hash").is_err());
+    }
+}
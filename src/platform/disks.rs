@@ -1,6 +1,10 @@
+use super::resume::Probe;
 use anyhow::{Context, Result, anyhow};
+use std::cell::{Cell, RefCell};
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Disk {
@@ -8,13 +12,137 @@ pub struct Disk {
     pub dev_path: PathBuf,
     pub size_bytes: u64,
     pub model: Option<String>,
+    pub transport: Transport,
+    /// Spinning disk (`queue/rotational`); `None` when the kernel does not say.
+    pub rotational: Option<bool>,
+    /// Partitions already on the disk; only filled in for eligible disks.
+    pub partitions: Vec<Partition>,
 }
 
+/// A partition found on a disk when it was scanned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub name: String,
+    pub size_bytes: u64,
+    /// Filesystem (or other signature, e.g. `swap`) blkid found on it.
+    pub fstype: Option<String>,
+}
+
+/// How a disk is attached, for display; hardware RAID volumes usually show up as SCSI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Sata,
+    Nvme,
+    Usb,
+    Virtio,
+    Scsi,
+    Mmc,
+    Xen,
+    Loop,
+    Unknown,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Transport::Sata => "SATA",
+            Transport::Nvme => "NVMe",
+            Transport::Usb => "USB",
+            Transport::Virtio => "virtio",
+            Transport::Scsi => "SCSI/SAS",
+            Transport::Mmc => "MMC",
+            Transport::Xen => "Xen",
+            Transport::Loop => "loop",
+            Transport::Unknown => "unknown",
+        };
+        f.write_str(text)
+    }
+}
+
+impl Disk {
+    /// How the disk is listed when the operator picks one, e.g.
+    /// `/dev/nvme0n1  476.9 GiB  NVMe SSD  Samsung SSD 980`.
+    pub fn menu_label(&self) -> String {
+        let media = match self.rotational {
+            Some(true) => " HDD",
+            Some(false) => " SSD",
+            None => "",
+        };
+        format!(
+            "{}  {}  {}{media}  {}",
+            self.dev_path.display(),
+            human_size(self.size_bytes),
+            self.transport,
+            self.model.as_deref().unwrap_or("(unknown model)")
+        )
+    }
+
+    /// A warning naming the filesystems that installing to this disk would destroy, if any.
+    pub fn existing_data_warning(&self) -> Option<String> {
+        let found: Vec<String> = self
+            .partitions
+            .iter()
+            .filter_map(|part| {
+                let fstype = part.fstype.as_deref()?;
+                Some(format!("{fstype} on {} ({})", part.name, human_size(part.size_bytes)))
+            })
+            .collect();
+        (!found.is_empty())
+            .then(|| format!("WARNING: disk contains existing data: {}", found.join(", ")))
+    }
+}
+
+/// Why a block device was not offered as an install target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    Removable,
+    ReadOnly,
+    TooSmall,
+    Mounted,
+    NoBackingDevice,
+    VirtualName,
+    /// Its sysfs attributes could not be read (e.g. hot-removed mid-scan).
+    Unreadable,
+    /// The running installer was booted from it.
+    BootMedium,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            RejectReason::Removable => "removable",
+            RejectReason::ReadOnly => "read-only",
+            RejectReason::TooSmall => "too small",
+            RejectReason::Mounted => "mounted",
+            RejectReason::NoBackingDevice => "no backing device",
+            RejectReason::VirtualName => "virtual device",
+            RejectReason::Unreadable => "unreadable",
+            RejectReason::BootMedium => "boot medium",
+        };
+        f.write_str(text)
+    }
+}
+
+type ScanResult = Vec<(Disk, Option<RejectReason>)>;
+
+/// Smallest disk offered as an install target unless `--min-disk-size` says otherwise.
+pub const DEFAULT_MIN_SIZE_BYTES: u64 = 8 * 1024 * 1024 * 1024;
+
 #[derive(Debug, Clone)]
 pub struct DiskScanner {
     sys_root: PathBuf,
     proc_root: PathBuf,
     min_size_bytes: u64,
+    /// How long [`DiskScanner::scan_cached`] may reuse the last scan; `None` disables caching.
+    cache_ttl: Option<Duration>,
+    cache: RefCell<Option<(Instant, ScanResult)>>,
+    /// Finds the filesystem type on a partition device (blkid; replaced in tests).
+    fstype_probe: fn(&Path) -> Option<String>,
+    /// Offer removable devices (e.g. a USB SSD) too.
+    allow_removable: bool,
+    /// The boot medium exclusion is logged on the first scan only; scans repeat while a menu
+    /// or re-scan prompt is up.
+    boot_medium_logged: Cell<bool>,
 }
 
 impl DiskScanner {
@@ -23,18 +151,77 @@ impl DiskScanner {
         proc_root: impl Into<PathBuf>,
         min_size_bytes: u64,
     ) -> Self {
-        Self { sys_root: sys_root.into(), proc_root: proc_root.into(), min_size_bytes }
+        Self {
+            sys_root: sys_root.into(),
+            proc_root: proc_root.into(),
+            min_size_bytes,
+            cache_ttl: None,
+            cache: RefCell::new(None),
+            fstype_probe: probe_fstype,
+            allow_removable: false,
+            boot_medium_logged: Cell::new(false),
+        }
+    }
+
+    /// Let [`DiskScanner::eligible_disks`] and [`DiskScanner::scan_cached`] reuse a scan younger
+    /// than `ttl` instead of re-reading sysfs.
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Also offer disks sysfs reports as removable. Mounted and read-only disks stay excluded.
+    pub fn with_allow_removable(mut self, allow: bool) -> Self {
+        self.allow_removable = allow;
+        self
+    }
+
+    /// Drop the cached scan, e.g. when the operator asks for a re-scan.
+    pub fn invalidate(&self) {
+        self.cache.borrow_mut().take();
     }
 
     pub fn new_default() -> Self {
-        // MVP safety threshold; can be made configurable later.
-        const GIB: u64 = 1024 * 1024 * 1024;
-        Self::new("/sys", "/proc", 8 * GIB)
+        Self::new("/sys", "/proc", DEFAULT_MIN_SIZE_BYTES)
     }
 
-    pub fn eligible_disks(&self) -> Result<Vec<Disk>> {
+    /// Refuse disks smaller than `bytes` instead of [`DEFAULT_MIN_SIZE_BYTES`].
+    pub fn with_min_size_bytes(mut self, bytes: u64) -> Self {
+        self.min_size_bytes = bytes;
+        self
+    }
+
+    pub fn min_size_bytes(&self) -> u64 {
+        self.min_size_bytes
+    }
+
+    /// Like [`DiskScanner::scan_all`], but reuse the previous result while it is fresh.
+    pub fn scan_cached(&self) -> Result<ScanResult> {
+        if let (Some(ttl), Some((scanned_at, scanned))) = (self.cache_ttl, &*self.cache.borrow())
+            && scanned_at.elapsed() < ttl
+        {
+            return Ok(scanned.clone());
+        }
+        self.scan_all()
+    }
+
+    /// Enumerate every whole-disk block device together with the reason it was rejected, if any.
+    ///
+    /// Always reads sysfs, for callers that must judge current state (an explicitly requested
+    /// disk, the image loop device). The result refreshes the cache.
+    pub fn scan_all(&self) -> Result<ScanResult> {
+        let scanned = self.read_sysfs()?;
+        if self.cache_ttl.is_some() {
+            *self.cache.borrow_mut() = Some((Instant::now(), scanned.clone()));
+        }
+        Ok(scanned)
+    }
+
+    fn read_sysfs(&self) -> Result<ScanResult> {
         let block_dir = self.sys_root.join("block");
-        let mut disks = Vec::new();
+        let mounted = mounted_sources(&self.proc_root);
+        let boot = boot_device(&self.proc_root);
+        let mut scanned = Vec::new();
 
         for entry in fs::read_dir(&block_dir)
             .with_context(|| format!("Failed to read {}", block_dir.display()))?
@@ -43,40 +230,127 @@ impl DiskScanner {
             let file_name = entry.file_name();
             let name = file_name.to_string_lossy().to_string();
             let disk_sys = entry.path();
-            if !is_candidate_block_device(&disk_sys, &name) {
+            if !is_block_device_entry(&disk_sys) || is_nvme_controller_path(&name) {
                 continue;
             }
 
-            // If there is no backing device directory, it is unlikely to be a real disk.
-            if !disk_sys.join("device").exists() {
-                continue;
-            }
+            let mut reason = rejected_by_sysfs(&disk_sys, &name, self.allow_removable);
 
-            if read_u64(disk_sys.join("removable")).unwrap_or(1) != 0 {
-                continue;
+            // One disk with a bad size (e.g. hot-removed mid-scan) must not abort the scan.
+            let size_bytes = match disk_size_bytes(&disk_sys) {
+                Ok(size_bytes) => size_bytes,
+                Err(e) => {
+                    if reason.is_none() {
+                        eprintln!("WARN: skipping {name}: {e:#}");
+                        reason = Some(RejectReason::Unreadable);
+                    }
+                    0
+                }
+            };
+            if reason.is_none() && is_device_mounted(&mounted, &name) {
+                reason = Some(RejectReason::Mounted);
             }
-            if read_u64(disk_sys.join("ro")).unwrap_or(1) != 0 {
-                continue;
+            // Some live USB sticks claim to be fixed disks, and a root the kernel mounted
+            // itself shows up as `/dev/root`, so the checks above can miss the boot medium.
+            if let Some(boot) = boot.as_deref()
+                && reason.is_none()
+                && holds(&disk_sys, &name, boot)
+            {
+                if !self.boot_medium_logged.replace(true) {
+                    eprintln!(
+                        "DEBUG: not offering /dev/{name}: the installer was booted from /dev/{boot}"
+                    );
+                }
+                reason = Some(RejectReason::BootMedium);
             }
-
-            let size_bytes = disk_size_bytes(&disk_sys)
-                .with_context(|| format!("Failed to read size for {name}"))?;
-            if size_bytes < self.min_size_bytes {
-                continue;
-            }
-
-            let dev_path = PathBuf::from("/dev").join(&name);
-            if is_device_mounted(&self.proc_root, &name)? {
-                continue;
+            // Checked last: `--force` waives a disk whose only problem is its size.
+            if reason.is_none() && size_bytes < self.min_size_bytes {
+                reason = Some(RejectReason::TooSmall);
             }
 
+            let dev_path = dev_path_for(&name);
             let model = read_string(disk_sys.join("device").join("model")).ok();
+            let transport = transport_of(&disk_sys, &name);
+            let rotational =
+                read_u64(disk_sys.join("queue").join("rotational")).ok().map(|value| value != 0);
+            // Probing every partition runs blkid; only disks that may be offered need it.
+            let partitions = match reason {
+                None => self.read_partitions(&disk_sys),
+                Some(_) => Vec::new(),
+            };
 
-            disks.push(Disk { name, dev_path, size_bytes, model });
+            scanned.push((
+                Disk { name, dev_path, size_bytes, model, transport, rotational, partitions },
+                reason,
+            ));
         }
 
-        disks.sort_by(|a, b| a.name.cmp(&b.name));
-        Ok(disks)
+        scanned.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+        Ok(scanned)
+    }
+
+    fn read_partitions(&self, disk_sys: &Path) -> Vec<Partition> {
+        sysfs_partitions(disk_sys)
+            .into_iter()
+            .map(|(_, name)| Partition {
+                size_bytes: disk_size_bytes(&disk_sys.join(&name)).unwrap_or(0),
+                fstype: (self.fstype_probe)(&dev_path_for(&name)),
+                name,
+            })
+            .collect()
+    }
+
+    /// Eligible disks for display; may come from the cache (see [`DiskScanner::with_cache_ttl`]).
+    pub fn eligible_disks(&self) -> Result<Vec<Disk>> {
+        Ok(self
+            .scan_cached()?
+            .into_iter()
+            .filter_map(|(disk, reason)| reason.is_none().then_some(disk))
+            .collect())
+    }
+
+    /// Pick an explicitly requested disk (e.g. `/dev/sda`), which must still be eligible.
+    ///
+    /// `force` waives only the size floor; mounted, read-only, or (unless
+    /// [`DiskScanner::with_allow_removable`]) removable devices are refused regardless.
+    pub fn choose_disk(&self, dev_path: &Path, force: bool) -> Result<Disk> {
+        let scanned = self.scan_all()?;
+        match scanned.into_iter().find(|(disk, _)| disk.dev_path == dev_path) {
+            Some((disk, None)) => Ok(disk),
+            Some((disk, Some(RejectReason::TooSmall))) if force => Ok(disk),
+            Some((_, Some(reason))) => {
+                Err(anyhow!("Requested disk {} is not eligible: {reason}", dev_path.display()))
+            }
+            None => Err(anyhow!("Requested disk {} was not found", dev_path.display())),
+        }
+    }
+
+    /// The loop device backing an `--image` install. Loop devices are never offered by the
+    /// normal scan; here only mounted or read-only ones are refused, and there is no size floor
+    /// (the image was sized explicitly).
+    pub fn image_disk(&self, dev_path: &Path) -> Result<Disk> {
+        let scanned = self.scan_all()?;
+        match scanned.into_iter().find(|(disk, _)| disk.dev_path == dev_path) {
+            Some((disk, _)) if is_device_mounted(&mounted_sources(&self.proc_root), &disk.name) => {
+                Err(anyhow!("Loop device {} is mounted", dev_path.display()))
+            }
+            Some((disk, _))
+                if read_u64(self.sys_root.join("block").join(&disk.name).join("ro"))? != 0 =>
+            {
+                Err(anyhow!("Loop device {} is read-only", dev_path.display()))
+            }
+            Some((disk, _)) => Ok(disk),
+            None => Err(anyhow!("Loop device {} was not found", dev_path.display())),
+        }
+    }
+
+    /// `--auto-largest`: the largest eligible disk, ties going to the first name in sort order.
+    /// Fails only when nothing is eligible.
+    pub fn choose_largest_disk(&self) -> Result<Disk> {
+        self.eligible_disks()?
+            .into_iter()
+            .reduce(|best, disk| if disk.size_bytes > best.size_bytes { disk } else { best })
+            .ok_or_else(|| anyhow!("No eligible disks found"))
     }
 
     pub fn choose_single_target_disk(&self) -> Result<Disk> {
@@ -98,11 +372,84 @@ impl DiskScanner {
     }
 }
 
-fn is_candidate_block_device(disk_sys: &Path, name: &str) -> bool {
-    if is_excluded_device_class(name) {
-        return false;
+/// Turn scan results into user-facing hints for why no disk was offered.
+///
+/// Virtual devices (loop, ram, ...) are always present and never interesting, so they are
+/// only mentioned when nothing else was found.
+pub fn explain_rejections(
+    scanned: &[(Disk, Option<RejectReason>)],
+    min_size_bytes: u64,
+) -> Vec<String> {
+    let real: Vec<_> =
+        scanned.iter().filter(|(_, reason)| *reason != Some(RejectReason::VirtualName)).collect();
+
+    if real.is_empty() {
+        return vec![
+            "No disks detected at all (is the storage controller supported by this kernel?)"
+                .to_string(),
+        ];
+    }
+
+    let mut lines = vec![match real.len() {
+        1 => "Found 1 disk but it cannot be used:".to_string(),
+        n => format!("Found {n} disks but none can be used:"),
+    }];
+
+    for (disk, reason) in &real {
+        let dev = disk.dev_path.display();
+        let line = match reason {
+            Some(RejectReason::Removable) => format!("{dev} is removable"),
+            Some(RejectReason::ReadOnly) => format!("{dev} is read-only"),
+            Some(RejectReason::TooSmall) => format!(
+                "{dev} is too small ({}, need >= {})",
+                human_size(disk.size_bytes),
+                human_size(min_size_bytes)
+            ),
+            Some(RejectReason::Mounted) => format!("{dev} is mounted"),
+            Some(RejectReason::NoBackingDevice) => format!("{dev} has no backing device"),
+            Some(RejectReason::Unreadable) => format!("{dev} could not be read from sysfs"),
+            Some(RejectReason::BootMedium) => format!("{dev} is the installer's boot medium"),
+            Some(RejectReason::VirtualName) | None => continue,
+        };
+        lines.push(format!("  - {line}"));
+    }
+
+    if real.iter().all(|(_, reason)| *reason == Some(RejectReason::Mounted)) {
+        lines.push("All disks are mounted; unmount them or boot the installer again.".to_string());
+    }
+
+    lines
+}
+
+/// Parse a byte count with an optional binary suffix: `4G`, `512M`, `16GiB`, `1073741824`.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let invalid = || anyhow!("Invalid size '{s}' (expected bytes or e.g. 512M, 4G, 1T)");
+    let (digits, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let value: u64 = digits.parse().map_err(|_| invalid())?;
+    let shift = match unit {
+        "" => 0,
+        "K" | "KiB" => 10,
+        "M" | "MiB" => 20,
+        "G" | "GiB" => 30,
+        "T" | "TiB" => 40,
+        _ => return Err(invalid()),
+    };
+    value.checked_mul(1 << shift).filter(|&bytes| bytes > 0).ok_or_else(invalid)
+}
+
+/// Format a byte count using binary units (e.g. `8.0 GiB`).
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
     }
+    if unit == 0 { format!("{bytes} B") } else { format!("{value:.1} {}", UNITS[unit]) }
+}
 
+fn is_block_device_entry(disk_sys: &Path) -> bool {
     // /sys/block typically contains whole disks already, but keep the explicit
     // partition guard so odd sysfs layouts do not slip through.
     !disk_sys.join("partition").exists()
@@ -110,6 +457,42 @@ fn is_candidate_block_device(disk_sys: &Path, name: &str) -> bool {
         && disk_sys.join("size").exists()
 }
 
+/// Native NVMe multipath exposes per-controller paths (`nvme0c0n1`) next to the namespace
+/// (`nvme0n1`); both are the same media, so only the namespace is offered.
+fn is_nvme_controller_path(name: &str) -> bool {
+    let Some(rest) = name.strip_prefix("nvme") else {
+        return false;
+    };
+    let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    let Some((subsystem, rest)) = rest.split_once('c') else {
+        return false;
+    };
+    let Some((controller, namespace)) = rest.split_once('n') else {
+        return false;
+    };
+    digits(subsystem) && digits(controller) && digits(namespace)
+}
+
+fn rejected_by_sysfs(disk_sys: &Path, name: &str, allow_removable: bool) -> Option<RejectReason> {
+    if is_excluded_device_class(name) {
+        return Some(RejectReason::VirtualName);
+    }
+
+    // If there is no backing device directory, it is unlikely to be a real disk.
+    if !disk_sys.join("device").exists() {
+        return Some(RejectReason::NoBackingDevice);
+    }
+
+    if !allow_removable && read_u64(disk_sys.join("removable")).unwrap_or(1) != 0 {
+        return Some(RejectReason::Removable);
+    }
+    if read_u64(disk_sys.join("ro")).unwrap_or(1) != 0 {
+        return Some(RejectReason::ReadOnly);
+    }
+
+    None
+}
+
 fn is_excluded_device_class(name: &str) -> bool {
     // Exclude common non-install targets. Everything else is vetted by sysfs
     // properties so broader block-device names such as xvda or mmcblk0 can work.
@@ -125,6 +508,61 @@ fn is_excluded_device_class(name: &str) -> bool {
         || name.starts_with("rbd")
 }
 
+/// sysfs spells `/` in device names as `!`, e.g. `cciss!c0d0` for `/dev/cciss/c0d0`.
+fn dev_path_for(name: &str) -> PathBuf {
+    PathBuf::from("/dev").join(name.replace('!', "/"))
+}
+
+fn transport_of(disk_sys: &Path, name: &str) -> Transport {
+    let device = fs::canonicalize(disk_sys.join("device")).unwrap_or_default();
+    classify_transport(name, &device.to_string_lossy())
+}
+
+/// Classify by the resolved `device` link (the bus path) first, since USB bridges and
+/// virtio-scsi also produce `sdX` names; fall back to the kernel name.
+fn classify_transport(name: &str, device_path: &str) -> Transport {
+    let on_bus = |bus: &str| device_path.split('/').any(|part| part.starts_with(bus));
+    if on_bus("usb") {
+        Transport::Usb
+    } else if on_bus("nvme") || name.starts_with("nvme") {
+        Transport::Nvme
+    } else if on_bus("virtio") || name.starts_with("vd") {
+        Transport::Virtio
+    } else if on_bus("ata") {
+        Transport::Sata
+    } else if on_bus("mmc") || name.starts_with("mmcblk") {
+        Transport::Mmc
+    } else if on_bus("vbd-") || name.starts_with("xvd") {
+        Transport::Xen
+    } else if name.starts_with("loop") {
+        Transport::Loop
+    } else if name.starts_with("sd") || name.starts_with("cciss!") {
+        Transport::Scsi
+    } else {
+        Transport::Unknown
+    }
+}
+
+/// Partition numbers and names under a disk's sysfs directory, in partition order.
+pub fn sysfs_partitions(disk_sys: &Path) -> Vec<(u32, String)> {
+    let Ok(entries) = fs::read_dir(disk_sys) else {
+        return Vec::new();
+    };
+    let mut partitions: Vec<(u32, String)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let number = read_u64(entry.path().join("partition")).ok()?;
+            Some((u32::try_from(number).ok()?, entry.file_name().to_string_lossy().into_owned()))
+        })
+        .collect();
+    partitions.sort();
+    partitions
+}
+
+fn probe_fstype(dev: &Path) -> Option<String> {
+    Probe::of(dev).get("TYPE").map(str::to_string)
+}
+
 fn read_u64(path: impl AsRef<Path>) -> Result<u64> {
     let s = fs::read_to_string(&path)
         .with_context(|| format!("Failed to read {}", path.as_ref().display()))?;
@@ -145,34 +583,73 @@ fn disk_size_bytes(disk_sys: &Path) -> Result<u64> {
     Ok(sectors.saturating_mul(512))
 }
 
-fn is_device_mounted(proc_root: &Path, dev_name: &str) -> Result<bool> {
+/// Source devices of everything mounted, from `/proc/self/mountinfo` or, when a stripped
+/// initramfs lacks it, `/proc/mounts`. With neither readable nothing counts as mounted.
+fn mounted_sources(proc_root: &Path) -> Vec<String> {
     let mountinfo = proc_root.join("self").join("mountinfo");
-    let contents = fs::read_to_string(&mountinfo)
-        .with_context(|| format!("Failed to read {}", mountinfo.display()))?;
-
-    let needle = format!("/dev/{dev_name}");
-
-    for line in contents.lines() {
+    if let Ok(contents) = fs::read_to_string(&mountinfo) {
         // mountinfo format: https://www.kernel.org/doc/Documentation/filesystems/proc.txt
         // ... optional fields ... - fstype source superoptions
-        let Some((_, after)) = line.split_once(" - ") else {
-            continue;
-        };
-        let mut parts = after.split_whitespace();
-        let _fstype = parts.next();
-        let source = parts.next();
-        let Some(source) = source else {
-            continue;
-        };
+        return contents
+            .lines()
+            .filter_map(|line| line.split_once(" - "))
+            .filter_map(|(_, after)| after.split_whitespace().nth(1))
+            .map(str::to_string)
+            .collect();
+    }
 
-        // Treat the whole disk or any of its partitions as "mounted".
-        // Examples: /dev/sda, /dev/sda1, /dev/nvme0n1, /dev/nvme0n1p1
-        if source == needle || source.starts_with(&needle) {
-            return Ok(true);
+    let mounts = proc_root.join("mounts");
+    match fs::read_to_string(&mounts) {
+        // `source mountpoint fstype options dump pass`
+        Ok(contents) => contents
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .map(str::to_string)
+            .collect(),
+        Err(e) => {
+            eprintln!(
+                "WARN: cannot read {} or {} ({e}); assuming no disk is mounted",
+                mountinfo.display(),
+                mounts.display()
+            );
+            Vec::new()
         }
     }
+}
 
-    Ok(false)
+/// The device the running system was booted from, by `/dev` name (e.g. `sdb2`), from the
+/// `root=` kernel argument. A root mounted from a real device is already caught by the mount
+/// check; this covers `/dev/root` and live media that were copied to RAM and unmounted.
+fn boot_device(proc_root: &Path) -> Option<String> {
+    let cmdline = fs::read_to_string(proc_root.join("cmdline")).ok()?;
+    let root = cmdline.split_whitespace().find_map(|arg| arg.strip_prefix("root="))?;
+    resolve_root_arg(root.strip_prefix("live:").unwrap_or(root))
+}
+
+/// Resolve a `root=` value (`/dev/sdb2`, `UUID=...`, `LABEL=...`) to a `/dev` name.
+fn resolve_root_arg(root: &str) -> Option<String> {
+    let link = match root.split_once('=') {
+        Some(("UUID", v)) => PathBuf::from("/dev/disk/by-uuid").join(v),
+        Some(("PARTUUID", v)) => PathBuf::from("/dev/disk/by-partuuid").join(v),
+        Some(("LABEL" | "CDLABEL", v)) => PathBuf::from("/dev/disk/by-label").join(v),
+        Some(("PARTLABEL", v)) => PathBuf::from("/dev/disk/by-partlabel").join(v),
+        Some(_) => return None,
+        None => PathBuf::from(root),
+    };
+    let dev = fs::canonicalize(&link).unwrap_or(link);
+    dev.strip_prefix("/dev").ok()?.to_str().map(str::to_string)
+}
+
+/// Whether `dev_name` is the disk `name` itself or one of its partitions.
+fn holds(disk_sys: &Path, name: &str, dev_name: &str) -> bool {
+    dev_name == name || sysfs_partitions(disk_sys).iter().any(|(_, part)| part == dev_name)
+}
+
+fn is_device_mounted(sources: &[String], dev_name: &str) -> bool {
+    let needle = format!("/dev/{dev_name}");
+    // Treat the whole disk or any of its partitions as "mounted".
+    // Examples: /dev/sda, /dev/sda1, /dev/nvme0n1, /dev/nvme0n1p1
+    sources.iter().any(|source| source.starts_with(&needle))
 }
 
 #[cfg(test)]
@@ -185,8 +662,28 @@ mod tests {
     }
 
     fn make_scanner(sys_root: &Path, proc_root: &Path) -> DiskScanner {
-        // 1 MiB threshold for tests.
-        DiskScanner::new(sys_root, proc_root, 1024 * 1024)
+        // 1 MiB threshold for tests; never run blkid on the host.
+        DiskScanner { fstype_probe: |_| None, ..DiskScanner::new(sys_root, proc_root, 1024 * 1024) }
+    }
+
+    #[test]
+    fn menu_label_shows_size_transport_and_model() {
+        let disk = |name: &str, gib: u64, model: Option<&str>| Disk {
+            name: name.to_string(),
+            dev_path: PathBuf::from(format!("/dev/{name}")),
+            size_bytes: gib << 30,
+            model: model.map(str::to_string),
+            transport: Transport::Sata,
+            rotational: None,
+            partitions: Vec::new(),
+        };
+        assert_eq!(
+            disk("sda", 500, Some("WDC WD5000")).menu_label(),
+            "/dev/sda  500.0 GiB  SATA  WDC WD5000"
+        );
+        let hdd = Disk { rotational: Some(true), ..disk("sda", 500, Some("WDC WD5000")) };
+        assert_eq!(hdd.menu_label(), "/dev/sda  500.0 GiB  SATA HDD  WDC WD5000");
+        assert_eq!(disk("sdb", 64, None).menu_label(), "/dev/sdb  64.0 GiB  SATA  (unknown model)");
     }
 
     #[test]
@@ -203,6 +700,7 @@ mod tests {
         write(&xvda.join("dev"), "202:0\n");
         fs::create_dir_all(xvda.join("device")).unwrap();
         write(&xvda.join("device").join("model"), "Xen Disk\n");
+        write(&xvda.join("queue").join("rotational"), "0\n");
 
         // No mounts
         write(&proc.join("self").join("mountinfo"), "");
@@ -213,6 +711,147 @@ mod tests {
         assert_eq!(disks[0].name, "xvda");
         assert_eq!(disks[0].dev_path, PathBuf::from("/dev/xvda"));
         assert_eq!(disks[0].model.as_deref(), Some("Xen Disk"));
+        assert_eq!(disks[0].transport, Transport::Xen);
+        assert_eq!(disks[0].rotational, Some(false));
+        assert!(disks[0].menu_label().contains("Xen SSD"));
+    }
+
+    #[test]
+    fn sizes_accept_binary_suffixes() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("4K").unwrap(), 4096);
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size("4G").unwrap(), 4 << 30);
+        assert_eq!(parse_size("16GiB").unwrap(), 16 << 30);
+        assert_eq!(parse_size("2T").unwrap(), 2 << 40);
+        for bad in ["", "G", "0", "0G", "4g", "4 G", "4GB", "-1", "1.5G", "99999999999T"] {
+            assert!(parse_size(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn partitions_are_listed_from_sysfs() {
+        let sys = tempfile::tempdir().unwrap();
+        for (entry, number) in [("nvme0n1p3", "3"), ("nvme0n1p1", "1"), ("nvme0n1p2", "2")] {
+            let dir = sys.path().join("nvme0n1").join(entry);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("partition"), format!("{number}\n")).unwrap();
+        }
+        fs::create_dir_all(sys.path().join("nvme0n1/queue")).unwrap();
+
+        let partitions = sysfs_partitions(&sys.path().join("nvme0n1"));
+        assert_eq!(
+            partitions,
+            vec![
+                (1, "nvme0n1p1".to_string()),
+                (2, "nvme0n1p2".to_string()),
+                (3, "nvme0n1p3".to_string()),
+            ]
+        );
+        assert!(sysfs_partitions(&sys.path().join("sda")).is_empty());
+    }
+
+    #[test]
+    fn eligible_disks_list_existing_partitions() {
+        let temp = tempfile::tempdir().unwrap();
+        let sys = temp.path().join("sys");
+        let proc = temp.path().join("proc");
+
+        let vda = sys.join("block").join("vda");
+        write(&vda.join("removable"), "0\n");
+        write(&vda.join("ro"), "0\n");
+        write(&vda.join("size"), "4096\n");
+        write(&vda.join("dev"), "253:0\n");
+        for (part, number, sectors) in [("vda2", "2", "3072"), ("vda1", "1", "1024")] {
+            write(&vda.join(part).join("partition"), &format!("{number}\n"));
+            write(&vda.join(part).join("size"), &format!("{sectors}\n"));
+        }
+        fs::create_dir_all(vda.join("device")).unwrap();
+        write(&proc.join("self").join("mountinfo"), "");
+
+        let scanner = DiskScanner {
+            fstype_probe: |dev| (dev == Path::new("/dev/vda2")).then(|| "ext4".to_string()),
+            ..make_scanner(&sys, &proc)
+        };
+        let disk = scanner.choose_single_target_disk().unwrap();
+        assert_eq!(
+            disk.partitions,
+            vec![
+                Partition { name: "vda1".into(), size_bytes: 512 * 1024, fstype: None },
+                Partition {
+                    name: "vda2".into(),
+                    size_bytes: 3 * 512 * 1024,
+                    fstype: Some("ext4".into())
+                },
+            ]
+        );
+        assert_eq!(
+            disk.existing_data_warning().as_deref(),
+            Some("WARNING: disk contains existing data: ext4 on vda2 (1.5 MiB)")
+        );
+        assert_eq!(Disk { partitions: Vec::new(), ..disk }.existing_data_warning(), None);
+    }
+
+    #[test]
+    fn cciss_volume_maps_to_dev_subdirectory() {
+        let temp = tempfile::tempdir().unwrap();
+        let sys = temp.path().join("sys");
+        let proc = temp.path().join("proc");
+
+        let vol = sys.join("block").join("cciss!c0d0");
+        write(&vol.join("removable"), "0\n");
+        write(&vol.join("ro"), "0\n");
+        write(&vol.join("size"), "4096\n");
+        write(&vol.join("dev"), "104:0\n");
+        fs::create_dir_all(vol.join("device")).unwrap();
+        write(&proc.join("self").join("mountinfo"), "");
+
+        let disk = make_scanner(&sys, &proc).choose_single_target_disk().unwrap();
+        assert_eq!(disk.dev_path, PathBuf::from("/dev/cciss/c0d0"));
+        assert_eq!(disk.transport, Transport::Scsi);
+    }
+
+    #[test]
+    fn transport_follows_bus_path() {
+        let cases = [
+            (
+                "sda",
+                "/sys/devices/pci0000:00/0000:00:1f.2/ata1/host0/target0:0:0/0:0:0:0",
+                Transport::Sata,
+            ),
+            (
+                "sdb",
+                "/sys/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host6/target6:0:0/6:0:0:0",
+                Transport::Usb,
+            ),
+            (
+                "sdc",
+                "/sys/devices/pci0000:00/0000:00:03.0/virtio1/host2/target2:0:0/2:0:0:0",
+                Transport::Virtio,
+            ),
+            (
+                "sdd",
+                "/sys/devices/pci0000:00/0000:00:01.0/0000:01:00.0/host0/target0:2:0/0:2:0:0",
+                Transport::Scsi,
+            ),
+            (
+                "nvme0n1",
+                "/sys/devices/pci0000:00/0000:00:1d.0/0000:3d:00.0/nvme/nvme0",
+                Transport::Nvme,
+            ),
+            ("vda", "/sys/devices/pci0000:00/0000:00:04.0/virtio2", Transport::Virtio),
+            (
+                "mmcblk0",
+                "/sys/devices/platform/soc/fe340000.mmc/mmc_host/mmc0/mmc0:0001",
+                Transport::Mmc,
+            ),
+            ("xvda", "/sys/devices/vbd-51712", Transport::Xen),
+            ("loop0", "", Transport::Loop),
+            ("pmem0", "", Transport::Unknown),
+        ];
+        for (name, path, expected) in cases {
+            assert_eq!(classify_transport(name, path), expected, "{name}");
+        }
     }
 
     #[test]
@@ -239,6 +878,228 @@ mod tests {
         assert!(msg.contains("/dev/vdb"));
     }
 
+    #[test]
+    fn explicit_disk_must_be_eligible() {
+        let temp = tempfile::tempdir().unwrap();
+        let sys = temp.path().join("sys");
+        let proc = temp.path().join("proc");
+
+        for (dev, removable, sectors) in
+            [("vda", "0\n", "4096\n"), ("vdb", "1\n", "4096\n"), ("vdd", "0\n", "1024\n")]
+        {
+            let d = sys.join("block").join(dev);
+            write(&d.join("removable"), removable);
+            write(&d.join("ro"), "0\n");
+            write(&d.join("size"), sectors);
+            write(&d.join("dev"), "252:0\n");
+            fs::create_dir_all(d.join("device")).unwrap();
+        }
+        write(&proc.join("self").join("mountinfo"), "");
+
+        let scanner = make_scanner(&sys, &proc);
+        assert_eq!(scanner.choose_disk(Path::new("/dev/vda"), false).unwrap().name, "vda");
+        let err = scanner.choose_disk(Path::new("/dev/vdb"), false).unwrap_err();
+        assert!(err.to_string().contains("removable"));
+        assert!(scanner.choose_disk(Path::new("/dev/vdc"), false).is_err());
+        assert!(scanner.choose_disk(Path::new("/dev/vdd"), false).is_err());
+    }
+
+    #[test]
+    fn force_waives_only_the_size_floor() {
+        let temp = tempfile::tempdir().unwrap();
+        let sys = temp.path().join("sys");
+        let proc = temp.path().join("proc");
+
+        for (dev, removable) in [("vda", "0\n"), ("vdb", "1\n"), ("vdc", "0\n"), ("vdd", "0\n")] {
+            let d = sys.join("block").join(dev);
+            write(&d.join("removable"), removable);
+            write(&d.join("ro"), "0\n");
+            write(&d.join("size"), "1024\n");
+            write(&d.join("dev"), "252:0\n");
+            fs::create_dir_all(d.join("device")).unwrap();
+            write(&d.join(format!("{dev}2")).join("partition"), "2\n");
+        }
+        // vdc is mounted and the installer was booted from vdd; both are also too small.
+        write(
+            &proc.join("self").join("mountinfo"),
+            "36 25 252:34 / /data rw - ext4 /dev/vdc2 rw\n",
+        );
+        write(&proc.join("cmdline"), "root=/dev/vdd2 ro\n");
+
+        let scanner = make_scanner(&sys, &proc);
+        assert_eq!(scanner.choose_disk(Path::new("/dev/vda"), true).unwrap().name, "vda");
+        assert!(scanner.choose_disk(Path::new("/dev/vdb"), true).is_err());
+        let err = scanner.choose_disk(Path::new("/dev/vdc"), true).unwrap_err();
+        assert!(err.to_string().contains("mounted"));
+        let err = scanner.choose_disk(Path::new("/dev/vdd"), true).unwrap_err();
+        assert!(err.to_string().contains("boot medium"));
+    }
+
+    #[test]
+    fn image_loop_device_bypasses_virtual_exclusion() {
+        let temp = tempfile::tempdir().unwrap();
+        let sys = temp.path().join("sys");
+        let proc = temp.path().join("proc");
+
+        for (dev, ro) in [("loop0", "0\n"), ("loop1", "1\n")] {
+            let d = sys.join("block").join(dev);
+            write(&d.join("removable"), "0\n");
+            write(&d.join("ro"), ro);
+            write(&d.join("size"), "1024\n");
+            write(&d.join("dev"), "7:0\n");
+        }
+        write(&proc.join("self").join("mountinfo"), "");
+
+        let scanner = make_scanner(&sys, &proc);
+        assert!(scanner.eligible_disks().unwrap().is_empty());
+        let disk = scanner.image_disk(Path::new("/dev/loop0")).unwrap();
+        assert_eq!(disk.transport, Transport::Loop);
+        assert!(scanner.image_disk(Path::new("/dev/loop1")).is_err());
+        assert!(scanner.image_disk(Path::new("/dev/loop2")).is_err());
+    }
+
+    #[test]
+    fn nvme_controller_paths_are_skipped() {
+        let temp = tempfile::tempdir().unwrap();
+        let sys = temp.path().join("sys");
+        let proc = temp.path().join("proc");
+
+        for dev in ["nvme0n1", "nvme0c0n1", "nvme0c1n1"] {
+            let d = sys.join("block").join(dev);
+            write(&d.join("removable"), "0\n");
+            write(&d.join("ro"), "0\n");
+            write(&d.join("size"), "4096\n");
+            write(&d.join("dev"), "259:0\n");
+            fs::create_dir_all(d.join("device")).unwrap();
+        }
+        write(&proc.join("self").join("mountinfo"), "");
+
+        let scanner = make_scanner(&sys, &proc);
+        let names: Vec<_> = scanner.scan_all().unwrap().into_iter().map(|(d, _)| d.name).collect();
+        assert_eq!(names, vec!["nvme0n1"]);
+        assert_eq!(scanner.choose_single_target_disk().unwrap().name, "nvme0n1");
+
+        assert!(is_nvme_controller_path("nvme12c3n45"));
+        assert!(!is_nvme_controller_path("nvme0n1"));
+        assert!(!is_nvme_controller_path("nvme0cxn1"));
+    }
+
+    #[test]
+    fn cached_scans_are_reused_until_invalidated() {
+        let temp = tempfile::tempdir().unwrap();
+        let sys = temp.path().join("sys");
+        let proc = temp.path().join("proc");
+        let add_disk = |dev: &str| {
+            let d = sys.join("block").join(dev);
+            write(&d.join("removable"), "0\n");
+            write(&d.join("ro"), "0\n");
+            write(&d.join("size"), "4096\n");
+            write(&d.join("dev"), "252:0\n");
+            fs::create_dir_all(d.join("device")).unwrap();
+        };
+        add_disk("vda");
+        write(&proc.join("self").join("mountinfo"), "");
+
+        let scanner = make_scanner(&sys, &proc).with_cache_ttl(Duration::from_secs(3600));
+        assert_eq!(scanner.eligible_disks().unwrap().len(), 1);
+        add_disk("vdb");
+        assert_eq!(scanner.eligible_disks().unwrap().len(), 1);
+
+        scanner.invalidate();
+        assert_eq!(scanner.eligible_disks().unwrap().len(), 2);
+
+        // The uncached path always sees current state.
+        add_disk("vdc");
+        assert_eq!(scanner.scan_all().unwrap().len(), 3);
+        assert_eq!(make_scanner(&sys, &proc).scan_cached().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn largest_disk_is_chosen_with_ties_by_name() {
+        let temp = tempfile::tempdir().unwrap();
+        let sys = temp.path().join("sys");
+        let proc = temp.path().join("proc");
+        let add_disk = |dev: &str, sectors: &str| {
+            let d = sys.join("block").join(dev);
+            write(&d.join("removable"), "0\n");
+            write(&d.join("ro"), "0\n");
+            write(&d.join("size"), sectors);
+            write(&d.join("dev"), "8:0\n");
+            fs::create_dir_all(d.join("device")).unwrap();
+        };
+        write(&proc.join("self").join("mountinfo"), "");
+
+        let scanner = make_scanner(&sys, &proc);
+        assert!(scanner.choose_largest_disk().is_err());
+
+        add_disk("sda", "2048\n");
+        add_disk("sdb", "8192\n");
+        add_disk("sdc", "4096\n");
+        assert!(scanner.choose_single_target_disk().is_err());
+        assert_eq!(scanner.choose_largest_disk().unwrap().name, "sdb");
+
+        add_disk("nvme0n1", "8192\n");
+        assert_eq!(scanner.choose_largest_disk().unwrap().name, "nvme0n1");
+    }
+
+    #[test]
+    fn malformed_size_rejects_only_that_disk() {
+        let temp = tempfile::tempdir().unwrap();
+        let sys = temp.path().join("sys");
+        let proc = temp.path().join("proc");
+
+        for (dev, size) in [("vda", "4096\n"), ("vdb", "\n"), ("vdc", "12x4\n")] {
+            let d = sys.join("block").join(dev);
+            write(&d.join("removable"), "0\n");
+            write(&d.join("ro"), "0\n");
+            write(&d.join("size"), size);
+            write(&d.join("dev"), "252:0\n");
+            fs::create_dir_all(d.join("device")).unwrap();
+        }
+        write(&proc.join("self").join("mountinfo"), "");
+
+        let scanner = make_scanner(&sys, &proc);
+        let reasons: Vec<_> =
+            scanner.scan_all().unwrap().into_iter().map(|(d, reason)| (d.name, reason)).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                ("vda".to_string(), None),
+                ("vdb".to_string(), Some(RejectReason::Unreadable)),
+                ("vdc".to_string(), Some(RejectReason::Unreadable)),
+            ]
+        );
+        assert_eq!(scanner.choose_single_target_disk().unwrap().name, "vda");
+    }
+
+    #[test]
+    fn proc_mounts_is_used_without_mountinfo() {
+        let temp = tempfile::tempdir().unwrap();
+        let sys = temp.path().join("sys");
+        let proc = temp.path().join("proc");
+
+        for dev in ["sda", "sdb"] {
+            let d = sys.join("block").join(dev);
+            write(&d.join("removable"), "0\n");
+            write(&d.join("ro"), "0\n");
+            write(&d.join("size"), "4096\n");
+            write(&d.join("dev"), "8:0\n");
+            fs::create_dir_all(d.join("device")).unwrap();
+        }
+
+        // Neither source: the scan still works and nothing counts as mounted.
+        let scanner = make_scanner(&sys, &proc);
+        assert_eq!(scanner.eligible_disks().unwrap().len(), 2);
+
+        write(&proc.join("mounts"), "rootfs / rootfs rw 0 0\n/dev/sdb1 /data ext4 rw 0 0\n");
+        let reasons: Vec<_> =
+            scanner.scan_all().unwrap().into_iter().map(|(d, reason)| (d.name, reason)).collect();
+        assert_eq!(
+            reasons,
+            vec![("sda".to_string(), None), ("sdb".to_string(), Some(RejectReason::Mounted))]
+        );
+    }
+
     #[test]
     fn removable_disk_is_excluded() {
         let temp = tempfile::tempdir().unwrap();
@@ -257,6 +1118,59 @@ mod tests {
         let scanner = make_scanner(&sys, &proc);
         let disks = scanner.eligible_disks().unwrap();
         assert_eq!(disks.len(), 0);
+
+        let disks = make_scanner(&sys, &proc).with_allow_removable(true).eligible_disks().unwrap();
+        assert_eq!(disks.len(), 1);
+        assert_eq!(disks[0].name, "vda");
+
+        // Allowing removable media never lets a mounted one through.
+        write(
+            &proc.join("self").join("mountinfo"),
+            "36 25 202:1 / /media rw - ext4 /dev/vda1 rw\n",
+        );
+        let disks = make_scanner(&sys, &proc).with_allow_removable(true).eligible_disks().unwrap();
+        assert_eq!(disks.len(), 0);
+    }
+
+    #[test]
+    fn boot_medium_is_excluded() {
+        let temp = tempfile::tempdir().unwrap();
+        let sys = temp.path().join("sys");
+        let proc = temp.path().join("proc");
+
+        for dev in ["sda", "sdb"] {
+            let d = sys.join("block").join(dev);
+            write(&d.join("removable"), "0\n");
+            write(&d.join("ro"), "0\n");
+            write(&d.join("size"), "4096\n");
+            write(&d.join("dev"), "8:0\n");
+            fs::create_dir_all(d.join("device")).unwrap();
+            write(&d.join(format!("{dev}2")).join("partition"), "2\n");
+        }
+        let eligible = |scanner: &DiskScanner| -> Vec<String> {
+            scanner.eligible_disks().unwrap().into_iter().map(|d| d.name).collect()
+        };
+
+        write(
+            &proc.join("self").join("mountinfo"),
+            "22 1 8:18 / / rw,relatime - ext4 /dev/sdb2 rw\n",
+        );
+        assert_eq!(eligible(&make_scanner(&sys, &proc)), vec!["sda"]);
+
+        // Mounted by the kernel without an initramfs: only root= names the device.
+        write(
+            &proc.join("self").join("mountinfo"),
+            "22 1 8:18 / / rw,relatime - ext4 /dev/root rw\n",
+        );
+        write(&proc.join("cmdline"), "BOOT_IMAGE=/vmlinuz root=/dev/sdb2 ro quiet\n");
+        let scanner = make_scanner(&sys, &proc);
+        assert!(!scanner.boot_medium_logged.get());
+        assert_eq!(eligible(&scanner), vec!["sda"]);
+        assert!(scanner.boot_medium_logged.get());
+        assert!(scanner.choose_disk(Path::new("/dev/sdb"), true).is_err());
+        let reasons: Vec<_> =
+            scanner.scan_all().unwrap().into_iter().map(|(d, reason)| (d.name, reason)).collect();
+        assert_eq!(reasons[1], ("sdb".to_string(), Some(RejectReason::BootMedium)));
     }
 
     #[test]
@@ -279,4 +1193,97 @@ mod tests {
         let disks = scanner.eligible_disks().unwrap();
         assert_eq!(disks.len(), 0);
     }
+
+    #[test]
+    fn scan_all_reports_reject_reasons() {
+        let temp = tempfile::tempdir().unwrap();
+        let sys = temp.path().join("sys");
+        let proc = temp.path().join("proc");
+
+        let disks = [
+            ("loop0", "0", "0", "4096"),
+            ("sda", "1", "0", "4096"),
+            ("sdb", "0", "1", "4096"),
+            ("sdc", "0", "0", "8"),
+            ("sdd", "0", "0", "4096"),
+            ("sde", "0", "0", "4096"),
+        ];
+        for (dev, removable, ro, size) in disks {
+            let d = sys.join("block").join(dev);
+            write(&d.join("removable"), removable);
+            write(&d.join("ro"), ro);
+            write(&d.join("size"), size);
+            write(&d.join("dev"), "8:0\n");
+            fs::create_dir_all(d.join("device")).unwrap();
+        }
+        let nodev = sys.join("block").join("sdf");
+        write(&nodev.join("size"), "4096\n");
+        write(&nodev.join("dev"), "8:80\n");
+
+        write(
+            &proc.join("self").join("mountinfo"),
+            "36 25 8:65 / /data rw,relatime - ext4 /dev/sde1 rw\n",
+        );
+
+        let scanner = make_scanner(&sys, &proc);
+        let reasons: Vec<_> = scanner
+            .scan_all()
+            .unwrap()
+            .into_iter()
+            .map(|(disk, reason)| (disk.name, reason))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                ("loop0".to_string(), Some(RejectReason::VirtualName)),
+                ("sda".to_string(), Some(RejectReason::Removable)),
+                ("sdb".to_string(), Some(RejectReason::ReadOnly)),
+                ("sdc".to_string(), Some(RejectReason::TooSmall)),
+                ("sdd".to_string(), None),
+                ("sde".to_string(), Some(RejectReason::Mounted)),
+                ("sdf".to_string(), Some(RejectReason::NoBackingDevice)),
+            ]
+        );
+
+        let eligible = scanner.eligible_disks().unwrap();
+        assert_eq!(eligible.len(), 1);
+        assert_eq!(eligible[0].name, "sdd");
+    }
+
+    #[test]
+    fn explain_rejections_describes_each_disk() {
+        let disk = |name: &str, size_bytes| Disk {
+            name: name.to_string(),
+            dev_path: PathBuf::from("/dev").join(name),
+            size_bytes,
+            model: None,
+            transport: Transport::Unknown,
+            rotational: None,
+            partitions: Vec::new(),
+        };
+        const GIB: u64 = 1024 * 1024 * 1024;
+
+        let scanned = vec![
+            (disk("loop0", 0), Some(RejectReason::VirtualName)),
+            (disk("sda", 4 * GIB), Some(RejectReason::TooSmall)),
+            (disk("sdb", 32 * GIB), Some(RejectReason::Removable)),
+        ];
+        let lines = explain_rejections(&scanned, 8 * GIB);
+        assert_eq!(
+            lines,
+            vec![
+                "Found 2 disks but none can be used:",
+                "  - /dev/sda is too small (4.0 GiB, need >= 8.0 GiB)",
+                "  - /dev/sdb is removable",
+            ]
+        );
+
+        let mounted = vec![(disk("sda", 32 * GIB), Some(RejectReason::Mounted))];
+        let lines = explain_rejections(&mounted, 8 * GIB);
+        assert!(lines.last().unwrap().starts_with("All disks are mounted"));
+
+        let only_virtual = vec![(disk("loop0", 0), Some(RejectReason::VirtualName))];
+        let lines = explain_rejections(&only_virtual, 8 * GIB);
+        assert!(lines[0].starts_with("No disks detected"));
+    }
 }
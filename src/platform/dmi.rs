@@ -0,0 +1,85 @@
+//! Firmware and machine identification from DMI
+//!
+//! Read from `/sys/class/dmi/id`; some boot quirks are vendor-specific, so this is reported up
+//! front alongside the firmware mode.
+
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FirmwareInfo {
+    pub bios_vendor: Option<String>,
+    pub bios_version: Option<String>,
+    pub sys_vendor: Option<String>,
+    pub product_name: Option<String>,
+}
+
+impl FirmwareInfo {
+    /// e.g. `QEMU Standard PC (Q35 + ICH9, 2009), firmware EDK II 0.0.0`.
+    pub fn summary(&self) -> String {
+        let machine = join_present(&[&self.sys_vendor, &self.product_name]);
+        let firmware = join_present(&[&self.bios_vendor, &self.bios_version]);
+        match (machine.is_empty(), firmware.is_empty()) {
+            (false, false) => format!("{machine}, firmware {firmware}"),
+            (false, true) => machine,
+            (true, false) => format!("firmware {firmware}"),
+            (true, true) => "unknown".to_string(),
+        }
+    }
+}
+
+pub fn firmware_info() -> Option<FirmwareInfo> {
+    firmware_info_in(Path::new("/sys/class/dmi/id"))
+}
+
+/// `None` when the DMI directory is absent or has none of the fields (e.g. some VMs/ARM).
+fn firmware_info_in(dmi_dir: &Path) -> Option<FirmwareInfo> {
+    let read = |name: &str| {
+        std::fs::read_to_string(dmi_dir.join(name))
+            .ok()
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let info = FirmwareInfo {
+        bios_vendor: read("bios_vendor"),
+        bios_version: read("bios_version"),
+        sys_vendor: read("sys_vendor"),
+        product_name: read("product_name"),
+    };
+    (info != FirmwareInfo::default()).then_some(info)
+}
+
+fn join_present(parts: &[&Option<String>]) -> String {
+    parts.iter().filter_map(|part| part.as_deref()).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn reads_fake_dmi_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(firmware_info_in(dir.path()), None);
+
+        fs::write(dir.path().join("bios_vendor"), "EFI Development Kit II / OVMF\n").unwrap();
+        fs::write(dir.path().join("bios_version"), "0.0.0\n").unwrap();
+        fs::write(dir.path().join("sys_vendor"), "QEMU\n").unwrap();
+        fs::write(dir.path().join("product_name"), "\n").unwrap();
+
+        let info = firmware_info_in(dir.path()).unwrap();
+        assert_eq!(info.bios_vendor.as_deref(), Some("EFI Development Kit II / OVMF"));
+        assert_eq!(info.product_name, None);
+        assert_eq!(info.summary(), "QEMU, firmware EFI Development Kit II / OVMF 0.0.0");
+    }
+
+    #[test]
+    fn summary_without_firmware_fields() {
+        let info = FirmwareInfo {
+            sys_vendor: Some("Dell Inc.".into()),
+            product_name: Some("PowerEdge R650".into()),
+            ..FirmwareInfo::default()
+        };
+        assert_eq!(info.summary(), "Dell Inc. PowerEdge R650");
+    }
+}
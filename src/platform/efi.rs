@@ -0,0 +1,220 @@
+//! UEFI firmware state
+//!
+//! Reads EFI variables through efivarfs. Everything here is best-effort: on BIOS boots (or
+//! when efivarfs can't be mounted) callers get `None` and fall back to the plain layout.
+
+use super::command::run;
+use std::path::Path;
+
+const EFI_DIR: &str = "/sys/firmware/efi";
+
+/// `SecureBoot` lives under the EFI global variable GUID.
+const SECURE_BOOT_VAR: &str = "efivars/SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+/// Whether the machine was booted in UEFI mode.
+pub fn booted_uefi() -> bool {
+    Path::new(EFI_DIR).exists()
+}
+
+/// Mount efivarfs if it isn't already; errors are ignored (efibootmgr and variable reads
+/// surface the real problem).
+pub fn mount_efivarfs() {
+    let efivars = Path::new(EFI_DIR).join("efivars");
+    let _ = std::fs::create_dir_all(&efivars);
+    let _ = run("mount", &["-t", "efivarfs", "efivarfs", &efivars.display().to_string()]);
+}
+
+/// Secure Boot state from the firmware; `None` when not booted via UEFI or the variable is
+/// unreadable.
+pub fn secure_boot_enabled() -> Option<bool> {
+    if !booted_uefi() {
+        return None;
+    }
+    mount_efivarfs();
+    secure_boot_enabled_in(Path::new(EFI_DIR))
+}
+
+/// Whether efivarfs is mounted read-write, i.e. boot entries can be written to NVRAM.
+///
+/// When it isn't, the firmware may not boot the install automatically and we rely on the
+/// removable-media fallback loader (`EFI/BOOT/BOOTX64.EFI`). Both bootloader backends install
+/// it and fail verification without it (systemd-boot copies it, GRUB uses
+/// `--force-extra-removable`).
+pub fn efivars_writable() -> bool {
+    if !booted_uefi() {
+        return false;
+    }
+    mount_efivarfs();
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+    efivarfs_writable_in(&mountinfo) == Some(true)
+}
+
+/// Look up the efivarfs mount in `/proc/self/mountinfo` contents; `None` when not mounted.
+fn efivarfs_writable_in(mountinfo: &str) -> Option<bool> {
+    mountinfo.lines().find_map(|line| {
+        // "<id> <parent> <dev> <root> <mountpoint> <options> [optional...] - <fstype> ..."
+        let (mount, fs) = line.split_once(" - ")?;
+        if fs.split(' ').next()? != "efivarfs" {
+            return None;
+        }
+        let options = mount.split(' ').nth(5)?;
+        Some(!options.split(',').any(|opt| opt == "ro"))
+    })
+}
+
+/// One-line firmware description for the startup banner, so operators know up front whether
+/// the install will boot (e.g. Secure Boot on without a shim).
+pub fn firmware_summary(booted_uefi: bool, secure_boot: Option<bool>) -> &'static str {
+    match (booted_uefi, secure_boot) {
+        (false, _) => "legacy BIOS (no UEFI)",
+        (true, Some(true)) => "UEFI, Secure Boot enabled",
+        (true, Some(false)) => "UEFI, Secure Boot disabled",
+        (true, None) => "UEFI, Secure Boot state unknown",
+    }
+}
+
+/// One `BootXXXX` line from `efibootmgr`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootEntry {
+    /// Four hex digits, as passed to `efibootmgr -b`.
+    pub num: String,
+    pub label: String,
+    pub active: bool,
+}
+
+/// Parsed `efibootmgr` listing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootEntries {
+    pub order: Vec<String>,
+    pub entries: Vec<BootEntry>,
+}
+
+impl BootEntries {
+    pub fn with_label<'a>(&'a self, label: &'a str) -> impl Iterator<Item = &'a BootEntry> {
+        self.entries.iter().filter(move |entry| entry.label == label)
+    }
+}
+
+/// Parse `efibootmgr` text output. Newer versions append a tab and the device path to each
+/// entry; older ones print the label alone.
+pub fn parse_efibootmgr(output: &str) -> BootEntries {
+    let mut parsed = BootEntries::default();
+    for line in output.lines() {
+        if let Some(order) = line.strip_prefix("BootOrder:") {
+            parsed.order = order.trim().split(',').map(str::to_string).collect();
+            continue;
+        }
+        let Some(rest) = line.strip_prefix("Boot") else {
+            continue;
+        };
+        let Some(num) = rest.get(..4).filter(|n| n.chars().all(|c| c.is_ascii_hexdigit())) else {
+            continue;
+        };
+        let rest = &rest[4..];
+        let (active, rest) = match rest.strip_prefix('*') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let label = rest.split('\t').next().unwrap_or_default().trim();
+        parsed.entries.push(BootEntry { num: num.to_string(), label: label.to_string(), active });
+    }
+    parsed
+}
+
+/// `order` with `first` moved to the front (added if missing).
+pub fn boot_order_with_first(order: &[String], first: &str) -> Vec<String> {
+    std::iter::once(first.to_string())
+        .chain(order.iter().filter(|num| num.as_str() != first).cloned())
+        .collect()
+}
+
+fn secure_boot_enabled_in(efi_dir: &Path) -> Option<bool> {
+    let bytes = std::fs::read(efi_dir.join(SECURE_BOOT_VAR)).ok()?;
+    parse_efivar_bool(&bytes)
+}
+
+/// efivarfs files hold a 4-byte attribute mask followed by the variable's data; `SecureBoot`
+/// is a single byte.
+fn parse_efivar_bool(bytes: &[u8]) -> Option<bool> {
+    match bytes {
+        [_, _, _, _, value] => Some(*value != 0),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn efivar_bool_skips_attribute_prefix() {
+        assert_eq!(parse_efivar_bool(&[0x06, 0, 0, 0, 1]), Some(true));
+        assert_eq!(parse_efivar_bool(&[0x06, 0, 0, 0, 0]), Some(false));
+        assert_eq!(parse_efivar_bool(&[1]), None);
+        assert_eq!(parse_efivar_bool(&[0x06, 0, 0, 0, 1, 0]), None);
+    }
+
+    #[test]
+    fn efivarfs_mount_options() {
+        let rw = "\
+25 22 0:22 / /sys rw,nosuid shared:7 - sysfs sysfs rw
+40 25 0:35 / /sys/firmware/efi/efivars rw,nosuid,nodev,noexec shared:9 - efivarfs efivarfs rw
+";
+        assert_eq!(efivarfs_writable_in(rw), Some(true));
+        let ro = "40 25 0:35 / /sys/firmware/efi/efivars ro,nosuid - efivarfs efivarfs ro\n";
+        assert_eq!(efivarfs_writable_in(ro), Some(false));
+        assert_eq!(efivarfs_writable_in("25 22 0:22 / /sys rw - sysfs sysfs rw\n"), None);
+    }
+
+    #[test]
+    fn firmware_summary_covers_each_state() {
+        assert_eq!(firmware_summary(false, None), "legacy BIOS (no UEFI)");
+        assert_eq!(firmware_summary(true, Some(true)), "UEFI, Secure Boot enabled");
+        assert_eq!(firmware_summary(true, None), "UEFI, Secure Boot state unknown");
+    }
+
+    const SAMPLE: &str = "\
+BootCurrent: 0001
+Timeout: 1 seconds
+BootOrder: 0004,0001,0003,0000
+Boot0000* UiApp\tFvVol(7cb8bdc9-f8eb-4f34-aaea-3ee4af6516a1)/FvFile(462caa21-7614-4503-836e-8ab6f4662331)
+Boot0001* UEFI QEMU DVD-ROM QM00003 \tPciRoot(0x0)/Pci(0x1,0x1)/Ata(0,0,0)N.....YM....R,Y.
+Boot0003* Debian (TruthDB)\tHD(1,GPT,0a1b,0x800,0x100000)/File(\\EFI\\systemd\\systemd-bootx64.efi)
+Boot0004* Debian (TruthDB)\tHD(1,GPT,9f2c,0x800,0x100000)/File(\\EFI\\systemd\\systemd-bootx64.efi)
+Boot0005  EFI Internal Shell
+";
+
+    #[test]
+    fn parses_efibootmgr_listing() {
+        let parsed = parse_efibootmgr(SAMPLE);
+        assert_eq!(parsed.order, vec!["0004", "0001", "0003", "0000"]);
+        assert_eq!(parsed.entries.len(), 5);
+        assert_eq!(parsed.entries[1].label, "UEFI QEMU DVD-ROM QM00003");
+        assert_eq!(
+            parsed.entries[4],
+            BootEntry { num: "0005".into(), label: "EFI Internal Shell".into(), active: false }
+        );
+
+        let ours: Vec<&str> =
+            parsed.with_label("Debian (TruthDB)").map(|entry| entry.num.as_str()).collect();
+        assert_eq!(ours, vec!["0003", "0004"]);
+    }
+
+    #[test]
+    fn boot_order_puts_entry_first() {
+        let order: Vec<String> = ["0001", "0007", "0000"].map(String::from).to_vec();
+        assert_eq!(boot_order_with_first(&order, "0007"), vec!["0007", "0001", "0000"]);
+        assert_eq!(boot_order_with_first(&order, "0009"), vec!["0009", "0001", "0007", "0000"]);
+    }
+
+    #[test]
+    fn secure_boot_read_from_efivars() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(secure_boot_enabled_in(dir.path()), None);
+
+        let var = dir.path().join(SECURE_BOOT_VAR);
+        std::fs::create_dir_all(var.parent().unwrap()).unwrap();
+        std::fs::write(&var, [0x16, 0, 0, 0, 1]).unwrap();
+        assert_eq!(secure_boot_enabled_in(dir.path()), Some(true));
+    }
+}
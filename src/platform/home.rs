@@ -0,0 +1,235 @@
+//! Reinstalling while keeping `/home`
+//!
+//! `--preserve-home` reinstalls over an existing ESP+root layout without touching the partition
+//! table: only the ESP and root are reformatted. A separate `/home` partition further along the
+//! same disk is never wiped or formatted; it is mounted into the new system and given an fstab
+//! entry.
+
+use super::command::{CommandRunner, RetryPolicy, SystemRunner};
+use super::disks::sysfs_partitions;
+use super::install::{MountGuard, MountPlan};
+use super::partition::{EFI_SYSTEM_PARTITION_GUID, PartitionPlan};
+use super::resume::Probe;
+use anyhow::{Context, Result, anyhow};
+use std::path::{Path, PathBuf};
+
+/// Discoverable Partitions Specification: `/home` partition.
+const DPS_HOME_GUID: &str = "933AC7E1-2EB4-4F13-B844-0E14E2AEF915";
+const HOME_LABEL: &str = "home";
+/// Partitions 1 and 2 are the ESP and root; `/home` can only come after them.
+const FIRST_HOME_CANDIDATE: u32 = 3;
+
+/// The existing partition that is kept and mounted at `/home`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HomePartition {
+    pub dev: PathBuf,
+    pub uuid: String,
+    pub fstype: String,
+    pub label: Option<String>,
+}
+
+impl HomePartition {
+    /// One-line description for the install plan.
+    pub fn describe(&self) -> String {
+        let label = self.label.as_deref().map(|l| format!(", label {l}")).unwrap_or_default();
+        format!("{} ({}{label}, UUID {})", self.dev.display(), self.fstype, self.uuid)
+    }
+}
+
+/// Find the `/home` partition on `disk`, if there is one.
+pub fn find_home(disk: &Path) -> Result<Option<HomePartition>> {
+    let name = disk
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid disk path: {}", disk.display()))?
+        .to_string_lossy()
+        .into_owned();
+    let candidates: Vec<(u32, PathBuf, Probe)> =
+        sysfs_partitions(&Path::new("/sys/block").join(name))
+            .into_iter()
+            .filter(|&(number, _)| number >= FIRST_HOME_CANDIDATE)
+            .map(|(number, part)| {
+                let dev = Path::new("/dev").join(part);
+                let probe = Probe::of(&dev);
+                (number, dev, probe)
+            })
+            .collect();
+    Ok(pick_home(&candidates))
+}
+
+/// A partition typed or labelled as `/home` wins; otherwise the one right after root, as long
+/// as it holds a filesystem.
+fn pick_home(candidates: &[(u32, PathBuf, Probe)]) -> Option<HomePartition> {
+    let with_filesystem = |probe: &Probe| probe.get("UUID").is_some() && is_mountable(probe);
+    let marked = candidates.iter().find(|(_, _, probe)| {
+        with_filesystem(probe)
+            && (probe.has_part_type(DPS_HOME_GUID)
+                || probe.get("LABEL").is_some_and(|l| l.eq_ignore_ascii_case(HOME_LABEL)))
+    });
+    let positional = || {
+        candidates
+            .iter()
+            .find(|(number, _, probe)| *number == FIRST_HOME_CANDIDATE && with_filesystem(probe))
+    };
+    let (_, dev, probe) = marked.or_else(positional)?;
+    Some(HomePartition {
+        dev: dev.clone(),
+        uuid: probe.get("UUID")?.to_string(),
+        fstype: probe.get("TYPE")?.to_string(),
+        label: probe.get("LABEL").map(str::to_string),
+    })
+}
+
+/// Swap, PVs, LUKS containers and the like have a UUID too but can't be mounted at `/home`.
+fn is_mountable(probe: &Probe) -> bool {
+    probe.get("USAGE").is_none_or(|usage| usage == "filesystem")
+        && probe.get("TYPE").is_some_and(|fstype| fstype != "swap" && fstype != "vfat")
+}
+
+/// Check the disk already has the ESP and root partitions the reinstall formats in place.
+pub fn check_reusable(plan: &PartitionPlan, esp: &Probe, root: &Probe) -> Result<()> {
+    if !esp.has_part_type(EFI_SYSTEM_PARTITION_GUID) {
+        return Err(anyhow!("Partition 1 is not an EFI System Partition"));
+    }
+    if !root.has_part_type(plan.root_type_guid()) {
+        return Err(anyhow!(
+            "Partition 2 does not have the root partition type {}",
+            plan.root_type_guid()
+        ));
+    }
+    Ok(())
+}
+
+/// Mount the kept partition at `/home` in the target.
+pub fn mount_home(guard: &mut MountGuard, home: &HomePartition, plan: &MountPlan) -> Result<()> {
+    guard.push(mount_home_with(&SystemRunner, home, plan)?);
+    Ok(())
+}
+
+fn mount_home_with(
+    runner: &dyn CommandRunner,
+    home: &HomePartition,
+    plan: &MountPlan,
+) -> Result<PathBuf> {
+    let target = plan.target_root.join("home");
+    std::fs::create_dir_all(&target)
+        .with_context(|| format!("Failed to create {}", target.display()))?;
+    let target_arg = target.display().to_string();
+    runner
+        .run_with_policy(
+            "mount",
+            &["-t", &home.fstype, &home.dev.display().to_string(), &target_arg],
+            RetryPolicy::racy(),
+        )
+        .with_context(|| format!("Failed to mount {} at {target_arg}", home.dev.display()))?;
+    Ok(target)
+}
+
+/// Add the `/home` entry to the target's fstab (written by the bootloader step).
+pub fn add_fstab_entry(plan: &MountPlan, home: &HomePartition) -> Result<()> {
+    let fstab_path = plan.target_root.join("etc/fstab");
+    let contents = std::fs::read_to_string(&fstab_path)
+        .with_context(|| format!("Failed to read {}", fstab_path.display()))?;
+    std::fs::write(&fstab_path, fstab_with_home(&contents, home))
+        .with_context(|| format!("Failed to write {}", fstab_path.display()))
+}
+
+fn fstab_with_home(contents: &str, home: &HomePartition) -> String {
+    let mut contents: String = contents
+        .lines()
+        .filter(|line| line.split_whitespace().nth(1) != Some("/home"))
+        .flat_map(|line| [line, "\n"])
+        .collect();
+    contents.push_str(&format!("UUID={} /home {} defaults 0 2\n", home.uuid, home.fstype));
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::command::testing::RecordingRunner;
+    use crate::platform::install::{FormatOptions, format_partitions};
+    use crate::platform::partition::expected_esp_and_root_partitions;
+
+    fn probe(export: &str) -> Probe {
+        Probe::parse(export)
+    }
+
+    fn candidate(number: u32, export: &str) -> (u32, PathBuf, Probe) {
+        (number, PathBuf::from(format!("/dev/vda{number}")), probe(export))
+    }
+
+    #[test]
+    fn home_is_found_by_type_label_or_position() {
+        let data = "UUID=1111\nTYPE=ext4\nLABEL=data\nUSAGE=filesystem\n";
+        let labelled = "UUID=2222\nTYPE=xfs\nLABEL=HOME\nUSAGE=filesystem\n";
+        let typed = "UUID=3333\nTYPE=ext4\nUSAGE=filesystem\n\
+                     PART_ENTRY_TYPE=933ac7e1-2eb4-4f13-b844-0e14e2aef915\n";
+
+        let home = pick_home(&[candidate(3, data), candidate(4, labelled)]).unwrap();
+        assert_eq!(home.dev, PathBuf::from("/dev/vda4"));
+        assert_eq!(home.fstype, "xfs");
+        assert_eq!(pick_home(&[candidate(3, data), candidate(5, typed)]).unwrap().uuid, "3333");
+
+        // No marker: the partition right after root.
+        let home = pick_home(&[candidate(3, data), candidate(4, data)]).unwrap();
+        assert_eq!(home.dev, PathBuf::from("/dev/vda3"));
+        assert_eq!(home.describe(), "/dev/vda3 (ext4, label data, UUID 1111)");
+
+        let swap = "UUID=4444\nTYPE=swap\nUSAGE=other\n";
+        assert_eq!(pick_home(&[candidate(3, swap), candidate(4, data)]), None);
+        assert_eq!(pick_home(&[candidate(3, "PART_ENTRY_NUMBER=3\n")]), None);
+        assert_eq!(pick_home(&[]), None);
+    }
+
+    #[test]
+    fn reinstall_never_formats_home() {
+        let (esp, root) = expected_esp_and_root_partitions(Path::new("/dev/vda")).unwrap();
+        let runner = RecordingRunner::default();
+        format_partitions(&runner, &esp, &root, &FormatOptions::default()).unwrap();
+        let commands = runner.commands();
+        assert_eq!(commands.len(), 2);
+        assert!(commands.iter().all(|c| !c.contains("/dev/vda3") && !c.starts_with("wipefs")));
+
+        let dir = tempfile::tempdir().unwrap();
+        let plan = MountPlan { target_root: dir.path().to_path_buf(), ..MountPlan::default() };
+        let home = HomePartition {
+            dev: "/dev/vda3".into(),
+            uuid: "1111".into(),
+            fstype: "ext4".into(),
+            label: None,
+        };
+        let runner = RecordingRunner::default();
+        let target = mount_home_with(&runner, &home, &plan).unwrap();
+        assert_eq!(target, dir.path().join("home"));
+        assert_eq!(
+            runner.commands(),
+            vec![format!("mount -t ext4 /dev/vda3 {}", target.display())]
+        );
+    }
+
+    #[test]
+    fn existing_layout_must_match() {
+        let plan = PartitionPlan::default();
+        let esp = probe("PART_ENTRY_TYPE=c12a7328-f81f-11d2-ba4b-00a0c93ec93b\n");
+        let root = probe("PART_ENTRY_TYPE=0fc63daf-8483-4772-8e79-3d69d8477de4\n");
+        check_reusable(&plan, &esp, &root).unwrap();
+        assert!(check_reusable(&plan, &root, &root).is_err());
+        let dps = PartitionPlan { discoverable_root: true, ..plan };
+        assert!(check_reusable(&dps, &esp, &root).is_err());
+    }
+
+    #[test]
+    fn fstab_gains_a_single_home_entry() {
+        let home = HomePartition {
+            dev: "/dev/vda3".into(),
+            uuid: "1111".into(),
+            fstype: "ext4".into(),
+            label: None,
+        };
+        let fstab = "# /etc/fstab\nUUID=r / ext4 defaults 0 1\nUUID=old /home ext4 defaults 0 2\n";
+        assert_eq!(
+            fstab_with_home(fstab, &home),
+            "# /etc/fstab\nUUID=r / ext4 defaults 0 1\nUUID=1111 /home ext4 defaults 0 2\n"
+        );
+    }
+}
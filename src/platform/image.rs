@@ -0,0 +1,127 @@
+//! Loopback image targets
+//!
+//! `--image` installs into a sparse file attached with `losetup -fP` instead of a real disk, so
+//! the full pipeline can run in CI/VMs. The loop device is detached when the [`LoopImage`] is
+//! dropped (or explicitly via [`LoopImage::detach`]).
+
+use super::command::command;
+use anyhow::{Context, Result, anyhow};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+const MIB: u64 = 1024 * 1024;
+
+/// An image file attached to a loop device (with partition scanning enabled).
+#[derive(Debug)]
+pub struct LoopImage {
+    pub dev_path: PathBuf,
+    attached: bool,
+}
+
+impl LoopImage {
+    /// Detach the loop device, reporting failures (drop only warns).
+    pub fn detach(mut self) -> Result<()> {
+        self.attached = false;
+        losetup(&["-d", &self.dev_path.display().to_string()])
+            .with_context(|| format!("Failed to detach {}", self.dev_path.display()))?;
+        Ok(())
+    }
+}
+
+impl Drop for LoopImage {
+    fn drop(&mut self) {
+        if self.attached
+            && let Err(e) = losetup(&["-d", &self.dev_path.display().to_string()])
+        {
+            eprintln!("WARN: could not detach {}: {e:#}", self.dev_path.display());
+        }
+    }
+}
+
+/// Create (or resize) `path` as a sparse file of `size_mib` when given, then attach it.
+///
+/// Without a size the file must already exist.
+pub fn attach_image(path: &Path, size_mib: Option<u64>) -> Result<LoopImage> {
+    match size_mib {
+        Some(size_mib) => create_sparse_file(path, size_mib)?,
+        None if !path.is_file() => {
+            return Err(anyhow!(
+                "Image {} does not exist (pass --image-size to create it)",
+                path.display()
+            ));
+        }
+        None => {}
+    }
+
+    let stdout = losetup(&["-f", "-P", "--show", &path.display().to_string()])
+        .with_context(|| format!("Failed to attach {}", path.display()))?;
+    let dev_path = parse_losetup_show(&stdout)?;
+    Ok(LoopImage { dev_path, attached: true })
+}
+
+fn create_sparse_file(path: &Path, size_mib: u64) -> Result<()> {
+    if size_mib == 0 {
+        return Err(anyhow!("Invalid image size 0"));
+    }
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    file.set_len(size_mib.saturating_mul(MIB))
+        .with_context(|| format!("Failed to size {}", path.display()))
+}
+
+/// `losetup --show` prints the device it picked, e.g. `/dev/loop3`.
+fn parse_losetup_show(stdout: &str) -> Result<PathBuf> {
+    let dev = stdout.trim();
+    if !dev.starts_with("/dev/loop") || dev.contains(char::is_whitespace) {
+        return Err(anyhow!("Unexpected losetup output '{dev}'"));
+    }
+    Ok(PathBuf::from(dev))
+}
+
+/// Run `losetup`, returning its stdout.
+fn losetup(args: &[&str]) -> Result<String> {
+    let output = command("losetup")
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .context("Failed to execute losetup")?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if output.status.success() {
+        return Ok(stdout);
+    }
+
+    Err(anyhow!(
+        "losetup failed: stdout='{}' stderr='{}'",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparse_file_is_created_and_resized() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk.img");
+        create_sparse_file(&image, 64).unwrap();
+        assert_eq!(std::fs::metadata(&image).unwrap().len(), 64 * MIB);
+        create_sparse_file(&image, 128).unwrap();
+        assert_eq!(std::fs::metadata(&image).unwrap().len(), 128 * MIB);
+        assert!(create_sparse_file(&image, 0).is_err());
+    }
+
+    #[test]
+    fn losetup_show_output() {
+        assert_eq!(parse_losetup_show("/dev/loop3\n").unwrap(), PathBuf::from("/dev/loop3"));
+        assert!(parse_losetup_show("").is_err());
+        assert!(parse_losetup_show("losetup: cannot find an unused loop device").is_err());
+    }
+}
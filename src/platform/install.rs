@@ -1,88 +1,844 @@
+use super::command::{
+    CommandRunner, RetryPolicy, SystemRunner, command, is_transient_failure, program_on_path, run,
+    run_output,
+};
+use super::crypt;
+use super::efi;
+use super::systemd::{enable_systemd_unit, enable_systemd_unit_optional, has_systemd_unit};
+use crate::progress::ProgressReporter;
 use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[cfg(unix)]
 use std::os::unix::fs as unix_fs;
 
-const DEFAULT_PATH: &str = "/bin:/sbin:/usr/bin:/usr/sbin";
+pub const DEFAULT_USERNAME: &str = "truthdb";
+pub const DEFAULT_PASSWORD: &str = "123456";
+
+/// Payload bytes handed to `tar` per write during extraction.
+const EXTRACT_CHUNK: usize = 1 << 20;
+
+/// Filesystem used for the root partition.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RootFs {
+    #[default]
+    Ext4,
+    Xfs,
+    F2fs,
+}
+
+impl RootFs {
+    /// Filesystem type as understood by `mount -t` and fstab.
+    pub fn fstype(self) -> &'static str {
+        match self {
+            RootFs::Ext4 => "ext4",
+            RootFs::Xfs => "xfs",
+            RootFs::F2fs => "f2fs",
+        }
+    }
+
+    /// The variant for a blkid `TYPE`, if the installer can create it.
+    pub fn from_fstype(fstype: &str) -> Option<RootFs> {
+        [RootFs::Ext4, RootFs::Xfs, RootFs::F2fs].into_iter().find(|fs| fs.fstype() == fstype)
+    }
+
+    fn mkfs_program(self) -> &'static str {
+        match self {
+            RootFs::Ext4 => "mkfs.ext4",
+            RootFs::Xfs => "mkfs.xfs",
+            RootFs::F2fs => "mkfs.f2fs",
+        }
+    }
+
+    /// Options for mounting an existing root without writing to it. Plain `ro` still replays
+    /// the journal (ext4) or log (XFS, f2fs roll-forward), which writes to the disk.
+    fn read_only_mount_opts(self) -> &'static str {
+        match self {
+            RootFs::Ext4 => "ro,noload",
+            RootFs::Xfs | RootFs::F2fs => "ro,norecovery",
+        }
+    }
+
+    /// Package that ships [`RootFs::mkfs_program`], for the error when it is missing.
+    fn mkfs_package(self) -> &'static str {
+        match self {
+            RootFs::Ext4 => "e2fsprogs",
+            RootFs::Xfs => "xfsprogs",
+            RootFs::F2fs => "f2fs-tools",
+        }
+    }
+
+    fn mkfs_args(self, label: &str) -> Vec<String> {
+        let (force, label_flag) = match self {
+            RootFs::Ext4 => ("-F", "-L"),
+            RootFs::Xfs => ("-f", "-L"),
+            RootFs::F2fs => ("-f", "-l"),
+        };
+        vec![force.to_string(), label_flag.to_string(), label.to_string()]
+    }
+
+    /// Longest filesystem label the mkfs accepts, in bytes.
+    fn max_label_len(self) -> usize {
+        match self {
+            RootFs::Ext4 => 16,
+            RootFs::Xfs => 12,
+            RootFs::F2fs => 512,
+        }
+    }
+
+    /// Root label derived from a hostname, cut to what this filesystem can store.
+    pub fn label_for_hostname(self, hostname: &str) -> String {
+        hostname.chars().take(self.max_label_len()).collect()
+    }
+
+    /// Flags from [`RootFs::mkfs_args`] that extra options may not repeat.
+    fn mkfs_reserved(self) -> &'static [&'static str] {
+        match self {
+            RootFs::Ext4 => &["-F", "-L"],
+            RootFs::Xfs => &["-f", "-L"],
+            RootFs::F2fs => &["-f", "-l"],
+        }
+    }
+
+    /// Fail early, before the disk is touched, when the initramfs lacks this filesystem's mkfs.
+    pub fn check_mkfs_available(self) -> Result<()> {
+        if program_on_path(self.mkfs_program()) { Ok(()) } else { Err(self.mkfs_missing()) }
+    }
+
+    fn mkfs_missing(self) -> anyhow::Error {
+        let program = self.mkfs_program();
+        anyhow!(
+            "{program} not available in initramfs (a {} root needs it, from {})",
+            self.fstype(),
+            self.mkfs_package()
+        )
+    }
+}
+
+/// Bootloader installed on the target.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bootloader {
+    /// systemd-boot copied from the initramfs, kernel/initrd on the ESP.
+    #[default]
+    SystemdBoot,
+    /// GRUB from the payload, installed via chroot.
+    Grub,
+}
+
+impl Bootloader {
+    pub fn name(self) -> &'static str {
+        match self {
+            Bootloader::SystemdBoot => "systemd-boot",
+            Bootloader::Grub => "grub",
+        }
+    }
+}
+
+impl FromStr for Bootloader {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "systemd-boot" => Ok(Bootloader::SystemdBoot),
+            "grub" => Ok(Bootloader::Grub),
+            other => Err(anyhow!("Invalid bootloader '{other}' (expected systemd-boot or grub)")),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct MountPlan {
     pub target_root: PathBuf,
     pub target_efi: PathBuf,
+    pub root_fs: RootFs,
 }
 
 impl Default for MountPlan {
     fn default() -> Self {
-        Self { target_root: PathBuf::from("/mnt"), target_efi: PathBuf::from("/mnt/boot/efi") }
+        Self {
+            target_root: PathBuf::from("/mnt"),
+            target_efi: PathBuf::from("/mnt/boot/efi"),
+            root_fs: RootFs::default(),
+        }
     }
 }
 
-pub fn format_partitions(esp: &Path, root: &Path) -> Result<()> {
-    // ESP
-    run("mkfs.vfat", &["-F", "32", "-n", "EFI", &esp.display().to_string()])
-        .with_context(|| format!("mkfs.vfat failed for {}", esp.display()))?;
+/// Filesystem labels set by [`format_partitions`] (both can be overridden, see
+/// [`FormatOptions`]) and [`format_swap`].
+pub const ESP_LABEL: &str = "EFI";
+pub const ROOT_LABEL: &str = "root";
+pub const SWAP_LABEL: &str = "swap";
+
+/// Flags the installer sets on `mkfs.vfat` itself; extra options may not override them.
+const ESP_MKFS_RESERVED: [&str; 3] = ["-F", "-n", "-i"];
+
+/// Characters FAT does not allow in a volume label.
+const FAT_LABEL_FORBIDDEN: &str = "\"*+,./:;<=>?[\\]|";
+
+/// How the ESP and root partitions are formatted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormatOptions {
+    pub root_fs: RootFs,
+    /// Run both mkfs at once (they touch different partitions). Some cheap media handle
+    /// concurrent writes badly, so this is opt-in.
+    pub parallel: bool,
+    /// Extra arguments for `mkfs.vfat`, placed before the device.
+    pub esp_mkfs_opts: Vec<String>,
+    /// Extra arguments for the root mkfs, placed before the device.
+    pub root_mkfs_opts: Vec<String>,
+    /// FAT volume label of the ESP; `None` uses [`ESP_LABEL`].
+    pub esp_label: Option<String>,
+    /// FAT volume ID (serial) of the ESP as 8 hex digits; `None` lets mkfs.vfat pick one.
+    pub esp_volume_id: Option<String>,
+    /// Filesystem label of root; `None` uses [`ROOT_LABEL`]. A fixed label collides when
+    /// several installs share a machine.
+    pub root_label: Option<String>,
+}
+
+impl FormatOptions {
+    /// Reject extra options that name a device or repeat flags the installer sets, and
+    /// labels/IDs FAT cannot store.
+    pub fn validate(&self) -> Result<()> {
+        validate_mkfs_opts("ESP", &self.esp_mkfs_opts, &ESP_MKFS_RESERVED)?;
+        validate_mkfs_opts("root", &self.root_mkfs_opts, self.root_fs.mkfs_reserved())?;
+        if let Some(label) = &self.esp_label {
+            let valid = !label.is_empty()
+                && label.len() <= 11
+                && label.chars().all(|c| c.is_ascii_graphic() || c == ' ')
+                && !label.contains(|c| FAT_LABEL_FORBIDDEN.contains(c));
+            if !valid {
+                return Err(anyhow!(
+                    "Invalid ESP label '{label}' (1-11 ASCII characters, no {FAT_LABEL_FORBIDDEN})"
+                ));
+            }
+        }
+        if let Some(id) = &self.esp_volume_id
+            && (id.len() != 8 || !id.chars().all(|c| c.is_ascii_hexdigit()))
+        {
+            return Err(anyhow!("Invalid ESP volume ID '{id}' (expected 8 hex digits)"));
+        }
+        if let Some(label) = &self.root_label {
+            let max = self.root_fs.max_label_len();
+            if label.is_empty() || label.len() > max || !label.chars().all(|c| c.is_ascii_graphic())
+            {
+                return Err(anyhow!(
+                    "Invalid root label '{label}' (1-{max} ASCII characters without spaces for {})",
+                    self.root_fs.fstype()
+                ));
+            }
+        }
+        Ok(())
+    }
 
-    // Root
-    run("mkfs.ext4", &["-F", "-L", "root", &root.display().to_string()])
-        .with_context(|| format!("mkfs.ext4 failed for {}", root.display()))?;
+    pub fn esp_label(&self) -> &str {
+        self.esp_label.as_deref().unwrap_or(ESP_LABEL)
+    }
+
+    pub fn root_label(&self) -> &str {
+        self.root_label.as_deref().unwrap_or(ROOT_LABEL)
+    }
+}
 
+fn validate_mkfs_opts(what: &str, opts: &[String], reserved: &[&str]) -> Result<()> {
+    for opt in opts {
+        if opt.starts_with('/') {
+            return Err(anyhow!(
+                "Invalid {what} mkfs option '{opt}': the device is set by the installer"
+            ));
+        }
+        if reserved.contains(&opt.as_str()) {
+            return Err(anyhow!(
+                "Invalid {what} mkfs option '{opt}': already set by the installer"
+            ));
+        }
+    }
     Ok(())
 }
 
-pub fn mount_partitions(esp: &Path, root: &Path, plan: &MountPlan) -> Result<()> {
+/// Format the ESP as FAT32 and root as `options.root_fs`. A failure of one mkfs does not
+/// hide the other.
+pub fn format_partitions(
+    runner: &dyn CommandRunner,
+    esp: &Path,
+    root: &Path,
+    options: &FormatOptions,
+) -> Result<()> {
+    let format_esp = || {
+        let mut args: Vec<&str> = vec!["-F", "32", "-n", options.esp_label()];
+        if let Some(id) = &options.esp_volume_id {
+            args.extend(["-i", id]);
+        }
+        args.extend(options.esp_mkfs_opts.iter().map(String::as_str));
+        let esp_arg = esp.display().to_string();
+        args.push(&esp_arg);
+        runner
+            .run("mkfs.vfat", &args)
+            .with_context(|| format!("mkfs.vfat failed for {}", esp.display()))
+    };
+    let format_root = || {
+        let root_fs = options.root_fs;
+        let program = root_fs.mkfs_program();
+        let mut args = root_fs.mkfs_args(options.root_label());
+        args.extend(options.root_mkfs_opts.iter().cloned());
+        args.push(root.display().to_string());
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        runner
+            .run(program, &args)
+            .with_context(|| format!("{program} failed for {}", root.display()))
+    };
+
+    let (esp_result, root_result) = if options.parallel {
+        std::thread::scope(|scope| {
+            let esp_thread = scope.spawn(format_esp);
+            let root_result = format_root();
+            let esp_result =
+                esp_thread.join().unwrap_or_else(|_| Err(anyhow!("mkfs.vfat thread panicked")));
+            (esp_result, root_result)
+        })
+    } else {
+        (format_esp(), format_root())
+    };
+
+    match (esp_result, root_result) {
+        (Ok(()), Ok(())) => Ok(()),
+        (Err(e), Ok(())) | (Ok(()), Err(e)) => Err(e),
+        (Err(esp_err), Err(root_err)) => Err(esp_err.context(format!("{root_err:#}"))),
+    }
+}
+
+/// Set up the swap partition.
+pub fn format_swap(runner: &dyn CommandRunner, swap: &Path) -> Result<()> {
+    runner
+        .run("mkswap", &["-f", "-L", SWAP_LABEL, &swap.display().to_string()])
+        .with_context(|| format!("mkswap failed for {}", swap.display()))
+}
+
+/// Mounts made for the install, torn down innermost-first when dropped.
+///
+/// Every early return out of the install pipeline drops the guard, so a failed attempt never
+/// leaves the target disk busy (which would make a retry's wipefs/partitioning fail).
+#[derive(Debug, Default)]
+pub struct MountGuard {
+    mounts: Vec<PathBuf>,
+}
+
+impl MountGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a mountpoint that must be unmounted before any mount recorded earlier.
+    pub fn push(&mut self, mountpoint: impl Into<PathBuf>) {
+        self.mounts.push(mountpoint.into());
+    }
+
+    /// Unmount everything recorded so far, innermost first.
+    pub fn unmount_all(&mut self) -> Result<()> {
+        self.unmount_all_with(&SystemRunner, Path::new("/proc"), RetryPolicy::racy())
+    }
+
+    fn unmount_all_with(
+        &mut self,
+        runner: &dyn CommandRunner,
+        proc_root: &Path,
+        policy: RetryPolicy,
+    ) -> Result<()> {
+        while let Some(mountpoint) = self.mounts.pop() {
+            if let Err(e) = unmount_escalating(runner, proc_root, &mountpoint, policy) {
+                // Keep it tracked so a later attempt (or drop) can try again.
+                self.mounts.push(mountpoint.clone());
+                return Err(e)
+                    .with_context(|| format!("Failed to umount {}", mountpoint.display()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `umount`, retried while busy. If it stays busy (a stray chroot process, a lingering fd),
+/// kill whatever has files open under the mountpoint and try again; as a last resort detach
+/// it lazily so the next mount/partition step isn't blocked by the tree.
+fn unmount_escalating(
+    runner: &dyn CommandRunner,
+    proc_root: &Path,
+    mountpoint: &Path,
+    policy: RetryPolicy,
+) -> Result<()> {
+    let target = mountpoint.display().to_string();
+    let err = match runner.run_with_policy("umount", &[&target], policy) {
+        Ok(()) => return Ok(()),
+        Err(e) if is_transient_failure(&e) => e,
+        Err(e) => return Err(e),
+    };
+
+    let pids = processes_using(proc_root, mountpoint);
+    if !pids.is_empty() {
+        eprintln!(
+            "WARN: {target} is busy; killing process(es) using it: {}",
+            pids.iter().map(u32::to_string).collect::<Vec<_>>().join(" ")
+        );
+        for pid in &pids {
+            let _ = runner.run("kill", &["-9", &pid.to_string()]);
+        }
+        if runner.run_with_policy("umount", &[&target], policy).is_ok() {
+            return Ok(());
+        }
+    }
+
+    eprintln!("WARN: {target} is still busy ({err:#}); unmounting lazily");
+    runner.run("umount", &["-l", &target])
+}
+
+/// PIDs (other than ours) whose cwd, root, or an open fd lies under `mountpoint`.
+fn processes_using(proc_root: &Path, mountpoint: &Path) -> Vec<u32> {
+    let Ok(entries) = std::fs::read_dir(proc_root) else {
+        return Vec::new();
+    };
+    let mut pids: Vec<u32> = entries
+        .flatten()
+        .filter_map(|entry| Some((entry.file_name().to_str()?.parse::<u32>().ok()?, entry.path())))
+        .filter(|&(pid, _)| pid != std::process::id())
+        .filter(|(_, dir)| {
+            let fds = std::fs::read_dir(dir.join("fd"))
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|fd| fd.path());
+            [dir.join("cwd"), dir.join("root")]
+                .into_iter()
+                .chain(fds)
+                .filter_map(|link| std::fs::read_link(link).ok())
+                .any(|target| target.starts_with(mountpoint))
+        })
+        .map(|(pid, _)| pid)
+        .collect();
+    pids.sort_unstable();
+    pids
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.unmount_all() {
+            eprintln!("WARN: could not clean up target mounts: {e:#}");
+        }
+    }
+}
+
+pub fn mount_partitions(esp: &Path, root: &Path, plan: &MountPlan) -> Result<MountGuard> {
+    let mut guard = MountGuard::new();
+
     // Ensure /mnt exists in the initramfs, then mount root.
     std::fs::create_dir_all(&plan.target_root)
         .with_context(|| format!("Failed to create {}", plan.target_root.display()))?;
 
     // Mount root first. Anything created under /mnt before this will be hidden by the mount.
-    run(
-        "mount",
-        &["-t", "ext4", &root.display().to_string(), &plan.target_root.display().to_string()],
-    )
-    .with_context(|| format!("Failed to mount root {}", root.display()))?;
+    SystemRunner
+        .run_with_policy(
+            "mount",
+            &[
+                "-t",
+                plan.root_fs.fstype(),
+                &root.display().to_string(),
+                &plan.target_root.display().to_string(),
+            ],
+            RetryPolicy::racy(),
+        )
+        .with_context(|| format!("Failed to mount root {}", root.display()))?;
+    guard.push(&plan.target_root);
 
     // Now create the ESP mountpoint *inside the mounted root*.
     std::fs::create_dir_all(&plan.target_efi)
         .with_context(|| format!("Failed to create {}", plan.target_efi.display()))?;
 
     // Mount ESP.
-    run(
-        "mount",
-        &["-t", "vfat", &esp.display().to_string(), &plan.target_efi.display().to_string()],
-    )
-    .with_context(|| format!("Failed to mount ESP {}", esp.display()))?;
+    mount_esp(esp, &plan.target_efi)?;
+    guard.push(&plan.target_efi);
+
+    // The kernel mounts a FAT with a dirty/damaged header read-only, which would only surface
+    // much later as a confusing EROFS when the boot files are copied. Repair it now instead.
+    if mounted_read_only(&read_mountinfo(), &plan.target_efi) {
+        eprintln!("WARN: ESP {} came up read-only; running fsck.vfat", esp.display());
+        run("umount", &[&plan.target_efi.display().to_string()])
+            .with_context(|| format!("Failed to unmount ESP {}", esp.display()))?;
+        repair_vfat(esp)?;
+        mount_esp(esp, &plan.target_efi)?;
+        if mounted_read_only(&read_mountinfo(), &plan.target_efi) {
+            return Err(anyhow!(
+                "ESP {} is still mounted read-only after fsck.vfat; the FAT filesystem is damaged",
+                esp.display()
+            ));
+        }
+    }
+
+    Ok(guard)
+}
+
+fn mount_esp(esp: &Path, target_efi: &Path) -> Result<()> {
+    let args = ["-t", "vfat", &esp.display().to_string(), &target_efi.display().to_string()];
+    SystemRunner
+        .run_with_policy("mount", &args, RetryPolicy::racy())
+        .with_context(|| format!("Failed to mount ESP {}", esp.display()))
+}
+
+/// `fsck.vfat -a` exits 1 when it fixed something, which is still a success here.
+fn repair_vfat(device: &Path) -> Result<()> {
+    let output = command("fsck.vfat")
+        .args(["-a", &device.display().to_string()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .context("Failed to execute fsck.vfat")?;
+    match output.status.code() {
+        Some(0 | 1) => Ok(()),
+        _ => Err(anyhow!(
+            "fsck.vfat could not repair {}: stdout='{}' stderr='{}'",
+            device.display(),
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        )),
+    }
+}
+
+fn read_mountinfo() -> String {
+    std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default()
+}
+
+/// Whether the mount at `mountpoint` has `ro` among its per-mount options (field 6).
+fn mounted_read_only(mountinfo: &str, mountpoint: &Path) -> bool {
+    mountinfo
+        .lines()
+        .map(|line| line.split(' ').collect::<Vec<_>>())
+        .rfind(|fields| fields.len() > 5 && Path::new(&unescape_mountinfo(fields[4])) == mountpoint)
+        .is_some_and(|fields| fields[5].split(',').any(|opt| opt == "ro"))
+}
 
+/// Anything already in the freshly mounted target besides `lost+found` and the ESP mountpoint.
+///
+/// The root was just formatted, so leftovers mean the format did not take (or the wrong device
+/// was mounted) and extracting would layer the payload over an old, possibly partial system.
+pub fn leftover_entries(plan: &MountPlan) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    collect_leftovers(&plan.target_root, &plan.target_efi, true, &mut found)?;
+    found.sort();
+    Ok(found)
+}
+
+fn collect_leftovers(dir: &Path, keep: &Path, top: bool, found: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if (top && path.file_name().is_some_and(|name| name == "lost+found")) || path == keep {
+            continue;
+        }
+        if keep.starts_with(&path) && path.is_dir() {
+            collect_leftovers(&path, keep, false, found)?;
+        } else {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Filesystem of an installed root, from blkid; the mount must not assume ext4.
+pub fn installed_root_fs(root: &Path) -> Result<RootFs> {
+    let stdout =
+        run_output("blkid", &["-o", "value", "-s", "TYPE", &root.display().to_string()])
+            .with_context(|| format!("Failed to read the filesystem type of {}", root.display()))?;
+    let fstype = stdout.trim();
+    RootFs::from_fstype(fstype).ok_or_else(|| {
+        anyhow!(
+            "Unsupported root filesystem '{fstype}' on {} (expected ext4, xfs or f2fs)",
+            root.display()
+        )
+    })
+}
+
+/// Mount an existing install read-only (root, then its ESP) for inspection.
+///
+/// Nothing is created: the ESP mountpoint must already exist inside the installed root.
+pub fn mount_installed_read_only(esp: &Path, root: &Path, plan: &MountPlan) -> Result<MountGuard> {
+    let mut guard = MountGuard::new();
+    mount_installed_read_only_with(&SystemRunner, &mut guard, esp, root, plan)?;
+    Ok(guard)
+}
+
+fn mount_installed_read_only_with(
+    runner: &dyn CommandRunner,
+    guard: &mut MountGuard,
+    esp: &Path,
+    root: &Path,
+    plan: &MountPlan,
+) -> Result<()> {
+    std::fs::create_dir_all(&plan.target_root)
+        .with_context(|| format!("Failed to create {}", plan.target_root.display()))?;
+
+    let target_root = plan.target_root.display().to_string();
+    runner
+        .run(
+            "mount",
+            &[
+                "-o",
+                plan.root_fs.read_only_mount_opts(),
+                "-t",
+                plan.root_fs.fstype(),
+                &root.display().to_string(),
+                &target_root,
+            ],
+        )
+        .with_context(|| format!("Failed to mount root {}", root.display()))?;
+    guard.push(&plan.target_root);
+
+    if !plan.target_efi.is_dir() {
+        return Err(anyhow!("Installed root has no ESP mountpoint {}", plan.target_efi.display()));
+    }
+    runner
+        .run(
+            "mount",
+            &[
+                "-o",
+                "ro",
+                "-t",
+                "vfat",
+                &esp.display().to_string(),
+                &plan.target_efi.display().to_string(),
+            ],
+        )
+        .with_context(|| format!("Failed to mount ESP {}", esp.display()))?;
+    guard.push(&plan.target_efi);
+
+    Ok(())
+}
+
+/// Host pseudo filesystems bind-mounted into the target, in mount order.
+const PSEUDO_FILESYSTEMS: [&str; 4] = ["/proc", "/sys", "/dev", "/dev/pts"];
+
+/// Bind-mount `/proc`, `/sys`, `/dev` and `/dev/pts` into `target_root` so chroot steps
+/// (locale-gen, update-initramfs, ...) see a working system. A plain bind of `/sys` leaves
+/// out the efivarfs submount, so when the host's efivarfs is writable it is mounted into the
+/// target as well (`grub-install` writes the NVRAM entry from inside the chroot).
+///
+/// Each mount is recorded on `guard`, so teardown happens innermost first and before the
+/// target partitions themselves. Targets that are already mountpoints (e.g. left over from an
+/// earlier attempt) are skipped.
+pub fn bind_mount_pseudo_filesystems(guard: &mut MountGuard, target_root: &Path) -> Result<()> {
+    let mounted = mount_points(&read_mountinfo());
+
+    for source in PSEUDO_FILESYSTEMS {
+        let target = target_root.join(source.trim_start_matches('/'));
+        if mounted.contains(&target) {
+            continue;
+        }
+        std::fs::create_dir_all(&target)
+            .with_context(|| format!("Failed to create {}", target.display()))?;
+        run("mount", &["--bind", source, &target.display().to_string()])
+            .with_context(|| format!("Failed to bind-mount {source} into the target"))?;
+        guard.push(target);
+    }
+
+    if efi::efivars_writable() {
+        let efivars = target_root.join("sys/firmware/efi/efivars");
+        if !mounted.contains(&efivars) {
+            run("mount", &["-t", "efivarfs", "efivarfs", &efivars.display().to_string()])
+                .context("Failed to mount efivarfs into the target")?;
+            guard.push(efivars);
+        }
+    }
     Ok(())
 }
 
-pub fn extract_rootfs_payload(payload: &Path, target_root: &Path) -> Result<()> {
+/// Undo any partial state when the operator cancels before the install starts: unmount
+/// whatever is still mounted at or under `target_root` (e.g. left behind by an earlier attempt
+/// whose cleanup failed). A no-op when nothing is mounted there.
+pub fn cancel_install(target_root: &Path) -> Result<()> {
+    cancel_install_with(&read_mountinfo(), target_root)
+}
+
+fn cancel_install_with(mountinfo: &str, target_root: &Path) -> Result<()> {
+    let mut guard = MountGuard::new();
+    // mountinfo lists parents before children, which is the order the guard expects.
+    for mountpoint in mounts_under(mountinfo, target_root) {
+        guard.push(mountpoint);
+    }
+    guard.unmount_all().context("Failed to clean up target mounts")
+}
+
+fn mounts_under(mountinfo: &str, target_root: &Path) -> Vec<PathBuf> {
+    mount_points(mountinfo).into_iter().filter(|mp| mp.starts_with(target_root)).collect()
+}
+
+/// Mount points listed in `/proc/self/mountinfo` contents (field 5, octal escapes decoded).
+fn mount_points(mountinfo: &str) -> Vec<PathBuf> {
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|field| PathBuf::from(unescape_mountinfo(field)))
+        .collect()
+}
+
+fn unescape_mountinfo(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let digits: String = chars.clone().take(3).collect();
+            if digits.len() == 3
+                && let Ok(byte) = u8::from_str_radix(&digits, 8)
+            {
+                out.push(char::from(byte));
+                chars.nth(2);
+                continue;
+            }
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Unpack the payload into `target_root`, reporting how much of it has been fed to `tar`.
+pub fn extract_rootfs_payload(
+    payload: &Path,
+    target_root: &Path,
+    reporter: &dyn ProgressReporter,
+) -> Result<()> {
     if !payload.exists() {
         return Err(anyhow!("Payload does not exist: {}", payload.display()));
     }
 
     // Extract payload with permissions/ownership preserved.
     // Note: tar must have zstd support in the initramfs.
-    run(
-        "tar",
-        &[
-            "--zstd",
-            "-xpf",
-            &payload.display().to_string(),
-            "-C",
-            &target_root.display().to_string(),
-        ],
-    )
-    .with_context(|| {
+    let target = target_root.display().to_string();
+    let child = command("tar")
+        .args(["--zstd", "-xpf", "-", "-C", &target])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to spawn tar")?;
+    feed_tar(child, payload, reporter).with_context(|| {
         format!("Failed to extract payload {} to {}", payload.display(), target_root.display())
     })
 }
 
-pub fn configure_initial_users(plan: &MountPlan) -> Result<()> {
-    let username = "truthdb";
-    let password = "123456";
+fn feed_tar(
+    mut child: std::process::Child,
+    payload: &Path,
+    reporter: &dyn ProgressReporter,
+) -> Result<()> {
+    use std::io::{Read, Write};
+
+    let mut file = std::fs::File::open(payload)
+        .with_context(|| format!("Failed to open {}", payload.display()))?;
+    let total = file.metadata().map(|m| m.len()).unwrap_or(0).max(1);
+    // Drain stderr meanwhile so a chatty tar can't block on a full pipe.
+    let mut stderr = child.stderr.take().ok_or_else(|| anyhow!("Failed to open tar stderr"))?;
+    let stderr = std::thread::spawn(move || {
+        let mut text = String::new();
+        let _ = stderr.read_to_string(&mut text);
+        text
+    });
+
+    let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("Failed to open tar stdin"))?;
+    let mut buf = vec![0u8; EXTRACT_CHUNK];
+    let (mut fed, mut last_percent) = (0u64, None);
+    loop {
+        let n =
+            file.read(&mut buf).with_context(|| format!("Failed to read {}", payload.display()))?;
+        // A write error means tar has exited; its status and stderr explain why.
+        if n == 0 || stdin.write_all(&buf[..n]).is_err() {
+            break;
+        }
+        fed += n as u64;
+        let percent = fed * 100 / total;
+        if last_percent != Some(percent) {
+            last_percent = Some(percent);
+            reporter.sub_progress(fed as f32 / total as f32);
+        }
+    }
+    drop(stdin);
+
+    let status = child.wait().context("Failed to wait for tar")?;
+    let stderr = stderr.join().unwrap_or_default();
+    if !status.success() {
+        return Err(anyhow!("tar failed: stdout='' stderr='{stderr}'"));
+    }
+    Ok(())
+}
+
+/// What to do with the root account of the installed system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RootAccess {
+    /// Set the root password.
+    Password(Credential),
+    /// Lock the root password and give root a non-login shell; use sudo instead.
+    Locked,
+    /// Leave root exactly as the payload ships it.
+    Unchanged,
+}
+
+impl Default for RootAccess {
+    fn default() -> Self {
+        RootAccess::Password(Credential::Plain(DEFAULT_PASSWORD.to_string()))
+    }
+}
+
+/// A login password as supplied by the operator.
+///
+/// Either way only a crypt(3) hash reaches the target (`chpasswd -e`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credential {
+    /// Plaintext; hashed in-process right before use.
+    Plain(String),
+    /// Already a crypt(3) hash; passed through untouched.
+    Hashed(String),
+}
+
+impl Credential {
+    fn crypt_hash(&self, scheme: crypt::PasswordScheme) -> Result<String> {
+        match self {
+            Credential::Plain(password) => crypt::hash_password(password, scheme),
+            Credential::Hashed(hash) => Ok(hash.clone()),
+        }
+    }
+}
+
+/// The initial login account and how root/sudo are set up around it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserSetup {
+    pub username: String,
+    pub password: Credential,
+    pub root_access: RootAccess,
+    /// Let the user sudo without a password prompt (kiosk/automation installs).
+    pub passwordless_sudo: bool,
+    /// How plaintext passwords are hashed; pre-hashed ones keep their own scheme.
+    pub password_scheme: crypt::PasswordScheme,
+}
+
+impl Default for UserSetup {
+    fn default() -> Self {
+        Self {
+            username: DEFAULT_USERNAME.to_string(),
+            password: Credential::Plain(DEFAULT_PASSWORD.to_string()),
+            root_access: RootAccess::default(),
+            passwordless_sudo: false,
+            password_scheme: crypt::PasswordScheme::default(),
+        }
+    }
+}
+
+pub fn configure_initial_users(
+    runner: &dyn CommandRunner,
+    plan: &MountPlan,
+    users: &UserSetup,
+) -> Result<()> {
+    let username = users.username.as_str();
 
     // Ensure sudo is present in the payload; otherwise the user won't actually be able to elevate.
     let sudo_path = plan.target_root.join("usr/bin/sudo");
@@ -106,27 +862,90 @@ pub fn configure_initial_users(plan: &MountPlan) -> Result<()> {
 
     // Ensure the sudo group exists (on Debian it's usually created by the sudo package, but keep
     // this resilient).
-    chroot_run(&plan.target_root, "/usr/sbin/groupadd", &["-f", "sudo"])
+    chroot_run(runner, &plan.target_root, "/usr/sbin/groupadd", &["-f", "sudo"])
         .context("Failed to ensure sudo group exists")?;
 
     if !target_user_exists(&plan.target_root, username).unwrap_or(false) {
         // Create a normal user with home dir and bash shell.
         chroot_run(
+            runner,
             &plan.target_root,
             "/usr/sbin/useradd",
             &["-m", "-s", "/bin/bash", "-G", "sudo", username],
         )
-        .context("Failed to create truthdb user")?;
+        .with_context(|| format!("Failed to create {username} user"))?;
+    }
+
+    // Set the user password, then apply the root policy.
+    chroot_chpasswd(runner, &plan.target_root, username, &users.password, users.password_scheme)
+        .with_context(|| format!("Failed to set {username} password"))?;
+    configure_root_access(runner, plan, &users.root_access, users.password_scheme)?;
+
+    if users.passwordless_sudo {
+        write_sudoers_nopasswd(runner, plan, username)
+            .context("Failed to configure passwordless sudo")?;
+    }
+
+    Ok(())
+}
+
+fn write_sudoers_nopasswd(
+    runner: &dyn CommandRunner,
+    plan: &MountPlan,
+    username: &str,
+) -> Result<()> {
+    let sudoers_d = plan.target_root.join("etc/sudoers.d");
+    std::fs::create_dir_all(&sudoers_d)
+        .with_context(|| format!("Failed to create {}", sudoers_d.display()))?;
+
+    let drop_in = sudoers_d.join(format!("10-{username}"));
+    std::fs::write(&drop_in, format!("{username} ALL=(ALL) NOPASSWD:ALL\n"))
+        .with_context(|| format!("Failed to write {}", drop_in.display()))?;
+    // sudo ignores drop-ins that are group/world writable; 0440 is what visudo creates.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&drop_in, std::fs::Permissions::from_mode(0o440))
+            .with_context(|| format!("Failed to chmod {}", drop_in.display()))?;
     }
 
-    // Set user and root passwords.
-    chroot_chpasswd(&plan.target_root, username, password)
-        .context("Failed to set truthdb password")?;
-    chroot_chpasswd(&plan.target_root, "root", password).context("Failed to set root password")?;
+    // A broken sudoers file locks everyone out of sudo, so validate it when visudo is available.
+    if plan.target_root.join("usr/sbin/visudo").exists() {
+        let in_target = path_in_target_root(&plan.target_root, &drop_in)?;
+        chroot_run(runner, &plan.target_root, "/usr/sbin/visudo", &["-c", "-f", &in_target])
+            .with_context(|| format!("visudo rejected {in_target}"))?;
+    }
 
     Ok(())
 }
 
+fn configure_root_access(
+    runner: &dyn CommandRunner,
+    plan: &MountPlan,
+    root_access: &RootAccess,
+    scheme: crypt::PasswordScheme,
+) -> Result<()> {
+    match root_access {
+        RootAccess::Password(password) => {
+            chroot_chpasswd(runner, &plan.target_root, "root", password, scheme)
+                .context("Failed to set root password")
+        }
+        RootAccess::Locked => {
+            chroot_run(runner, &plan.target_root, "/usr/sbin/usermod", &["-L", "root"])
+                .context("Failed to lock root password")?;
+            // `sudo -s` still works; it uses the invoking user's shell.
+            chroot_run(
+                runner,
+                &plan.target_root,
+                "/usr/sbin/usermod",
+                &["-s", "/usr/sbin/nologin", "root"],
+            )
+            .context("Failed to set root shell")
+        }
+        RootAccess::Unchanged => Ok(()),
+    }
+}
+
 pub fn configure_hostname(plan: &MountPlan, hostname: &str) -> Result<()> {
     let etc_dir = plan.target_root.join("etc");
     std::fs::create_dir_all(&etc_dir)
@@ -188,28 +1007,420 @@ pub fn configure_hostname(plan: &MountPlan, hostname: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn configure_first_boot_dhcp(plan: &MountPlan) -> Result<()> {
-    // Configure networking first so DHCP works even if other tweaks fail.
-    ensure_machine_id(plan).context("Failed to ensure machine-id")?;
-    configure_systemd_networkd_dhcp(plan).context("Failed to configure systemd-networkd DHCP")?;
+/// Which address families the installed system configures on first boot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    /// DHCPv4 only; IPv6 (including link-local) is turned off.
+    V4,
+    /// DHCPv6/SLAAC only.
+    V6,
+    /// DHCPv4 plus DHCPv6/SLAAC.
+    #[default]
+    Dual,
+}
 
-    // Best-effort: some payloads or usr-merge layouts can make /sbin/init handling surprising.
-    // DHCP should not be blocked by this.
-    if let Err(e) = ensure_systemd_pid1(plan) {
-        eprintln!("WARN: could not ensure systemd is PID 1: {e:#}");
+impl FromStr for IpFamily {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "v4" => Ok(IpFamily::V4),
+            "v6" => Ok(IpFamily::V6),
+            "dual" => Ok(IpFamily::Dual),
+            other => Err(anyhow!("Invalid IP family '{other}' (expected v4, v6, or dual)")),
+        }
     }
-    Ok(())
 }
 
-fn ensure_machine_id(plan: &MountPlan) -> Result<()> {
-    let machine_id_path = plan.target_root.join("etc/machine-id");
-    if let Ok(contents) = std::fs::read_to_string(&machine_id_path)
-        && contents.trim().len() >= 32
-    {
-        return Ok(());
+/// Service that brings up networking on first boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkBackend {
+    Networkd,
+    NetworkManager,
+}
+
+impl FromStr for NetworkBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "networkd" => Ok(NetworkBackend::Networkd),
+            "networkmanager" => Ok(NetworkBackend::NetworkManager),
+            other => Err(anyhow!(
+                "Invalid network backend '{other}' (expected networkd or networkmanager)"
+            )),
+        }
     }
+}
 
-    // Generate a deterministic-length id from the kernel UUID source.
+impl NetworkBackend {
+    /// Human-readable name for progress output.
+    pub fn name(self) -> &'static str {
+        match self {
+            NetworkBackend::Networkd => "systemd-networkd",
+            NetworkBackend::NetworkManager => "NetworkManager",
+        }
+    }
+
+    fn unit(self) -> &'static str {
+        match self {
+            NetworkBackend::Networkd => "systemd-networkd.service",
+            NetworkBackend::NetworkManager => "NetworkManager.service",
+        }
+    }
+}
+
+/// Use the requested backend (which must be in the payload), or pick one: NetworkManager when
+/// the payload ships it (desktop images; running networkd next to it conflicts), else networkd.
+pub fn select_network_backend(
+    plan: &MountPlan,
+    requested: Option<NetworkBackend>,
+) -> Result<NetworkBackend> {
+    match requested {
+        Some(backend) if has_systemd_unit(&plan.target_root, backend.unit()) => Ok(backend),
+        Some(backend) => Err(anyhow!(
+            "Requested network backend needs {} but the payload does not include it",
+            backend.unit()
+        )),
+        None if has_systemd_unit(&plan.target_root, NetworkBackend::NetworkManager.unit()) => {
+            Ok(NetworkBackend::NetworkManager)
+        }
+        None => Ok(NetworkBackend::Networkd),
+    }
+}
+
+pub fn configure_timezone(plan: &MountPlan, timezone: &str) -> Result<()> {
+    let zoneinfo = plan.target_root.join("usr/share/zoneinfo").join(timezone);
+    if !zoneinfo.is_file() {
+        return Err(anyhow!(
+            "Unknown timezone '{timezone}' (missing {} in target rootfs)",
+            zoneinfo.display()
+        ));
+    }
+
+    let localtime = plan.target_root.join("etc/localtime");
+    symlink_force(&format!("/usr/share/zoneinfo/{timezone}"), &localtime)
+        .with_context(|| format!("Failed to symlink {}", localtime.display()))?;
+
+    // Debian's tzdata also reads /etc/timezone.
+    let timezone_path = plan.target_root.join("etc/timezone");
+    std::fs::write(&timezone_path, format!("{timezone}\n"))
+        .with_context(|| format!("Failed to write {}", timezone_path.display()))
+}
+
+/// Where local packages are staged inside the target while `dpkg` runs.
+const LOCAL_DEBS_DIR: &str = "var/cache/truthdb-installer/debs";
+
+/// Install every `*.deb` in `debs_dir` into the target with `dpkg -i` (via chroot).
+///
+/// Needs the pseudo filesystems bind-mounted (maintainer scripts expect `/proc` and `/dev`).
+/// If dpkg leaves dependencies unresolved and apt-get is present, `apt-get -f install` is
+/// tried without downloading anything.
+pub fn install_local_debs(
+    runner: &dyn CommandRunner,
+    plan: &MountPlan,
+    debs_dir: &Path,
+) -> Result<()> {
+    let mut debs = Vec::new();
+    for entry in std::fs::read_dir(debs_dir)
+        .with_context(|| format!("Failed to read {}", debs_dir.display()))?
+    {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "deb") && path.is_file() {
+            debs.push(path);
+        }
+    }
+    if debs.is_empty() {
+        return Err(anyhow!("No .deb files in {}", debs_dir.display()));
+    }
+    debs.sort();
+
+    let staging = plan.target_root.join(LOCAL_DEBS_DIR);
+    std::fs::create_dir_all(&staging)
+        .with_context(|| format!("Failed to create {}", staging.display()))?;
+    let mut staged = Vec::new();
+    for deb in &debs {
+        let file_name = deb.file_name().ok_or_else(|| anyhow!("Bad path {}", deb.display()))?;
+        let dst = staging.join(file_name);
+        std::fs::copy(deb, &dst)
+            .with_context(|| format!("Failed to copy {} to {}", deb.display(), dst.display()))?;
+        staged.push(path_in_target_root(&plan.target_root, &dst)?);
+    }
+
+    let mut args = vec!["-i"];
+    args.extend(staged.iter().map(String::as_str));
+    let result = chroot_run(runner, &plan.target_root, "/usr/bin/dpkg", &args).or_else(|e| {
+        if !plan.target_root.join("usr/bin/apt-get").exists() {
+            return Err(e);
+        }
+        chroot_run(
+            runner,
+            &plan.target_root,
+            "/usr/bin/apt-get",
+            &["-y", "--no-download", "-f", "install"],
+        )
+        .map_err(|apt_err| e.context(format!("apt-get -f install also failed: {apt_err:#}")))
+    });
+
+    // The packages are installed (or not); either way don't leave the .debs behind.
+    let _ = std::fs::remove_dir_all(&staging);
+    result
+}
+
+/// Rebuild the target's initramfs for every installed kernel, so modules and hooks added
+/// after the payload was built (local packages, module config) are picked up at boot.
+///
+/// Uses initramfs-tools when present, otherwise dracut. Needs the pseudo filesystems
+/// bind-mounted; the tool's output is part of the error on failure.
+pub fn regenerate_initramfs(runner: &dyn CommandRunner, plan: &MountPlan) -> Result<()> {
+    let root = &plan.target_root;
+    if root.join("usr/sbin/update-initramfs").exists() {
+        chroot_run(runner, root, "/usr/sbin/update-initramfs", &["-u", "-k", "all"])
+    } else if root.join("usr/bin/dracut").exists() {
+        chroot_run(runner, root, "/usr/bin/dracut", &["--force", "--regenerate-all"])
+    } else {
+        Err(anyhow!("Neither update-initramfs nor dracut is installed in the target"))
+    }
+}
+
+/// zram swap size: a share of RAM or a fixed size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ZramSize {
+    /// Percent of RAM (1-200; zram compresses, so more than 100% is reasonable).
+    Percent(u8),
+    Mib(u64),
+}
+
+impl ZramSize {
+    /// Value for `zram-size` in zram-generator.conf (an expression over `ram`, in MiB).
+    fn generator_expr(self) -> String {
+        match self {
+            ZramSize::Percent(pct) => format!("ram * {pct} / 100"),
+            ZramSize::Mib(mib) => mib.to_string(),
+        }
+    }
+}
+
+impl FromStr for ZramSize {
+    type Err = anyhow::Error;
+
+    /// Accepts `50%`, `512M`, or `4G`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid zram size '{s}' (expected e.g. 50%, 512M, or 4G)");
+        let (digits, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+        let value: u64 = digits.parse().map_err(|_| invalid())?;
+        if value == 0 {
+            return Err(invalid());
+        }
+        match unit {
+            "%" if value <= 200 => Ok(ZramSize::Percent(value as u8)),
+            "M" | "MiB" => Ok(ZramSize::Mib(value)),
+            "G" | "GiB" => value.checked_mul(1024).map(ZramSize::Mib).ok_or_else(invalid),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for ZramSize {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Configure compressed swap in RAM via systemd-zram-generator, which must be in the payload.
+///
+/// The generator creates and activates `dev-zram0.swap` at boot, so nothing needs enabling.
+pub fn configure_zram(plan: &MountPlan, size: ZramSize) -> Result<()> {
+    let generator_present = ["lib", "usr/lib"].iter().any(|lib| {
+        plan.target_root.join(lib).join("systemd/system-generators/zram-generator").exists()
+    });
+    if !generator_present {
+        return Err(anyhow!(
+            "Payload has no zram-generator (include systemd-zram-generator to use zram)"
+        ));
+    }
+
+    let systemd_dir = plan.target_root.join("etc/systemd");
+    std::fs::create_dir_all(&systemd_dir)
+        .with_context(|| format!("Failed to create {}", systemd_dir.display()))?;
+    let conf_path = systemd_dir.join("zram-generator.conf");
+    std::fs::write(&conf_path, zram_generator_conf(size))
+        .with_context(|| format!("Failed to write {}", conf_path.display()))
+}
+
+fn zram_generator_conf(size: ZramSize) -> String {
+    format!(
+        "[zram0]\nzram-size = {}\ncompression-algorithm = zstd\nswap-priority = 100\n",
+        size.generator_expr()
+    )
+}
+
+pub fn configure_locale(runner: &dyn CommandRunner, plan: &MountPlan, locale: &str) -> Result<()> {
+    let default_dir = plan.target_root.join("etc/default");
+    std::fs::create_dir_all(&default_dir)
+        .with_context(|| format!("Failed to create {}", default_dir.display()))?;
+    let default_locale = default_dir.join("locale");
+    std::fs::write(&default_locale, format!("LANG={locale}\n"))
+        .with_context(|| format!("Failed to write {}", default_locale.display()))?;
+
+    // Without the `locales` package there is nothing to generate; glibc falls back to C.
+    let locale_gen = plan.target_root.join("etc/locale.gen");
+    if !locale_gen.exists() || !plan.target_root.join("usr/sbin/locale-gen").exists() {
+        eprintln!("WARN: target has no locale-gen; {locale} will not be generated");
+        return Ok(());
+    }
+
+    let contents = std::fs::read_to_string(&locale_gen)
+        .with_context(|| format!("Failed to read {}", locale_gen.display()))?;
+    std::fs::write(&locale_gen, enable_locale_gen_entry(&contents, locale))
+        .with_context(|| format!("Failed to write {}", locale_gen.display()))?;
+
+    chroot_run(runner, &plan.target_root, "/usr/sbin/locale-gen", &[]).context("locale-gen failed")
+}
+
+/// Where kbd keeps keymaps, relative to the target root (Debian, then other distributions).
+const KEYMAP_DIRS: [&str; 2] = ["usr/share/keymaps", "usr/lib/kbd/keymaps"];
+
+/// Write `/etc/vconsole.conf` so the installed system's text consoles use `font`/`keymap`.
+///
+/// When the target ships keymaps, `keymap` must be one of them; otherwise it is written as given.
+pub fn configure_console(plan: &MountPlan, font: Option<&str>, keymap: Option<&str>) -> Result<()> {
+    if let Some(keymap) = keymap {
+        let dirs: Vec<_> = KEYMAP_DIRS
+            .iter()
+            .map(|rel| plan.target_root.join(rel))
+            .filter(|dir| dir.is_dir())
+            .collect();
+        if !dirs.is_empty() && !dirs.iter().any(|dir| keymap_exists(dir, keymap)) {
+            return Err(anyhow!("Unknown keymap '{keymap}' (not found in the target's keymaps)"));
+        }
+    }
+
+    let etc = plan.target_root.join("etc");
+    std::fs::create_dir_all(&etc).with_context(|| format!("Failed to create {}", etc.display()))?;
+    let vconsole = etc.join("vconsole.conf");
+    std::fs::write(&vconsole, vconsole_conf(font, keymap))
+        .with_context(|| format!("Failed to write {}", vconsole.display()))
+}
+
+fn vconsole_conf(font: Option<&str>, keymap: Option<&str>) -> String {
+    let mut conf = String::new();
+    if let Some(keymap) = keymap {
+        conf.push_str(&format!("KEYMAP={keymap}\n"));
+    }
+    if let Some(font) = font {
+        conf.push_str(&format!("FONT={font}\n"));
+    }
+    conf
+}
+
+/// Keymaps live in nested layout directories, e.g. `i386/qwerty/us.kmap.gz`.
+fn keymap_exists(dir: &Path, keymap: &str) -> bool {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let path = entry.path();
+        if path.is_dir() {
+            return keymap_exists(&path, keymap);
+        }
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        [".map", ".map.gz", ".kmap", ".kmap.gz"]
+            .iter()
+            .any(|ext| name.strip_suffix(ext) == Some(keymap))
+    })
+}
+
+/// Uncomment (or append) the `locale.gen` entry for `locale`, e.g. `en_US.UTF-8 UTF-8`.
+fn enable_locale_gen_entry(contents: &str, locale: &str) -> String {
+    let charset = locale.split_once('.').map_or("ISO-8859-1", |(_, charset)| charset);
+    let entry = format!("{locale} {charset}");
+
+    let mut found = false;
+    let mut out = String::new();
+    for line in contents.lines() {
+        let uncommented = line.trim_start_matches('#').trim();
+        if uncommented == entry {
+            found = true;
+            out.push_str(&entry);
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+    if !found {
+        out.push_str(&entry);
+        out.push('\n');
+    }
+    out
+}
+
+/// Install `key` as the only entry in `username`'s `~/.ssh/authorized_keys`.
+pub fn install_authorized_key(plan: &MountPlan, username: &str, key: &str) -> Result<()> {
+    let (uid, gid, home) = target_user_ids(&plan.target_root, username)?;
+    let home = plan.target_root.join(home.trim_start_matches('/'));
+    let ssh_dir = home.join(".ssh");
+    std::fs::create_dir_all(&ssh_dir)
+        .with_context(|| format!("Failed to create {}", ssh_dir.display()))?;
+
+    let authorized_keys = ssh_dir.join("authorized_keys");
+    std::fs::write(&authorized_keys, format!("{}\n", key.trim()))
+        .with_context(|| format!("Failed to write {}", authorized_keys.display()))?;
+
+    // sshd's StrictModes rejects keys readable or writable by others.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&ssh_dir, std::fs::Permissions::from_mode(0o700))
+            .with_context(|| format!("Failed to chmod {}", ssh_dir.display()))?;
+        std::fs::set_permissions(&authorized_keys, std::fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to chmod {}", authorized_keys.display()))?;
+        for path in [&ssh_dir, &authorized_keys] {
+            unix_fs::chown(path, Some(uid), Some(gid))
+                .with_context(|| format!("Failed to chown {}", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn configure_first_boot_dhcp(
+    plan: &MountPlan,
+    family: IpFamily,
+    backend: NetworkBackend,
+) -> Result<()> {
+    // Configure networking first so DHCP works even if other tweaks fail.
+    ensure_machine_id(plan).context("Failed to ensure machine-id")?;
+    match backend {
+        NetworkBackend::Networkd => configure_systemd_networkd_dhcp(plan, family)
+            .context("Failed to configure systemd-networkd DHCP")?,
+        NetworkBackend::NetworkManager => configure_network_manager_dhcp(plan, family)
+            .context("Failed to configure NetworkManager DHCP")?,
+    }
+
+    // Best-effort: some payloads or usr-merge layouts can make /sbin/init handling surprising.
+    // DHCP should not be blocked by this.
+    if let Err(e) = ensure_systemd_pid1(plan) {
+        eprintln!("WARN: could not ensure systemd is PID 1: {e:#}");
+    }
+    Ok(())
+}
+
+fn ensure_machine_id(plan: &MountPlan) -> Result<()> {
+    let machine_id_path = plan.target_root.join("etc/machine-id");
+    if let Ok(contents) = std::fs::read_to_string(&machine_id_path)
+        && contents.trim().len() >= 32
+    {
+        return Ok(());
+    }
+
+    // Generate a deterministic-length id from the kernel UUID source.
     // /proc should be mounted in the initramfs environment.
     let uuid = std::fs::read_to_string("/proc/sys/kernel/random/uuid")
         .context("Failed to read /proc/sys/kernel/random/uuid")?;
@@ -244,19 +1455,33 @@ fn ensure_machine_id(plan: &MountPlan) -> Result<()> {
     Ok(())
 }
 
+/// `register_nvram` off skips the `efibootmgr` entry (e.g. for image installs, which must not
+/// touch the host's NVRAM); the fallback loader is installed either way.
 pub fn configure_boot_systemd_boot(
     disk_dev: &Path,
     esp_dev: &Path,
     root_dev: &Path,
     plan: &MountPlan,
+    register_nvram: bool,
 ) -> Result<()> {
     let root_uuid = blkid_uuid(root_dev).context("Failed to get root UUID")?;
     let esp_uuid = blkid_uuid(esp_dev).context("Failed to get ESP UUID")?;
 
     write_fstab(&root_uuid, &esp_uuid, plan).context("Failed to write /etc/fstab")?;
 
+    // On Secure Boot machines the unsigned systemd-boot won't load on its own; chainload it from
+    // the signed shim when the initramfs carries one.
+    let secure_boot = efi::secure_boot_enabled();
+    let shim = select_shim_chain(secure_boot, Path::new("/"));
+    if secure_boot == Some(true) && shim.is_none() {
+        eprintln!(
+            "WARN: Secure Boot is enabled but no shim is available; the install may not boot"
+        );
+    }
+
     // Install systemd-boot into the mounted ESP.
-    install_systemd_boot_efi(&plan.target_efi).context("Failed to install systemd-boot EFI")?;
+    install_systemd_boot_efi(&plan.target_efi, Path::new("/"), shim.as_ref())
+        .context("Failed to install systemd-boot EFI")?;
 
     // Copy the installed Debian kernel + initrd into ESP so systemd-boot can load them.
     let (kernel_src, initrd_src) = find_installed_kernel_and_initrd(&plan.target_root)
@@ -271,10 +1496,10 @@ pub fn configure_boot_systemd_boot(
         std::fs::create_dir_all(parent)
             .with_context(|| format!("Failed to create {}", parent.display()))?;
     }
-    std::fs::copy(&kernel_src, &kernel_dst).with_context(|| {
+    copy_preserving(&kernel_src, &kernel_dst).with_context(|| {
         format!("Failed to copy kernel {} to {}", kernel_src.display(), kernel_dst.display())
     })?;
-    std::fs::copy(&initrd_src, &initrd_dst).with_context(|| {
+    copy_preserving(&initrd_src, &initrd_dst).with_context(|| {
         format!("Failed to copy initrd {} to {}", initrd_src.display(), initrd_dst.display())
     })?;
 
@@ -286,18 +1511,122 @@ pub fn configure_boot_systemd_boot(
     )
     .context("Failed to write systemd-boot entry")?;
 
-    verify_esp_layout(&plan.target_efi).context("ESP does not contain expected boot files")?;
+    verify_esp_layout(&plan.target_efi, shim.is_some())
+        .context("ESP does not contain expected boot files")?;
 
     // Some firmwares/VMs won't auto-scan the fallback path (EFI/BOOT/BOOTX64.EFI) on an internal
     // disk. Create an explicit NVRAM boot entry as well.
-    if let Err(e) = register_uefi_boot_entry(disk_dev) {
+    // Note: efibootmgr expects the EFI path with backslashes.
+    let efi_loader = if shim.is_some() {
+        r"\\EFI\\BOOT\\BOOTX64.EFI"
+    } else {
+        r"\\EFI\\systemd\\systemd-bootx64.efi"
+    };
+    if register_nvram && let Err(e) = register_uefi_boot_entry(disk_dev, efi_loader) {
         eprintln!("WARN: could not register UEFI boot entry (will rely on EFI fallback): {e:#}");
     }
 
     Ok(())
 }
 
-fn configure_systemd_networkd_dhcp(plan: &MountPlan) -> Result<()> {
+/// Install GRUB (x86_64-efi) from the payload and generate its config.
+///
+/// Runs inside the target, so the pseudo filesystems must already be bind-mounted
+/// (see [`bind_mount_pseudo_filesystems`]). `grub-install` registers its own NVRAM entry
+/// unless `register_nvram` is off or efivarfs is not writable; the fallback loader
+/// (`EFI/BOOT/BOOTX64.EFI`) is installed either way.
+pub fn configure_boot_grub(
+    runner: &dyn CommandRunner,
+    esp_dev: &Path,
+    root_dev: &Path,
+    plan: &MountPlan,
+    register_nvram: bool,
+) -> Result<()> {
+    let root_uuid = blkid_uuid(root_dev).context("Failed to get root UUID")?;
+    let esp_uuid = blkid_uuid(esp_dev).context("Failed to get ESP UUID")?;
+
+    write_fstab(&root_uuid, &esp_uuid, plan).context("Failed to write /etc/fstab")?;
+
+    // Same policy as systemd-boot: behind Secure Boot, boot through the signed shim when the
+    // payload carries it (shim-signed + grub-efi-amd64-signed).
+    let secure_boot = efi::secure_boot_enabled();
+    let shim = secure_boot == Some(true) && grub_shim_available(&plan.target_root);
+    if secure_boot == Some(true) && !shim {
+        eprintln!(
+            "WARN: Secure Boot is enabled but the payload has no signed shim/GRUB; the install \
+             may not boot"
+        );
+    }
+
+    // Without writable efivarfs grub-install's efibootmgr call fails; degrade to the fallback
+    // loader like register_uefi_boot_entry does.
+    let register_nvram = register_nvram && efi::efivars_writable();
+    install_grub(runner, plan, register_nvram, shim)?;
+
+    verify_grub_layout(plan, shim).context("Target does not contain expected GRUB files")
+}
+
+/// Signed shim and GRUB as Debian's `grub-install --uefi-secure-boot` expects them.
+const GRUB_SHIM_FILES: [&str; 2] =
+    ["usr/lib/shim/shimx64.efi.signed", "usr/lib/grub/x86_64-efi-signed/grubx64.efi.signed"];
+
+fn grub_shim_available(target_root: &Path) -> bool {
+    GRUB_SHIM_FILES.iter().all(|rel| target_root.join(rel).exists())
+}
+
+fn install_grub(
+    runner: &dyn CommandRunner,
+    plan: &MountPlan,
+    register_nvram: bool,
+    shim: bool,
+) -> Result<()> {
+    let grub_install = plan.target_root.join("usr/sbin/grub-install");
+    if !grub_install.exists() {
+        return Err(anyhow!(
+            "Payload has no grub-install ({}); include grub-efi-amd64 to use --bootloader grub",
+            grub_install.display()
+        ));
+    }
+
+    let efi_dir = path_in_target_root(&plan.target_root, &plan.target_efi)?;
+    let efi_dir_arg = format!("--efi-directory={efi_dir}");
+    let bootloader_id_arg = format!("--bootloader-id={GRUB_BOOTLOADER_ID}");
+    // --force-extra-removable also writes EFI/BOOT/BOOTX64.EFI, which firmware boots without
+    // an NVRAM entry (image installs, read-only efivarfs, firmware that drops entries).
+    let mut args =
+        vec!["--target=x86_64-efi", &efi_dir_arg, &bootloader_id_arg, "--force-extra-removable"];
+    args.push(if shim { "--uefi-secure-boot" } else { "--no-uefi-secure-boot" });
+    if !register_nvram {
+        args.push("--no-nvram");
+    }
+    chroot_run(runner, &plan.target_root, "/usr/sbin/grub-install", &args)?;
+    chroot_run(runner, &plan.target_root, "/usr/sbin/grub-mkconfig", &["-o", "/boot/grub/grub.cfg"])
+}
+
+const GRUB_BOOTLOADER_ID: &str = "truthdb";
+
+fn verify_grub_layout(plan: &MountPlan, shim: bool) -> Result<()> {
+    let mut must_exist = vec![
+        plan.target_efi.join(FALLBACK_LOADER),
+        plan.target_efi.join(format!("EFI/{GRUB_BOOTLOADER_ID}/grubx64.efi")),
+        plan.target_root.join("boot/grub/grub.cfg"),
+    ];
+    if shim {
+        // The removable path holds shim as BOOTX64.EFI, chainloading grubx64.efi beside it.
+        must_exist.push(plan.target_efi.join("EFI/BOOT/grubx64.efi"));
+        must_exist.push(plan.target_efi.join(format!("EFI/{GRUB_BOOTLOADER_ID}/shimx64.efi")));
+    }
+
+    for path in must_exist {
+        if !path.exists() {
+            return Err(anyhow!("Missing: {}", path.display()));
+        }
+    }
+
+    Ok(())
+}
+
+fn configure_systemd_networkd_dhcp(plan: &MountPlan, family: IpFamily) -> Result<()> {
     // Configure DHCP on first boot using systemd-networkd so we don't depend on interface names
     // being known (enp*, ens*, eth* ...).
     let network_dir = plan.target_root.join("etc/systemd/network");
@@ -305,9 +1634,7 @@ fn configure_systemd_networkd_dhcp(plan: &MountPlan) -> Result<()> {
         .with_context(|| format!("Failed to create {}", network_dir.display()))?;
 
     let dhcp_network = network_dir.join("20-dhcp.network");
-    // Match common wired + wifi patterns. Keep it conservative; networking is required for bring-up.
-    let contents = "[Match]\nName=en* eth* wl* ww* usb*\n\n[Network]\nDHCP=yes\nIPv6AcceptRA=yes\n";
-    std::fs::write(&dhcp_network, contents)
+    std::fs::write(&dhcp_network, dhcp_network_contents(family))
         .with_context(|| format!("Failed to write {}", dhcp_network.display()))?;
 
     // Enable systemd-networkd (+ optional companions) offline (symlinks under /etc/systemd/system).
@@ -330,6 +1657,52 @@ fn configure_systemd_networkd_dhcp(plan: &MountPlan) -> Result<()> {
     Ok(())
 }
 
+fn configure_network_manager_dhcp(plan: &MountPlan, family: IpFamily) -> Result<()> {
+    let connections_dir = plan.target_root.join("etc/NetworkManager/system-connections");
+    std::fs::create_dir_all(&connections_dir)
+        .with_context(|| format!("Failed to create {}", connections_dir.display()))?;
+
+    let profile = connections_dir.join("truthdb-dhcp.nmconnection");
+    std::fs::write(&profile, nm_connection_contents(family))
+        .with_context(|| format!("Failed to write {}", profile.display()))?;
+    // NetworkManager ignores keyfiles readable by anyone but root.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&profile, std::fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to chmod {}", profile.display()))?;
+    }
+
+    enable_systemd_unit(plan, "NetworkManager.service")
+        .context("Failed to enable NetworkManager")?;
+    enable_systemd_unit_optional(plan, "NetworkManager-wait-online.service")?;
+    Ok(())
+}
+
+fn nm_connection_contents(family: IpFamily) -> String {
+    let (ipv4, ipv6) = match family {
+        IpFamily::V4 => ("auto", "disabled"),
+        IpFamily::V6 => ("disabled", "auto"),
+        IpFamily::Dual => ("auto", "auto"),
+    };
+    // No interface-name, so the profile applies to whichever wired NIC shows up. The UUID is
+    // derived from the file name by NetworkManager.
+    format!(
+        "[connection]\nid=truthdb-dhcp\ntype=ethernet\nautoconnect=true\n\n\
+[ipv4]\nmethod={ipv4}\n\n[ipv6]\nmethod={ipv6}\n"
+    )
+}
+
+fn dhcp_network_contents(family: IpFamily) -> String {
+    let network = match family {
+        IpFamily::V4 => "DHCP=ipv4\nLinkLocalAddressing=no\nIPv6AcceptRA=no\n",
+        IpFamily::V6 => "DHCP=ipv6\nLinkLocalAddressing=ipv6\nIPv6AcceptRA=yes\n",
+        IpFamily::Dual => "DHCP=yes\nIPv6AcceptRA=yes\n",
+    };
+    // Match common wired + wifi patterns. Keep it conservative; networking is required for bring-up.
+    format!("[Match]\nName=en* eth* wl* ww* usb*\n\n[Network]\n{network}")
+}
+
 fn symlink_force(src: &str, dst: &Path) -> Result<()> {
     #[cfg(unix)]
     {
@@ -374,62 +1747,7 @@ fn symlink_force(src: &str, dst: &Path) -> Result<()> {
     }
 }
 
-fn enable_systemd_unit_optional(plan: &MountPlan, unit_name: &str) -> Result<bool> {
-    match find_systemd_unit_file(&plan.target_root, unit_name) {
-        Ok(_) => {
-            enable_systemd_unit(plan, unit_name)?;
-            Ok(true)
-        }
-        Err(e) => {
-            eprintln!("WARN: skipping enable of {unit_name}: {e:#}");
-            Ok(false)
-        }
-    }
-}
-
-fn enable_systemd_unit(plan: &MountPlan, unit_name: &str) -> Result<()> {
-    let unit_src = find_systemd_unit_file(&plan.target_root, unit_name)?;
-
-    let wants_dir = plan.target_root.join("etc/systemd/system/multi-user.target.wants");
-    std::fs::create_dir_all(&wants_dir)
-        .with_context(|| format!("Failed to create {}", wants_dir.display()))?;
-
-    let link_path = wants_dir.join(unit_name);
-    if link_path.exists() {
-        // If it's already enabled, keep it.
-        return Ok(());
-    }
-
-    let link_target = path_in_target_root(&plan.target_root, &unit_src)?;
-
-    #[cfg(unix)]
-    {
-        unix_fs::symlink(&link_target, &link_path).with_context(|| {
-            format!("Failed to create symlink {} -> {}", link_path.display(), link_target)
-        })?;
-    }
-
-    Ok(())
-}
-
-fn find_systemd_unit_file(target_root: &Path, unit_name: &str) -> Result<PathBuf> {
-    // Debian typically uses /lib/systemd/system; some distros use /usr/lib/systemd/system.
-    let candidates = [
-        target_root.join("lib/systemd/system").join(unit_name),
-        target_root.join("usr/lib/systemd/system").join(unit_name),
-    ];
-    for candidate in candidates {
-        if candidate.exists() {
-            return Ok(candidate);
-        }
-    }
-    Err(anyhow!(
-        "Missing systemd unit file '{}' in target root (expected under /lib/systemd/system or /usr/lib/systemd/system). Ensure payload includes systemd.",
-        unit_name
-    ))
-}
-
-fn path_in_target_root(target_root: &Path, absolute_in_target: &Path) -> Result<String> {
+pub(crate) fn path_in_target_root(target_root: &Path, absolute_in_target: &Path) -> Result<String> {
     let rel = absolute_in_target.strip_prefix(target_root).with_context(|| {
         format!(
             "Path {} is not under target root {}",
@@ -499,15 +1817,32 @@ fn ensure_systemd_pid1(plan: &MountPlan) -> Result<()> {
     Ok(())
 }
 
-fn verify_esp_layout(esp_mount: &Path) -> Result<()> {
-    let must_exist = [
-        esp_mount.join("EFI/BOOT/BOOTX64.EFI"),
+/// UEFI removable-media path, booted by firmware that has no NVRAM entry for the disk.
+const FALLBACK_LOADER: &str = "EFI/BOOT/BOOTX64.EFI";
+
+/// The ESP must carry the fallback loader, whichever bootloader was installed.
+pub fn check_fallback_loader(esp_mount: &Path) -> Result<()> {
+    let loader = esp_mount.join(FALLBACK_LOADER);
+    if !loader.is_file() {
+        return Err(anyhow!("Missing fallback loader {}", loader.display()));
+    }
+    Ok(())
+}
+
+fn verify_esp_layout(esp_mount: &Path, shim: bool) -> Result<()> {
+    let mut must_exist = vec![
+        esp_mount.join(FALLBACK_LOADER),
         esp_mount.join("EFI/systemd/systemd-bootx64.efi"),
         esp_mount.join("loader/loader.conf"),
         esp_mount.join("loader/entries/debian.conf"),
         esp_mount.join("EFI/debian/vmlinuz"),
         esp_mount.join("EFI/debian/initrd.img"),
     ];
+    if shim {
+        // shim chainloads grubx64.efi from its own directory; MokManager handles key enrolment.
+        must_exist.push(esp_mount.join("EFI/BOOT/grubx64.efi"));
+        must_exist.push(esp_mount.join("EFI/BOOT/mmx64.efi"));
+    }
 
     for path in must_exist {
         if !path.exists() {
@@ -518,135 +1853,271 @@ fn verify_esp_layout(esp_mount: &Path) -> Result<()> {
     Ok(())
 }
 
-pub fn sync_disks() -> Result<()> {
-    // We only have busybox in initramfs by default; call its applet directly.
-    run("/bin/busybox", &["sync"]).context("busybox sync failed")
-}
-
-pub fn unmount_target(plan: &MountPlan) -> Result<()> {
-    // Unmount ESP first (it is nested under the root mount), then root.
-    run("umount", &[&plan.target_efi.display().to_string()])
-        .with_context(|| format!("Failed to umount {}", plan.target_efi.display()))?;
-
-    run("umount", &[&plan.target_root.display().to_string()])
-        .with_context(|| format!("Failed to umount {}", plan.target_root.display()))?;
-
-    Ok(())
-}
+/// Read-only sanity check that the target will boot, run while it is still mounted.
+///
+/// Checks that systemd is present and executable, that `/etc/fstab` mounts the partitions by
+/// their current UUIDs, and that the bootloader files are in place. Every problem found is
+/// listed in the error.
+pub fn final_verify(
+    plan: &MountPlan,
+    bootloader: Bootloader,
+    root_uuid: &str,
+    esp_uuid: &str,
+) -> Result<()> {
+    let mut problems = Vec::new();
 
-fn register_uefi_boot_entry(disk_dev: &Path) -> Result<()> {
-    // Only meaningful when booted in UEFI mode.
-    if !Path::new("/sys/firmware/efi").exists() {
-        return Ok(());
+    let init_ok = ["lib/systemd/systemd", "usr/lib/systemd/systemd"]
+        .iter()
+        .any(|rel| is_executable(&plan.target_root.join(rel)));
+    if !init_ok {
+        problems.push("systemd (/lib/systemd/systemd) is missing or not executable".to_string());
     }
 
-    // Ensure efivarfs is mounted; efibootmgr needs it.
-    let efivars = Path::new("/sys/firmware/efi/efivars");
-    std::fs::create_dir_all(efivars)
-        .with_context(|| format!("Failed to create {}", efivars.display()))?;
-
-    // Ignore mount errors if it is already mounted; if it's not mounted, efibootmgr will fail and
-    // we'll surface that error.
-    let _ = run("mount", &["-t", "efivarfs", "efivarfs", &efivars.display().to_string()]);
-
-    // ESP is always partition 1 in our GPT layout.
-    // Note: efibootmgr expects the EFI path with backslashes.
-    let efi_loader = r"\\EFI\\systemd\\systemd-bootx64.efi";
-    let disk = disk_dev.display().to_string();
+    let fstab_path = plan.target_root.join("etc/fstab");
+    match std::fs::read_to_string(&fstab_path) {
+        Ok(fstab) => {
+            for (what, uuid) in [("root", root_uuid), ("ESP", esp_uuid)] {
+                let mounted_by_uuid = fstab
+                    .lines()
+                    .any(|line| line.split_whitespace().next() == Some(&format!("UUID={uuid}")));
+                if !mounted_by_uuid {
+                    problems.push(format!("/etc/fstab has no entry for the {what} UUID {uuid}"));
+                }
+            }
+        }
+        Err(e) => problems.push(format!("Cannot read {}: {e}", fstab_path.display())),
+    }
 
-    let output = command("efibootmgr")
-        .args(["-c", "-d", &disk, "-p", "1", "-L", "Debian (TruthDB)", "-l", efi_loader])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .context("Failed to execute efibootmgr")?;
+    let boot_check = match bootloader {
+        Bootloader::SystemdBoot => verify_esp_layout(&plan.target_efi, false)
+            .and_then(|()| verify_boot_entry_files(&plan.target_efi)),
+        Bootloader::Grub => verify_grub_layout(plan, false),
+    };
+    if let Err(e) = boot_check {
+        problems.push(format!("{e:#}"));
+    }
 
-    if output.status.success() {
+    if problems.is_empty() {
         return Ok(());
     }
+    Err(anyhow!("Installed system failed verification:\n- {}", problems.join("\n- ")))
+}
 
-    let stderr = String::from_utf8_lossy(&output.stderr);
-
-    // Common in some VM configs (or when efivarfs isn't available): we can't write NVRAM vars.
-    // This should not be fatal as long as the ESP fallback loader exists.
-    if stderr.contains("EFI variables are not supported")
-        || stderr.contains("Could not prepare boot variable")
-        || stderr.contains("Operation not permitted")
-        || stderr.contains("Read-only file system")
-    {
-        return Ok(());
+/// Which bootloader an existing install uses, judged by what is on its ESP.
+pub fn detect_bootloader(plan: &MountPlan) -> Option<Bootloader> {
+    if plan.target_efi.join("loader/entries/debian.conf").is_file() {
+        Some(Bootloader::SystemdBoot)
+    } else if plan.target_efi.join(format!("EFI/{GRUB_BOOTLOADER_ID}/grubx64.efi")).is_file() {
+        Some(Bootloader::Grub)
+    } else {
+        None
     }
+}
 
-    Err(anyhow!(
-        "efibootmgr failed: stdout='{}' stderr='{}'",
-        String::from_utf8_lossy(&output.stdout),
-        stderr
-    ))
+/// The systemd-boot entry's `linux`/`initrd` paths must exist on the ESP; a stale entry boots
+/// into a firmware error rather than the system.
+fn verify_boot_entry_files(esp_mount: &Path) -> Result<()> {
+    let entry_path = esp_mount.join("loader/entries/debian.conf");
+    let entry = std::fs::read_to_string(&entry_path)
+        .with_context(|| format!("Failed to read {}", entry_path.display()))?;
+    for line in entry.lines() {
+        let mut parts = line.split_whitespace();
+        let (Some(key @ ("linux" | "initrd")), Some(path)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        let file = esp_mount.join(path.trim_start_matches('/'));
+        if !file.is_file() {
+            return Err(anyhow!("Boot entry {key} {path} does not exist on the ESP"));
+        }
+    }
+    Ok(())
 }
 
-fn target_user_exists(target_root: &Path, username: &str) -> Result<bool> {
-    let passwd_path = target_root.join("etc/passwd");
-    let contents = std::fs::read_to_string(&passwd_path)
-        .with_context(|| format!("Failed to read {}", passwd_path.display()))?;
-    Ok(contents.lines().any(|line| line.starts_with(&format!("{username}:"))))
+fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path)
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+pub fn sync_disks() -> Result<()> {
+    // We only have busybox in initramfs by default; call its applet directly.
+    run("/bin/busybox", &["sync"]).context("busybox sync failed")
+}
+
+const UEFI_BOOT_LABEL: &str = "Debian (TruthDB)";
+
+fn register_uefi_boot_entry(disk_dev: &Path, efi_loader: &str) -> Result<()> {
+    // Only meaningful when booted in UEFI mode.
+    if !efi::booted_uefi() {
+        return Ok(());
+    }
+
+    // Ensure efivarfs is mounted; efibootmgr needs it. If that fails, efibootmgr will too and
+    // we'll surface that error.
+    efi::mount_efivarfs();
+
+    // Drop entries left behind by earlier installs so reinstalling doesn't pile up duplicates.
+    if let Ok(listing) = efibootmgr(&[]) {
+        for entry in efi::parse_efibootmgr(&listing).with_label(UEFI_BOOT_LABEL) {
+            if let Err(e) = efibootmgr(&["-b", &entry.num, "-B"]) {
+                eprintln!("WARN: could not delete stale boot entry Boot{}: {e:#}", entry.num);
+            }
+        }
+    }
+
+    // ESP is always partition 1 in our GPT layout.
+    let disk = disk_dev.display().to_string();
+
+    let listing = match efibootmgr(&[
+        "-c",
+        "-d",
+        &disk,
+        "-p",
+        "1",
+        "-L",
+        UEFI_BOOT_LABEL,
+        "-l",
+        efi_loader,
+    ]) {
+        Ok(listing) => listing,
+        Err(e) => {
+            let message = format!("{e:#}");
+            // Common in some VM configs (or when efivarfs isn't available): we can't write NVRAM
+            // vars. This should not be fatal as long as the ESP fallback loader exists.
+            if message.contains("EFI variables are not supported")
+                || message.contains("Could not prepare boot variable")
+                || message.contains("Operation not permitted")
+                || message.contains("Read-only file system")
+            {
+                return Ok(());
+            }
+            return Err(e);
+        }
+    };
+
+    // efibootmgr -c prints the updated listing; make sure the new entry boots first.
+    let parsed = efi::parse_efibootmgr(&listing);
+    if let Some(entry) = parsed.with_label(UEFI_BOOT_LABEL).last() {
+        let order = efi::boot_order_with_first(&parsed.order, &entry.num).join(",");
+        efibootmgr(&["-o", &order]).context("Failed to set BootOrder")?;
+    }
+
+    Ok(())
 }
 
-fn chroot_run(target_root: &Path, program_in_chroot: &str, args: &[&str]) -> Result<()> {
-    let output = command("chroot")
-        .arg(target_root)
-        .arg(program_in_chroot)
+/// Run `efibootmgr`, returning its stdout (the current boot entry listing).
+fn efibootmgr(args: &[&str]) -> Result<String> {
+    let output = command("efibootmgr")
         .args(args)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .output()
-        .with_context(|| format!("Failed to execute chroot {}", program_in_chroot))?;
+        .context("Failed to execute efibootmgr")?;
 
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
     if output.status.success() {
-        return Ok(());
+        return Ok(stdout);
     }
 
     Err(anyhow!(
-        "chroot {} failed: stdout='{}' stderr='{}'",
-        program_in_chroot,
-        String::from_utf8_lossy(&output.stdout),
+        "efibootmgr failed: stdout='{}' stderr='{}'",
+        stdout,
         String::from_utf8_lossy(&output.stderr)
     ))
 }
 
-fn chroot_chpasswd(target_root: &Path, username: &str, password: &str) -> Result<()> {
-    let input = format!("{username}:{password}\n");
+fn target_user_exists(target_root: &Path, username: &str) -> Result<bool> {
+    let passwd_path = target_root.join("etc/passwd");
+    let contents = std::fs::read_to_string(&passwd_path)
+        .with_context(|| format!("Failed to read {}", passwd_path.display()))?;
+    Ok(contents.lines().any(|line| line.starts_with(&format!("{username}:"))))
+}
 
-    let mut cmd = command("chroot");
-    cmd.arg(target_root)
-        .arg("/usr/sbin/chpasswd")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
+/// Look up `(uid, gid, home)` for `username` in the target's `/etc/passwd`.
+fn target_user_ids(target_root: &Path, username: &str) -> Result<(u32, u32, String)> {
+    let passwd_path = target_root.join("etc/passwd");
+    let contents = std::fs::read_to_string(&passwd_path)
+        .with_context(|| format!("Failed to read {}", passwd_path.display()))?;
 
-    let mut child = cmd.spawn().context("Failed to spawn chroot for chpasswd")?;
-    {
-        use std::io::Write;
-        let stdin =
-            child.stdin.as_mut().ok_or_else(|| anyhow!("Failed to open stdin for chpasswd"))?;
-        stdin.write_all(input.as_bytes()).context("Failed to write chpasswd input")?;
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 6 || fields[0] != username {
+            continue;
+        }
+        let uid = fields[2].parse().with_context(|| format!("Bad uid for {username}"))?;
+        let gid = fields[3].parse().with_context(|| format!("Bad gid for {username}"))?;
+        return Ok((uid, gid, fields[5].to_string()));
     }
 
-    let output = child.wait_with_output().context("Failed to wait for chpasswd")?;
-    if output.status.success() {
-        return Ok(());
-    }
+    Err(anyhow!("User {username} not found in {}", passwd_path.display()))
+}
 
-    Err(anyhow!(
-        "chpasswd failed: stdout='{}' stderr='{}'",
-        String::from_utf8_lossy(&output.stdout),
-        String::from_utf8_lossy(&output.stderr)
-    ))
+fn chroot_run(
+    runner: &dyn CommandRunner,
+    target_root: &Path,
+    program_in_chroot: &str,
+    args: &[&str],
+) -> Result<()> {
+    let root = target_root.display().to_string();
+    let mut chroot_args = vec![root.as_str(), program_in_chroot];
+    chroot_args.extend_from_slice(args);
+    runner.run("chroot", &chroot_args).with_context(|| format!("chroot {program_in_chroot} failed"))
+}
+
+fn chroot_chpasswd(
+    runner: &dyn CommandRunner,
+    target_root: &Path,
+    username: &str,
+    password: &Credential,
+    scheme: crypt::PasswordScheme,
+) -> Result<()> {
+    let input = format!("{username}:{}\n", password.crypt_hash(scheme)?);
+    let root = target_root.display().to_string();
+    runner
+        .run_with_input("chroot", &[&root, "/usr/sbin/chpasswd", "-e"], &input)
+        .context("chpasswd failed")
+}
+
+/// Where the initramfs build puts the EFI binaries (relative to the host root).
+const SYSTEMD_BOOT_EFI: &str = "usr/lib/systemd/boot/efi/systemd-bootx64.efi";
+const SHIM_EFI: &str = "usr/lib/shim/shimx64.efi.signed";
+const MOK_MANAGER_EFI: &str = "usr/lib/shim/mmx64.efi.signed";
+
+/// Signed first-stage loader plus MokManager, used when Secure Boot is on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ShimChain {
+    shim: PathBuf,
+    mok_manager: PathBuf,
+}
+
+/// Use the shim chain only when Secure Boot is known to be enabled and both binaries exist.
+fn select_shim_chain(secure_boot: Option<bool>, host_root: &Path) -> Option<ShimChain> {
+    if secure_boot != Some(true) {
+        return None;
+    }
+    let chain =
+        ShimChain { shim: host_root.join(SHIM_EFI), mok_manager: host_root.join(MOK_MANAGER_EFI) };
+    (chain.shim.exists() && chain.mok_manager.exists()).then_some(chain)
 }
 
-fn install_systemd_boot_efi(esp_mount: &Path) -> Result<()> {
+fn install_systemd_boot_efi(
+    esp_mount: &Path,
+    host_root: &Path,
+    shim: Option<&ShimChain>,
+) -> Result<()> {
     // The initramfs build copies /usr/lib/systemd/boot/efi into the initramfs.
-    // For x86_64 UEFI, the loader binary is systemd-bootx64.efi.
-    let src = Path::new("/usr/lib/systemd/boot/efi/systemd-bootx64.efi");
+    // For x86_64 UEFI, the loader binary is systemd-bootx64.efi. Behind shim, prefer the
+    // distro-signed build when it was copied along.
+    let unsigned = host_root.join(SYSTEMD_BOOT_EFI);
+    let signed = host_root.join(format!("{SYSTEMD_BOOT_EFI}.signed"));
+    let src = if shim.is_some() && signed.exists() { signed } else { unsigned };
     if !src.exists() {
         return Err(anyhow!("Missing systemd-boot EFI binary in initramfs: {}", src.display()));
     }
@@ -656,35 +2127,107 @@ fn install_systemd_boot_efi(esp_mount: &Path) -> Result<()> {
     std::fs::create_dir_all(&boot_dir)
         .with_context(|| format!("Failed to create {}", boot_dir.display()))?;
     let fallback_dst = boot_dir.join("BOOTX64.EFI");
-    std::fs::copy(src, &fallback_dst)
-        .with_context(|| format!("Failed to copy systemd-boot to {}", fallback_dst.display()))?;
+    match shim {
+        Some(chain) => {
+            // shim loads "grubx64.efi" from its own directory, whatever that binary really is.
+            copy_preserving(&chain.shim, &fallback_dst)
+                .with_context(|| format!("Failed to copy shim to {}", fallback_dst.display()))?;
+            let second_stage = boot_dir.join("grubx64.efi");
+            copy_preserving(&src, &second_stage).with_context(|| {
+                format!("Failed to copy systemd-boot to {}", second_stage.display())
+            })?;
+            let mok_dst = boot_dir.join("mmx64.efi");
+            copy_preserving(&chain.mok_manager, &mok_dst)
+                .with_context(|| format!("Failed to copy MokManager to {}", mok_dst.display()))?;
+        }
+        None => {
+            copy_preserving(&src, &fallback_dst).with_context(|| {
+                format!("Failed to copy systemd-boot to {}", fallback_dst.display())
+            })?;
+        }
+    }
 
     // Also place it at the conventional systemd location.
     let systemd_dir = esp_mount.join("EFI/systemd");
     std::fs::create_dir_all(&systemd_dir)
         .with_context(|| format!("Failed to create {}", systemd_dir.display()))?;
     let systemd_dst = systemd_dir.join("systemd-bootx64.efi");
-    std::fs::copy(src, &systemd_dst)
+    copy_preserving(&src, &systemd_dst)
         .with_context(|| format!("Failed to copy systemd-boot to {}", systemd_dst.display()))?;
 
     Ok(())
 }
 
+/// Copy a regular file, carrying over its mode, owner, and timestamps.
+///
+/// `std::fs::copy` only keeps permission bits; files owned by service users would end up
+/// root-owned in the target.
+pub fn copy_preserving(src: &Path, dst: &Path) -> Result<()> {
+    let meta =
+        std::fs::metadata(src).with_context(|| format!("Failed to stat {}", src.display()))?;
+
+    std::fs::copy(src, dst)
+        .with_context(|| format!("Failed to copy {} to {}", src.display(), dst.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        // chown first: changing ownership clears setuid/setgid bits, so the mode goes last.
+        unix_fs::chown(dst, Some(meta.uid()), Some(meta.gid()))
+            .with_context(|| format!("Failed to chown {}", dst.display()))?;
+        std::fs::set_permissions(dst, std::fs::Permissions::from_mode(meta.mode() & 0o7777))
+            .with_context(|| format!("Failed to chmod {}", dst.display()))?;
+    }
+
+    let times = std::fs::FileTimes::new()
+        .set_accessed(meta.accessed().context("Failed to read access time")?)
+        .set_modified(meta.modified().context("Failed to read modification time")?);
+    std::fs::File::options()
+        .write(true)
+        .open(dst)
+        .and_then(|f| f.set_times(times))
+        .with_context(|| format!("Failed to set timestamps on {}", dst.display()))?;
+
+    Ok(())
+}
+
 fn write_fstab(root_uuid: &str, esp_uuid: &str, plan: &MountPlan) -> Result<()> {
     let etc_dir = plan.target_root.join("etc");
     std::fs::create_dir_all(&etc_dir)
         .with_context(|| format!("Failed to create {}", etc_dir.display()))?;
 
     let fstab_path = etc_dir.join("fstab");
+    let root_fs = plan.root_fs.fstype();
     let contents = format!(
         "# /etc/fstab: static file system information.\n\
-UUID={root_uuid} / ext4 defaults 0 1\n\
+UUID={root_uuid} / {root_fs} defaults 0 1\n\
 UUID={esp_uuid} /boot/efi vfat umask=0077 0 1\n"
     );
     std::fs::write(&fstab_path, contents)
         .with_context(|| format!("Failed to write {}", fstab_path.display()))
 }
 
+/// Add the swap partition to the target's fstab (written by the bootloader step).
+pub fn add_swap_fstab_entry(plan: &MountPlan, swap: &Path) -> Result<()> {
+    let uuid = blkid_uuid(swap).context("Failed to get swap UUID")?;
+    let fstab_path = plan.target_root.join("etc/fstab");
+    let contents = std::fs::read_to_string(&fstab_path)
+        .with_context(|| format!("Failed to read {}", fstab_path.display()))?;
+    std::fs::write(&fstab_path, fstab_with_swap(&contents, &uuid))
+        .with_context(|| format!("Failed to write {}", fstab_path.display()))
+}
+
+fn fstab_with_swap(contents: &str, uuid: &str) -> String {
+    let mut contents: String = contents
+        .lines()
+        .filter(|line| line.split_whitespace().nth(2) != Some("swap"))
+        .flat_map(|line| [line, "\n"])
+        .collect();
+    contents.push_str(&format!("UUID={uuid} none swap defaults 0 0\n"));
+    contents
+}
+
 fn write_systemd_boot_entry(
     esp_mount: &Path,
     linux_path: &str,
@@ -712,28 +2255,56 @@ options root=UUID={root_uuid} rw init=/lib/systemd/systemd\n"
         .with_context(|| format!("Failed to write {}", entry_path.display()))
 }
 
-fn blkid_uuid(dev: &Path) -> Result<String> {
-    let output = command("blkid")
-        .args(["-s", "UUID", "-o", "value", &dev.display().to_string()])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .with_context(|| format!("Failed to execute blkid for {}", dev.display()))?;
-
-    if !output.status.success() {
-        return Err(anyhow!(
-            "blkid failed for {}: stdout='{}' stderr='{}'",
-            dev.display(),
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr)
-        ));
+/// Cached `blkid` lookups tried before probing the superblock directly.
+const BLKID_ATTEMPTS: u32 = 5;
+const BLKID_RETRY_DELAY: Duration = Duration::from_millis(400);
+
+/// Filesystem UUID of `dev`.
+///
+/// Right after mkfs (especially on fast NVMe) blkid's cache and udev may not have caught up
+/// and report nothing, so the lookup is retried briefly, then the superblock is read directly:
+/// `blkid -p`, and `dumpe2fs -h` for ext filesystems.
+pub fn blkid_uuid(dev: &Path) -> Result<String> {
+    let dev_arg = dev.display().to_string();
+    let mut last_err = anyhow!("blkid returned empty UUID for {}", dev.display());
+    for attempt in 0..BLKID_ATTEMPTS {
+        if attempt > 0 {
+            std::thread::sleep(BLKID_RETRY_DELAY);
+        }
+        match run_output("blkid", &["-s", "UUID", "-o", "value", &dev_arg]) {
+            Ok(stdout) => {
+                if let Some(uuid) = non_empty_line(&stdout) {
+                    return Ok(uuid);
+                }
+            }
+            Err(e) => last_err = e.context(format!("blkid failed for {}", dev.display())),
+        }
     }
 
-    let uuid = String::from_utf8_lossy(&output.stdout).trim().to_string();
-    if uuid.is_empty() {
-        return Err(anyhow!("blkid returned empty UUID for {}", dev.display()));
+    if let Ok(stdout) = run_output("blkid", &["-p", "-s", "UUID", "-o", "value", &dev_arg])
+        && let Some(uuid) = non_empty_line(&stdout)
+    {
+        return Ok(uuid);
     }
-    Ok(uuid)
+    if let Ok(stdout) = run_output("dumpe2fs", &["-h", &dev_arg])
+        && let Some(uuid) = dumpe2fs_uuid(&stdout)
+    {
+        return Ok(uuid);
+    }
+    Err(last_err)
+}
+
+fn non_empty_line(stdout: &str) -> Option<String> {
+    let value = stdout.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// `Filesystem UUID:          0c6a...` from `dumpe2fs -h`.
+fn dumpe2fs_uuid(stdout: &str) -> Option<String> {
+    stdout.lines().find_map(|line| {
+        let uuid = line.strip_prefix("Filesystem UUID:")?.trim();
+        (!uuid.is_empty() && uuid != "<none>").then(|| uuid.to_string())
+    })
 }
 
 fn find_installed_kernel_and_initrd(target_root: &Path) -> Result<(PathBuf, PathBuf)> {
@@ -767,38 +2338,18 @@ fn find_installed_kernel_and_initrd(target_root: &Path) -> Result<(PathBuf, Path
     Ok((kernel, initrd))
 }
 
-fn run(program: &str, args: &[&str]) -> Result<()> {
-    let output = command(program)
-        .args(args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .with_context(|| format!("Failed to execute {program}"))?;
-
-    if output.status.success() {
-        return Ok(());
-    }
-
-    Err(anyhow!(
-        "{program} failed: stdout='{}' stderr='{}'",
-        String::from_utf8_lossy(&output.stdout),
-        String::from_utf8_lossy(&output.stderr)
-    ))
-}
-
-fn command(program: &str) -> Command {
-    let mut cmd = Command::new(program);
-    cmd.env("PATH", DEFAULT_PATH);
-    cmd
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::platform::command::testing::RecordingRunner;
     use std::fs;
 
     fn make_plan(root: &Path) -> MountPlan {
-        MountPlan { target_root: root.to_path_buf(), target_efi: root.join("boot/efi") }
+        MountPlan {
+            target_root: root.to_path_buf(),
+            target_efi: root.join("boot/efi"),
+            root_fs: RootFs::Ext4,
+        }
     }
 
     #[test]
@@ -837,4 +2388,863 @@ mod tests {
         let link = fs::read_link(root.join("sbin/init")).unwrap();
         assert_eq!(link, PathBuf::from("/lib/systemd/systemd"));
     }
+
+    fn make_user_tools(root: &Path) {
+        for tool in ["usr/bin/sudo", "usr/sbin/groupadd", "usr/sbin/useradd", "usr/sbin/chpasswd"] {
+            fs::create_dir_all(root.join(tool).parent().unwrap()).unwrap();
+            fs::write(root.join(tool), "").unwrap();
+        }
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/passwd"), "root:x:0:0:root:/root:/bin/bash\n").unwrap();
+    }
+
+    fn root_commands(root_access: RootAccess) -> Vec<String> {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        make_user_tools(root);
+
+        let runner = RecordingRunner::default();
+        let users = UserSetup { root_access, ..UserSetup::default() };
+        configure_initial_users(&runner, &make_plan(root), &users).unwrap();
+
+        // Skip the common groupadd/useradd/user chpasswd prefix.
+        let prefix = format!("chroot {} ", root.display());
+        runner.commands()[3..].iter().map(|c| c.replace(&prefix, "")).collect()
+    }
+
+    #[test]
+    fn root_access_password_sets_root_password() {
+        assert_eq!(
+            root_commands(RootAccess::Password(Credential::Hashed("$6$salt$hash".to_string()))),
+            vec!["/usr/sbin/chpasswd -e < root:$6$salt$hash"]
+        );
+    }
+
+    #[test]
+    fn plaintext_passwords_are_hashed_before_chpasswd() {
+        let commands = root_commands(RootAccess::Password(Credential::Plain("s3cret".to_string())));
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].starts_with("/usr/sbin/chpasswd -e < root:$6$"));
+        assert!(!commands[0].contains("s3cret"));
+    }
+
+    #[test]
+    fn root_access_locked_locks_root() {
+        assert_eq!(
+            root_commands(RootAccess::Locked),
+            vec!["/usr/sbin/usermod -L root", "/usr/sbin/usermod -s /usr/sbin/nologin root"]
+        );
+    }
+
+    #[test]
+    fn root_access_unchanged_leaves_root_alone() {
+        assert!(root_commands(RootAccess::Unchanged).is_empty());
+    }
+
+    #[test]
+    fn passwordless_sudo_writes_validated_drop_in() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        make_user_tools(root);
+        fs::write(root.join("usr/sbin/visudo"), "").unwrap();
+
+        let runner = RecordingRunner::default();
+        let users = UserSetup { passwordless_sudo: true, ..UserSetup::default() };
+        configure_initial_users(&runner, &make_plan(root), &users).unwrap();
+
+        let drop_in = root.join("etc/sudoers.d/10-truthdb");
+        assert_eq!(fs::read_to_string(&drop_in).unwrap(), "truthdb ALL=(ALL) NOPASSWD:ALL\n");
+        assert_eq!(fs::metadata(&drop_in).unwrap().permissions().mode() & 0o777, 0o440);
+        assert_eq!(
+            runner.commands().last().unwrap(),
+            &format!("chroot {} /usr/sbin/visudo -c -f /etc/sudoers.d/10-truthdb", root.display())
+        );
+    }
+
+    #[test]
+    fn sudo_drop_in_is_opt_in() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        make_user_tools(root);
+
+        let runner = RecordingRunner::default();
+        configure_initial_users(&runner, &make_plan(root), &UserSetup::default()).unwrap();
+        assert!(!root.join("etc/sudoers.d").exists());
+    }
+
+    #[test]
+    fn copy_preserving_keeps_mode_and_mtime() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let src = temp.path().join("src");
+        let dst = temp.path().join("dst");
+        fs::write(&src, "payload").unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o750)).unwrap();
+        let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        fs::File::options().write(true).open(&src).unwrap().set_modified(mtime).unwrap();
+
+        copy_preserving(&src, &dst).unwrap();
+
+        let meta = fs::metadata(&dst).unwrap();
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "payload");
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o750);
+        assert_eq!(meta.modified().unwrap(), mtime);
+    }
+
+    #[test]
+    fn dhcp_network_per_ip_family() {
+        let header = "[Match]\nName=en* eth* wl* ww* usb*\n\n[Network]\n";
+        assert_eq!(
+            dhcp_network_contents(IpFamily::Dual),
+            format!("{header}DHCP=yes\nIPv6AcceptRA=yes\n")
+        );
+        assert_eq!(
+            dhcp_network_contents(IpFamily::V4),
+            format!("{header}DHCP=ipv4\nLinkLocalAddressing=no\nIPv6AcceptRA=no\n")
+        );
+        assert_eq!(
+            dhcp_network_contents(IpFamily::V6),
+            format!("{header}DHCP=ipv6\nLinkLocalAddressing=ipv6\nIPv6AcceptRA=yes\n")
+        );
+    }
+
+    #[test]
+    fn nm_profile_per_ip_family() {
+        assert_eq!(
+            nm_connection_contents(IpFamily::V4),
+            "[connection]\nid=truthdb-dhcp\ntype=ethernet\nautoconnect=true\n\n\
+[ipv4]\nmethod=auto\n\n[ipv6]\nmethod=disabled\n"
+        );
+        assert!(nm_connection_contents(IpFamily::Dual).ends_with("[ipv6]\nmethod=auto\n"));
+    }
+
+    #[test]
+    fn network_backend_selection() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let plan = make_plan(root);
+        let units = root.join("lib/systemd/system");
+        fs::create_dir_all(&units).unwrap();
+        fs::write(units.join("systemd-networkd.service"), "").unwrap();
+
+        assert_eq!(select_network_backend(&plan, None).unwrap(), NetworkBackend::Networkd);
+        assert!(select_network_backend(&plan, Some(NetworkBackend::NetworkManager)).is_err());
+
+        fs::write(units.join("NetworkManager.service"), "").unwrap();
+        assert_eq!(select_network_backend(&plan, None).unwrap(), NetworkBackend::NetworkManager);
+        assert_eq!(
+            select_network_backend(&plan, Some(NetworkBackend::Networkd)).unwrap(),
+            NetworkBackend::Networkd
+        );
+
+        configure_network_manager_dhcp(&plan, IpFamily::Dual).unwrap();
+        let profile = root.join("etc/NetworkManager/system-connections/truthdb-dhcp.nmconnection");
+        assert_eq!(fs::metadata(profile).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(
+            fs::symlink_metadata(
+                root.join("etc/systemd/system/multi-user.target.wants/NetworkManager.service")
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn timezone_links_localtime() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("usr/share/zoneinfo/Europe")).unwrap();
+        fs::write(root.join("usr/share/zoneinfo/Europe/Oslo"), "TZif").unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/localtime"), "old").unwrap();
+        let plan = make_plan(root);
+
+        configure_timezone(&plan, "Europe/Oslo").unwrap();
+        assert_eq!(
+            fs::read_link(root.join("etc/localtime")).unwrap(),
+            PathBuf::from("/usr/share/zoneinfo/Europe/Oslo")
+        );
+        assert_eq!(fs::read_to_string(root.join("etc/timezone")).unwrap(), "Europe/Oslo\n");
+
+        assert!(configure_timezone(&plan, "Mars/Olympus").is_err());
+    }
+
+    #[test]
+    fn locale_gen_entry_is_uncommented_or_appended() {
+        let contents = "# en_GB.UTF-8 UTF-8\n# en_US.UTF-8 UTF-8\n";
+        assert_eq!(
+            enable_locale_gen_entry(contents, "en_US.UTF-8"),
+            "# en_GB.UTF-8 UTF-8\nen_US.UTF-8 UTF-8\n"
+        );
+        assert_eq!(enable_locale_gen_entry("", "nb_NO.UTF-8"), "nb_NO.UTF-8 UTF-8\n");
+    }
+
+    #[test]
+    fn authorized_key_is_private_to_user() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        // Use our own ids so the chown works without privileges.
+        let meta = fs::metadata(root).unwrap();
+        use std::os::unix::fs::MetadataExt;
+        fs::write(
+            root.join("etc/passwd"),
+            format!("truthdb:x:{}:{}::/home/truthdb:/bin/bash\n", meta.uid(), meta.gid()),
+        )
+        .unwrap();
+
+        install_authorized_key(&make_plan(root), "truthdb", "ssh-ed25519 AAAA test\n").unwrap();
+
+        let keys = root.join("home/truthdb/.ssh/authorized_keys");
+        assert_eq!(fs::read_to_string(&keys).unwrap(), "ssh-ed25519 AAAA test\n");
+        assert_eq!(fs::metadata(&keys).unwrap().permissions().mode() & 0o777, 0o600);
+        let ssh_dir = root.join("home/truthdb/.ssh");
+        assert_eq!(fs::metadata(ssh_dir).unwrap().permissions().mode() & 0o777, 0o700);
+    }
+
+    #[test]
+    fn mount_guard_with_no_mounts_is_a_no_op() {
+        let mut guard = MountGuard::new();
+        guard.unmount_all().unwrap();
+    }
+
+    fn write_host_efi_binaries(host: &Path) {
+        for (rel, contents) in
+            [(SYSTEMD_BOOT_EFI, "sd-boot"), (SHIM_EFI, "shim"), (MOK_MANAGER_EFI, "mok")]
+        {
+            let path = host.join(rel);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn shim_chain_only_used_with_secure_boot() {
+        let host = tempfile::tempdir().unwrap();
+        assert_eq!(select_shim_chain(Some(true), host.path()), None);
+
+        write_host_efi_binaries(host.path());
+        assert_eq!(select_shim_chain(None, host.path()), None);
+        assert_eq!(select_shim_chain(Some(false), host.path()), None);
+        assert!(select_shim_chain(Some(true), host.path()).is_some());
+    }
+
+    #[test]
+    fn shim_chainloads_systemd_boot() {
+        let host = tempfile::tempdir().unwrap();
+        let esp = tempfile::tempdir().unwrap();
+        write_host_efi_binaries(host.path());
+        let chain = select_shim_chain(Some(true), host.path()).unwrap();
+
+        install_systemd_boot_efi(esp.path(), host.path(), Some(&chain)).unwrap();
+        let read = |rel: &str| fs::read_to_string(esp.path().join(rel)).unwrap();
+        assert_eq!(read("EFI/BOOT/BOOTX64.EFI"), "shim");
+        assert_eq!(read("EFI/BOOT/grubx64.efi"), "sd-boot");
+        assert_eq!(read("EFI/BOOT/mmx64.efi"), "mok");
+        assert_eq!(read("EFI/systemd/systemd-bootx64.efi"), "sd-boot");
+
+        for rel in ["loader/entries/debian.conf", "EFI/debian/vmlinuz", "EFI/debian/initrd.img"] {
+            let path = esp.path().join(rel);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        fs::write(esp.path().join("loader/loader.conf"), "").unwrap();
+        verify_esp_layout(esp.path(), true).unwrap();
+
+        fs::remove_file(esp.path().join("EFI/BOOT/mmx64.efi")).unwrap();
+        assert!(verify_esp_layout(esp.path(), true).is_err());
+        verify_esp_layout(esp.path(), false).unwrap();
+    }
+
+    #[test]
+    fn local_debs_are_staged_and_installed_with_dpkg() {
+        let target = tempfile::tempdir().unwrap();
+        let debs = tempfile::tempdir().unwrap();
+        fs::write(debs.path().join("b_1.0_amd64.deb"), "b").unwrap();
+        fs::write(debs.path().join("a_2.0_all.deb"), "a").unwrap();
+        fs::write(debs.path().join("README"), "").unwrap();
+
+        let runner = RecordingRunner::default();
+        install_local_debs(&runner, &make_plan(target.path()), debs.path()).unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec![format!(
+                "chroot {} /usr/bin/dpkg -i /{LOCAL_DEBS_DIR}/a_2.0_all.deb /{LOCAL_DEBS_DIR}/b_1.0_amd64.deb",
+                target.path().display()
+            )]
+        );
+        assert!(!target.path().join(LOCAL_DEBS_DIR).exists());
+
+        let empty = tempfile::tempdir().unwrap();
+        assert!(install_local_debs(&runner, &make_plan(target.path()), empty.path()).is_err());
+    }
+
+    #[test]
+    fn initramfs_tool_is_detected() {
+        let target = tempfile::tempdir().unwrap();
+        let plan = make_plan(target.path());
+        let runner = RecordingRunner::default();
+        assert!(regenerate_initramfs(&runner, &plan).is_err());
+
+        fs::create_dir_all(target.path().join("usr/bin")).unwrap();
+        fs::write(target.path().join("usr/bin/dracut"), "").unwrap();
+        regenerate_initramfs(&runner, &plan).unwrap();
+
+        fs::create_dir_all(target.path().join("usr/sbin")).unwrap();
+        fs::write(target.path().join("usr/sbin/update-initramfs"), "").unwrap();
+        regenerate_initramfs(&runner, &plan).unwrap();
+
+        let root = target.path().display();
+        assert_eq!(
+            runner.commands(),
+            vec![
+                format!("chroot {root} /usr/bin/dracut --force --regenerate-all"),
+                format!("chroot {root} /usr/sbin/update-initramfs -u -k all"),
+            ]
+        );
+    }
+
+    #[test]
+    fn final_verify_reports_every_problem() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let plan = make_plan(root);
+
+        let err = final_verify(&plan, Bootloader::Grub, "r-uuid", "e-uuid").unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("systemd"));
+        assert!(message.contains("fstab"));
+        assert!(message.contains("EFI/BOOT/BOOTX64.EFI"));
+
+        fs::create_dir_all(root.join("lib/systemd")).unwrap();
+        fs::write(root.join("lib/systemd/systemd"), "").unwrap();
+        fs::set_permissions(root.join("lib/systemd/systemd"), fs::Permissions::from_mode(0o755))
+            .unwrap();
+        write_fstab("r-uuid", "e-uuid", &plan).unwrap();
+        fs::create_dir_all(plan.target_efi.join("EFI/truthdb")).unwrap();
+        fs::write(plan.target_efi.join("EFI/truthdb/grubx64.efi"), "").unwrap();
+        fs::create_dir_all(plan.target_efi.join("EFI/BOOT")).unwrap();
+        fs::write(plan.target_efi.join("EFI/BOOT/BOOTX64.EFI"), "").unwrap();
+        fs::create_dir_all(root.join("boot/grub")).unwrap();
+        fs::write(root.join("boot/grub/grub.cfg"), "").unwrap();
+        final_verify(&plan, Bootloader::Grub, "r-uuid", "e-uuid").unwrap();
+
+        // A stale UUID (e.g. the disk was reformatted after fstab was written) is caught.
+        let err = final_verify(&plan, Bootloader::Grub, "new-uuid", "e-uuid").unwrap_err();
+        assert!(err.to_string().contains("root UUID new-uuid"));
+    }
+
+    #[test]
+    fn swap_partition_is_formatted_and_listed_in_fstab() {
+        let runner = RecordingRunner::default();
+        format_swap(&runner, Path::new("/dev/vda3")).unwrap();
+        assert_eq!(runner.commands(), vec!["mkswap -f -L swap /dev/vda3"]);
+
+        let fstab = "UUID=r / ext4 defaults 0 1\n/swapfile none swap sw 0 0\n";
+        assert_eq!(
+            fstab_with_swap(fstab, "5555"),
+            "UUID=r / ext4 defaults 0 1\nUUID=5555 none swap defaults 0 0\n"
+        );
+    }
+
+    #[test]
+    fn boot_entry_must_reference_existing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan = make_plan(dir.path());
+        assert_eq!(detect_bootloader(&plan), None);
+
+        write_systemd_boot_entry(
+            &plan.target_efi,
+            "/EFI/debian/vmlinuz",
+            "/EFI/debian/initrd.img",
+            "r",
+        )
+        .unwrap();
+        assert_eq!(detect_bootloader(&plan), Some(Bootloader::SystemdBoot));
+        let err = verify_boot_entry_files(&plan.target_efi).unwrap_err();
+        assert!(err.to_string().contains("linux /EFI/debian/vmlinuz"));
+
+        fs::create_dir_all(plan.target_efi.join("EFI/debian")).unwrap();
+        fs::write(plan.target_efi.join("EFI/debian/vmlinuz"), "").unwrap();
+        fs::write(plan.target_efi.join("EFI/debian/initrd.img"), "").unwrap();
+        verify_boot_entry_files(&plan.target_efi).unwrap();
+    }
+
+    #[test]
+    fn leftovers_ignore_lost_found_and_esp_mountpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan = make_plan(dir.path());
+        fs::create_dir_all(dir.path().join("lost+found")).unwrap();
+        fs::create_dir_all(&plan.target_efi).unwrap();
+        fs::write(plan.target_efi.join("stale.efi"), "").unwrap();
+        assert!(leftover_entries(&plan).unwrap().is_empty());
+
+        fs::write(dir.path().join("boot/vmlinuz-6.1.0"), "").unwrap();
+        fs::create_dir_all(dir.path().join("etc")).unwrap();
+        assert_eq!(
+            leftover_entries(&plan).unwrap(),
+            vec![dir.path().join("boot/vmlinuz-6.1.0"), dir.path().join("etc")]
+        );
+    }
+
+    #[test]
+    fn vconsole_conf_lists_keymap_and_font() {
+        assert_eq!(vconsole_conf(Some("ter-v16n"), Some("de")), "KEYMAP=de\nFONT=ter-v16n\n");
+        assert_eq!(vconsole_conf(None, Some("us")), "KEYMAP=us\n");
+
+        let dir = tempfile::tempdir().unwrap();
+        let plan = make_plan(dir.path());
+        configure_console(&plan, None, Some("fr")).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("etc/vconsole.conf")).unwrap(),
+            "KEYMAP=fr\n"
+        );
+
+        // Once the target ships keymaps, unknown names are refused.
+        let qwerty = dir.path().join("usr/share/keymaps/i386/qwerty");
+        fs::create_dir_all(&qwerty).unwrap();
+        fs::write(qwerty.join("us.kmap.gz"), "").unwrap();
+        configure_console(&plan, None, Some("us")).unwrap();
+        assert!(configure_console(&plan, None, Some("xx")).is_err());
+    }
+
+    #[test]
+    fn uuid_fallback_parsing() {
+        assert_eq!(non_empty_line("  \n"), None);
+        assert_eq!(non_empty_line("1234-ABCD\n").as_deref(), Some("1234-ABCD"));
+
+        let header = "\
+dumpe2fs 1.47.0 (5-Feb-2023)
+Filesystem volume name:   root
+Filesystem UUID:          0c6a3f4e-5d2b-4c1a-9f8e-7d6c5b4a3921
+Filesystem magic number:  0xEF53
+";
+        assert_eq!(dumpe2fs_uuid(header).as_deref(), Some("0c6a3f4e-5d2b-4c1a-9f8e-7d6c5b4a3921"));
+        assert_eq!(dumpe2fs_uuid("Filesystem UUID:          <none>\n"), None);
+    }
+
+    #[test]
+    fn format_issues_both_mkfs_commands() {
+        for parallel in [false, true] {
+            let runner = RecordingRunner::default();
+            let options = FormatOptions { parallel, ..FormatOptions::default() };
+            format_partitions(&runner, Path::new("/dev/vda1"), Path::new("/dev/vda2"), &options)
+                .unwrap();
+            let mut commands = runner.commands();
+            commands.sort();
+            assert_eq!(
+                commands,
+                vec!["mkfs.ext4 -F -L root /dev/vda2", "mkfs.vfat -F 32 -n EFI /dev/vda1"]
+            );
+        }
+    }
+
+    #[test]
+    fn each_root_filesystem_has_its_own_mkfs() {
+        let cases = [
+            (RootFs::Ext4, "mkfs.ext4 -F -L root /dev/vda2"),
+            (RootFs::Xfs, "mkfs.xfs -f -L root /dev/vda2"),
+            (RootFs::F2fs, "mkfs.f2fs -f -l root /dev/vda2"),
+        ];
+        for (root_fs, expected) in cases {
+            let runner = RecordingRunner::default();
+            let options = FormatOptions { root_fs, ..FormatOptions::default() };
+            format_partitions(&runner, Path::new("/dev/vda1"), Path::new("/dev/vda2"), &options)
+                .unwrap();
+            assert_eq!(runner.commands()[1], expected);
+        }
+
+        let options = FormatOptions {
+            root_fs: RootFs::F2fs,
+            root_mkfs_opts: vec!["-l".into()],
+            ..FormatOptions::default()
+        };
+        assert!(options.validate().is_err());
+
+        assert_eq!(
+            RootFs::Xfs.mkfs_missing().to_string(),
+            "mkfs.xfs not available in initramfs (a xfs root needs it, from xfsprogs)"
+        );
+    }
+
+    #[test]
+    fn format_errors_from_either_mkfs_propagate() {
+        let esp = Path::new("/dev/vda1");
+        let root = Path::new("/dev/vda2");
+        for parallel in [false, true] {
+            let options = FormatOptions { parallel, ..FormatOptions::default() };
+            let runner = RecordingRunner::failing_on("mkfs.vfat");
+            let err = format_partitions(&runner, esp, root, &options).unwrap_err();
+            assert!(format!("{err:#}").contains("mkfs.vfat failed"));
+            // The root mkfs still ran.
+            assert!(runner.commands().iter().any(|c| c.starts_with("mkfs.ext4")));
+
+            let runner = RecordingRunner::failing_on("mkfs.ext4");
+            let err = format_partitions(&runner, esp, root, &options).unwrap_err();
+            assert!(format!("{err:#}").contains("mkfs.ext4 failed"));
+        }
+    }
+
+    #[test]
+    fn extra_mkfs_options_go_before_the_device() {
+        let options = FormatOptions {
+            esp_mkfs_opts: vec!["-s".into(), "1".into()],
+            root_mkfs_opts: vec!["-O".into(), "^has_journal".into(), "-i".into(), "65536".into()],
+            ..FormatOptions::default()
+        };
+        options.validate().unwrap();
+
+        let runner = RecordingRunner::default();
+        format_partitions(&runner, Path::new("/dev/vda1"), Path::new("/dev/vda2"), &options)
+            .unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "mkfs.vfat -F 32 -n EFI -s 1 /dev/vda1",
+                "mkfs.ext4 -F -L root -O ^has_journal -i 65536 /dev/vda2",
+            ]
+        );
+    }
+
+    #[test]
+    fn esp_label_and_volume_id() {
+        let options = FormatOptions {
+            esp_label: Some("TRUTHDB".into()),
+            esp_volume_id: Some("1234ABCD".into()),
+            esp_mkfs_opts: vec!["-s".into(), "1".into()],
+            ..FormatOptions::default()
+        };
+        options.validate().unwrap();
+
+        let runner = RecordingRunner::default();
+        format_partitions(&runner, Path::new("/dev/vda1"), Path::new("/dev/vda2"), &options)
+            .unwrap();
+        assert_eq!(runner.commands()[0], "mkfs.vfat -F 32 -n TRUTHDB -i 1234ABCD -s 1 /dev/vda1");
+
+        for (label, id) in [
+            (Some("TWELVE_CHARS"), None),
+            (Some(""), None),
+            (Some("A/B"), None),
+            (None, Some("1234ABC")),
+            (None, Some("1234ABCG")),
+        ] {
+            let options = FormatOptions {
+                esp_label: label.map(String::from),
+                esp_volume_id: id.map(String::from),
+                ..FormatOptions::default()
+            };
+            assert!(options.validate().is_err(), "{label:?} {id:?}");
+        }
+    }
+
+    #[test]
+    fn root_label_is_passed_to_mkfs() {
+        let options = FormatOptions { root_label: Some("db07".into()), ..FormatOptions::default() };
+        options.validate().unwrap();
+        let runner = RecordingRunner::default();
+        format_partitions(&runner, Path::new("/dev/vda1"), Path::new("/dev/vda2"), &options)
+            .unwrap();
+        assert_eq!(runner.commands()[1], "mkfs.ext4 -F -L db07 /dev/vda2");
+
+        assert_eq!(RootFs::Xfs.label_for_hostname("warehouse-db-07"), "warehouse-db");
+        assert_eq!(RootFs::Ext4.label_for_hostname("db07"), "db07");
+        for (root_fs, label) in [
+            (RootFs::Ext4, "seventeen-chars-x"),
+            (RootFs::Xfs, "thirteen-char"),
+            (RootFs::Ext4, "a b"),
+        ] {
+            let options = FormatOptions {
+                root_fs,
+                root_label: Some(label.into()),
+                ..FormatOptions::default()
+            };
+            assert!(options.validate().is_err(), "{label}");
+        }
+    }
+
+    #[test]
+    fn extra_mkfs_options_cannot_override_ours() {
+        for (esp, root) in [
+            (vec!["-n".to_string(), "X".to_string()], vec![]),
+            (vec![], vec!["-L".to_string(), "other".to_string()]),
+            (vec![], vec!["/dev/sdb1".to_string()]),
+        ] {
+            let options =
+                FormatOptions { esp_mkfs_opts: esp, root_mkfs_opts: root, ..Default::default() };
+            assert!(options.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn zram_size_parsing() {
+        assert_eq!("50%".parse::<ZramSize>().unwrap(), ZramSize::Percent(50));
+        assert_eq!("512M".parse::<ZramSize>().unwrap(), ZramSize::Mib(512));
+        assert_eq!("4G".parse::<ZramSize>().unwrap(), ZramSize::Mib(4096));
+        for bad in ["", "0%", "250%", "4T", "-1G", "G"] {
+            assert!(bad.parse::<ZramSize>().is_err(), "{bad} should be rejected");
+        }
+    }
+
+    #[test]
+    fn zram_generator_config() {
+        assert_eq!(
+            zram_generator_conf(ZramSize::Percent(50)),
+            "[zram0]\nzram-size = ram * 50 / 100\ncompression-algorithm = zstd\nswap-priority = 100\n"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let plan = make_plan(dir.path());
+        assert!(configure_zram(&plan, ZramSize::Mib(2048)).is_err());
+
+        let generators = dir.path().join("usr/lib/systemd/system-generators");
+        fs::create_dir_all(&generators).unwrap();
+        fs::write(generators.join("zram-generator"), "").unwrap();
+        configure_zram(&plan, ZramSize::Mib(2048)).unwrap();
+        let conf = fs::read_to_string(dir.path().join("etc/systemd/zram-generator.conf")).unwrap();
+        assert!(conf.contains("zram-size = 2048\n"));
+    }
+
+    #[test]
+    fn grub_is_installed_through_chroot() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan = make_plan(dir.path());
+        let runner = RecordingRunner::default();
+
+        assert!(install_grub(&runner, &plan, true, false).is_err());
+        assert!(runner.commands().is_empty());
+
+        fs::create_dir_all(dir.path().join("usr/sbin")).unwrap();
+        fs::write(dir.path().join("usr/sbin/grub-install"), "").unwrap();
+        install_grub(&runner, &plan, true, false).unwrap();
+        install_grub(&runner, &plan, false, true).unwrap();
+        let root = dir.path().display();
+        let grub_install = format!(
+            "chroot {root} /usr/sbin/grub-install --target=x86_64-efi --efi-directory=/boot/efi \
+             --bootloader-id=truthdb --force-extra-removable"
+        );
+        assert_eq!(
+            runner.commands(),
+            vec![
+                format!("{grub_install} --no-uefi-secure-boot"),
+                format!("chroot {root} /usr/sbin/grub-mkconfig -o /boot/grub/grub.cfg"),
+                format!("{grub_install} --uefi-secure-boot --no-nvram"),
+                format!("chroot {root} /usr/sbin/grub-mkconfig -o /boot/grub/grub.cfg"),
+            ]
+        );
+    }
+
+    #[test]
+    fn grub_shim_needs_signed_shim_and_grub() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!grub_shim_available(dir.path()));
+        for rel in GRUB_SHIM_FILES {
+            let path = dir.path().join(rel);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        assert!(grub_shim_available(dir.path()));
+    }
+
+    #[test]
+    fn both_bootloaders_need_the_fallback_loader() {
+        // Image installs write no NVRAM entry, so they only boot from EFI/BOOT/BOOTX64.EFI.
+        let dir = tempfile::tempdir().unwrap();
+        let plan = make_plan(dir.path());
+        for rel in [
+            "EFI/systemd/systemd-bootx64.efi",
+            "loader/loader.conf",
+            "loader/entries/debian.conf",
+            "EFI/debian/vmlinuz",
+            "EFI/debian/initrd.img",
+            "EFI/truthdb/grubx64.efi",
+        ] {
+            let path = plan.target_efi.join(rel);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        fs::create_dir_all(dir.path().join("boot/grub")).unwrap();
+        fs::write(dir.path().join("boot/grub/grub.cfg"), "").unwrap();
+
+        assert!(check_fallback_loader(&plan.target_efi).is_err());
+        assert!(verify_esp_layout(&plan.target_efi, false).is_err());
+        assert!(verify_grub_layout(&plan, false).is_err());
+
+        fs::create_dir_all(plan.target_efi.join("EFI/BOOT")).unwrap();
+        fs::write(plan.target_efi.join(FALLBACK_LOADER), "").unwrap();
+        check_fallback_loader(&plan.target_efi).unwrap();
+        verify_esp_layout(&plan.target_efi, false).unwrap();
+        verify_grub_layout(&plan, false).unwrap();
+    }
+
+    #[test]
+    fn grub_layout_requires_loader_and_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan = make_plan(dir.path());
+        fs::create_dir_all(plan.target_efi.join("EFI/truthdb")).unwrap();
+        fs::write(plan.target_efi.join("EFI/truthdb/grubx64.efi"), "").unwrap();
+        fs::create_dir_all(dir.path().join("boot/grub")).unwrap();
+        fs::write(dir.path().join("boot/grub/grub.cfg"), "").unwrap();
+        // Without the removable-path loader nothing boots once the NVRAM entry is gone.
+        let err = verify_grub_layout(&plan, false).unwrap_err();
+        assert!(err.to_string().contains("EFI/BOOT/BOOTX64.EFI"), "{err:#}");
+        fs::create_dir_all(plan.target_efi.join("EFI/BOOT")).unwrap();
+        fs::write(plan.target_efi.join("EFI/BOOT/BOOTX64.EFI"), "").unwrap();
+        verify_grub_layout(&plan, false).unwrap();
+
+        assert!(verify_grub_layout(&plan, true).is_err());
+        fs::write(plan.target_efi.join("EFI/BOOT/grubx64.efi"), "").unwrap();
+        fs::write(plan.target_efi.join("EFI/truthdb/shimx64.efi"), "").unwrap();
+        verify_grub_layout(&plan, true).unwrap();
+    }
+
+    const QUICK: RetryPolicy =
+        RetryPolicy { attempts: 3, backoff: std::time::Duration::ZERO, timeout: None };
+
+    #[test]
+    fn busy_unmount_resolves_after_a_retry() {
+        let proc = tempfile::tempdir().unwrap();
+        let runner = RecordingRunner::failing_first("umount", 1, "umount: /mnt: target is busy.");
+        let mut guard = MountGuard::new();
+        guard.push("/mnt");
+        guard.push("/mnt/boot/efi");
+        guard.unmount_all_with(&runner, proc.path(), QUICK).unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec!["umount /mnt/boot/efi", "umount /mnt/boot/efi", "umount /mnt"]
+        );
+    }
+
+    #[test]
+    fn stubborn_mount_kills_users_then_unmounts_lazily() {
+        let proc = tempfile::tempdir().unwrap();
+        let fd_dir = proc.path().join("4242/fd");
+        fs::create_dir_all(&fd_dir).unwrap();
+        std::os::unix::fs::symlink("/mnt/var/log/syslog", fd_dir.join("3")).unwrap();
+        fs::create_dir_all(proc.path().join("77/fd")).unwrap();
+        std::os::unix::fs::symlink("/", proc.path().join("77/cwd")).unwrap();
+
+        let runner = RecordingRunner::failing_first("umount", 6, "umount: /mnt: target is busy.");
+        let mut guard = MountGuard::new();
+        guard.push("/mnt");
+        guard.unmount_all_with(&runner, proc.path(), QUICK).unwrap();
+
+        let commands = runner.commands();
+        assert_eq!(commands.iter().filter(|c| *c == "umount /mnt").count(), 6);
+        assert!(commands.contains(&"kill -9 4242".to_string()));
+        assert!(!commands.iter().any(|c| c.contains("77")));
+        assert_eq!(commands.last().unwrap(), "umount -l /mnt");
+    }
+
+    #[test]
+    fn installed_root_is_mounted_without_journal_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let cases = [
+            ("ext4", "mount -o ro,noload -t ext4"),
+            ("xfs", "mount -o ro,norecovery -t xfs"),
+            ("f2fs", "mount -o ro,norecovery -t f2fs"),
+        ];
+        for (fstype, expected) in cases {
+            let plan = MountPlan {
+                root_fs: RootFs::from_fstype(fstype).unwrap(),
+                ..make_plan(dir.path())
+            };
+            fs::create_dir_all(&plan.target_efi).unwrap();
+            let runner = RecordingRunner::default();
+            let mut guard = MountGuard::new();
+            mount_installed_read_only_with(
+                &runner,
+                &mut guard,
+                Path::new("/dev/vda1"),
+                Path::new("/dev/vda2"),
+                &plan,
+            )
+            .unwrap();
+            assert_eq!(
+                runner.commands(),
+                vec![
+                    format!("{expected} /dev/vda2 {}", plan.target_root.display()),
+                    format!("mount -o ro -t vfat /dev/vda1 {}", plan.target_efi.display()),
+                ]
+            );
+            guard.mounts.clear();
+        }
+        assert_eq!(RootFs::from_fstype("btrfs"), None);
+    }
+
+    #[test]
+    fn permanent_unmount_errors_do_not_escalate() {
+        let proc = tempfile::tempdir().unwrap();
+        let runner = RecordingRunner::failing_on("umount");
+        let mut guard = MountGuard::new();
+        guard.push("/mnt");
+        assert!(guard.unmount_all_with(&runner, proc.path(), QUICK).is_err());
+        assert_eq!(runner.commands(), vec!["umount /mnt"]);
+        // Still tracked, so a later unmount_all can try again.
+        assert_eq!(guard.mounts, vec![PathBuf::from("/mnt")]);
+        guard.mounts.clear();
+    }
+
+    #[test]
+    fn read_only_esp_is_detected_from_mountinfo() {
+        let mountinfo = "\
+36 25 8:2 / /mnt rw,relatime - ext4 /dev/sda2 rw
+37 36 8:1 / /mnt/boot/efi ro,relatime - vfat /dev/sda1 ro,fmask=0022
+38 25 8:17 / /mnt/boot/efi2 rw,relatime - vfat /dev/sdb1 rw
+";
+        assert!(mounted_read_only(mountinfo, Path::new("/mnt/boot/efi")));
+        assert!(!mounted_read_only(mountinfo, Path::new("/mnt")));
+        assert!(!mounted_read_only(mountinfo, Path::new("/mnt/boot/efi2")));
+        assert!(!mounted_read_only(mountinfo, Path::new("/boot")));
+
+        // A later (rw) mount over the same point is the one in effect.
+        let remounted =
+            format!("{mountinfo}39 37 8:1 / /mnt/boot/efi rw,relatime - vfat /dev/sda1 rw\n");
+        assert!(!mounted_read_only(&remounted, Path::new("/mnt/boot/efi")));
+    }
+
+    #[test]
+    fn mount_points_decode_mountinfo_escapes() {
+        let mountinfo = "\
+22 1 8:2 / / rw,relatime - ext4 /dev/sda2 rw
+30 22 0:5 / /mnt/dev rw - devtmpfs udev rw
+31 22 8:3 / /mnt/my\\040disk rw - ext4 /dev/sda3 rw
+";
+        assert_eq!(
+            mount_points(mountinfo),
+            vec![PathBuf::from("/"), PathBuf::from("/mnt/dev"), PathBuf::from("/mnt/my disk")]
+        );
+    }
+
+    #[test]
+    fn cancel_without_mounts_is_a_noop() {
+        let mountinfo = "\
+22 1 8:2 / / rw,relatime - ext4 /dev/sda2 rw
+23 22 0:5 / /mnt2 rw - tmpfs tmpfs rw
+";
+        assert!(mounts_under(mountinfo, Path::new("/mnt")).is_empty());
+        cancel_install_with(mountinfo, Path::new("/mnt")).unwrap();
+        cancel_install_with("", Path::new("/mnt")).unwrap();
+    }
+
+    #[test]
+    fn cancel_finds_leftover_target_mounts() {
+        let mountinfo = "\
+22 1 8:2 / / rw,relatime - ext4 /dev/sda2 rw
+40 22 8:18 / /mnt rw - ext4 /dev/sdb2 rw
+41 40 8:17 / /mnt/boot/efi rw - vfat /dev/sdb1 rw
+42 40 0:5 / /mnt/dev rw - devtmpfs udev rw
+";
+        assert_eq!(
+            mounts_under(mountinfo, Path::new("/mnt")),
+            vec![PathBuf::from("/mnt"), PathBuf::from("/mnt/boot/efi"), PathBuf::from("/mnt/dev")]
+        );
+    }
 }
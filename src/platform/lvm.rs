@@ -0,0 +1,181 @@
+//! LVM install layout
+//!
+//! With `--layout lvm` the second partition becomes the single PV of volume group `truthdb`,
+//! and the root filesystem lives on LV `root` spanning all of it. The payload needs `lvm2` so
+//! its initramfs can activate the volume group at boot.
+
+use super::command::{CommandRunner, run_output};
+use super::install::MountPlan;
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const VOLUME_GROUP: &str = "truthdb";
+pub const ROOT_VOLUME: &str = "root";
+/// blkid `TYPE` of an LVM physical volume.
+pub const PV_TYPE: &str = "LVM2_member";
+
+/// How the root filesystem sits on the disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    /// Root filesystem directly on the second partition.
+    #[default]
+    Plain,
+    /// Root on an LV in a VG backed by the second partition.
+    Lvm,
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "plain" => Ok(Layout::Plain),
+            "lvm" => Ok(Layout::Lvm),
+            other => Err(anyhow!("Invalid layout '{other}' (expected plain or lvm)")),
+        }
+    }
+}
+
+/// Device-mapper path of the root LV, which exists without udev.
+pub fn root_volume_path() -> PathBuf {
+    PathBuf::from(format!("/dev/mapper/{VOLUME_GROUP}-{ROOT_VOLUME}"))
+}
+
+/// A PV other than `pv` that already carries our volume group, from `pvs --noheadings -o
+/// pv_name,vg_name` output. Another disk holding a `truthdb` group (say, a second install
+/// plugged in) would make `vgcreate` fail, and `vgchange` would act on that disk instead.
+pub fn foreign_group_member(pvs_output: &str, pv: &Path) -> Option<PathBuf> {
+    pvs_output.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let (name, vg) = (fields.next()?, fields.next()?);
+        (vg == VOLUME_GROUP && Path::new(name) != pv).then(|| PathBuf::from(name))
+    })
+}
+
+/// Refuse to start when the volume group name is already taken by a disk other than `pv`.
+pub fn check_group_name_free(pv: &Path) -> Result<()> {
+    let pvs = run_output("pvs", &["--noheadings", "-o", "pv_name,vg_name"])
+        .context("Could not list LVM physical volumes")?;
+    match foreign_group_member(&pvs, pv) {
+        Some(other) => Err(anyhow!(
+            "Volume group '{VOLUME_GROUP}' already exists on {}; detach that disk or remove \
+             the group (vgremove {VOLUME_GROUP}) before installing with --layout lvm",
+            other.display()
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Make `pv` the only PV of a fresh volume group and create the root LV across all of it.
+///
+/// Stale LVM or filesystem signatures on the partition (from an earlier install) are
+/// overwritten. Returns the root LV's device path.
+pub fn create_root_volume(runner: &dyn CommandRunner, pv: &Path) -> Result<PathBuf> {
+    let pv = pv.display().to_string();
+    runner.run("pvcreate", &["-ff", "-y", &pv]).context("pvcreate failed")?;
+    runner.run("vgcreate", &[VOLUME_GROUP, &pv]).context("vgcreate failed")?;
+    runner
+        .run("lvcreate", &["-y", "-n", ROOT_VOLUME, "-l", "100%FREE", VOLUME_GROUP])
+        .context("lvcreate failed")?;
+    Ok(root_volume_path())
+}
+
+/// Activate the volume group of an existing install with read-only device-mapper tables, so
+/// nothing (metadata or filesystem) can be written through it. Returns the root LV's path.
+pub fn activate_read_only(runner: &dyn CommandRunner) -> Result<PathBuf> {
+    let config = format!("activation {{ read_only_volume_list = [ \"{VOLUME_GROUP}\" ] }}");
+    runner
+        .run("vgchange", &["-a", "y", "--config", &config, VOLUME_GROUP])
+        .context("vgchange failed")?;
+    Ok(root_volume_path())
+}
+
+/// Deactivate our volume group so the disk underneath can be released (retries, image detach).
+pub fn deactivate(runner: &dyn CommandRunner) -> Result<()> {
+    runner.run("vgchange", &["-a", "n", VOLUME_GROUP]).context("vgchange failed")
+}
+
+/// The installed system must be able to activate the VG itself.
+pub fn check_target_support(plan: &MountPlan) -> Result<()> {
+    let has_lvm =
+        ["sbin/lvm", "usr/sbin/lvm"].iter().any(|rel| plan.target_root.join(rel).exists());
+    if !has_lvm {
+        return Err(anyhow!("Payload has no lvm2 (sbin/lvm); it cannot boot from an LVM root"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::command::testing::RecordingRunner;
+
+    #[test]
+    fn root_volume_command_sequence() {
+        let runner = RecordingRunner::default();
+        let root = create_root_volume(&runner, Path::new("/dev/vda2")).unwrap();
+        assert_eq!(root, PathBuf::from("/dev/mapper/truthdb-root"));
+        deactivate(&runner).unwrap();
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "pvcreate -ff -y /dev/vda2",
+                "vgcreate truthdb /dev/vda2",
+                "lvcreate -y -n root -l 100%FREE truthdb",
+                "vgchange -a n truthdb",
+            ]
+        );
+    }
+
+    #[test]
+    fn existing_install_is_activated_read_only() {
+        let runner = RecordingRunner::default();
+        let root = activate_read_only(&runner).unwrap();
+        assert_eq!(root, PathBuf::from("/dev/mapper/truthdb-root"));
+        assert_eq!(
+            runner.commands(),
+            vec![
+                "vgchange -a y --config activation { read_only_volume_list = [ \"truthdb\" ] } \
+                 truthdb"
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_step_stops_the_sequence() {
+        let runner = RecordingRunner::failing_on("vgcreate");
+        let err = create_root_volume(&runner, Path::new("/dev/vda2")).unwrap_err();
+        assert!(format!("{err:#}").contains("vgcreate failed"));
+        assert_eq!(runner.commands().len(), 2);
+    }
+
+    #[test]
+    fn group_on_another_disk_is_foreign() {
+        let pvs = "  /dev/vda2  truthdb\n  /dev/sdb1  data\n  /dev/sdc1\n";
+        assert_eq!(foreign_group_member(pvs, Path::new("/dev/vda2")), None);
+        assert_eq!(
+            foreign_group_member(pvs, Path::new("/dev/nvme0n1p2")),
+            Some(PathBuf::from("/dev/vda2"))
+        );
+        assert_eq!(foreign_group_member("", Path::new("/dev/vda2")), None);
+    }
+
+    #[test]
+    fn target_needs_lvm2() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan = MountPlan { target_root: dir.path().to_path_buf(), ..MountPlan::default() };
+        assert!(check_target_support(&plan).is_err());
+        std::fs::create_dir_all(dir.path().join("usr/sbin")).unwrap();
+        std::fs::write(dir.path().join("usr/sbin/lvm"), "").unwrap();
+        check_target_support(&plan).unwrap();
+    }
+
+    #[test]
+    fn layout_values() {
+        assert_eq!("lvm".parse::<Layout>().unwrap(), Layout::Lvm);
+        assert!("zfs".parse::<Layout>().is_err());
+    }
+}
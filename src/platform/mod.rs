@@ -3,9 +3,20 @@
 //! Handles system operations like reboot, poweroff, etc.
 //! Currently placeholder for future implementation
 
+pub mod command;
+pub mod crypt;
 pub mod disks;
+pub mod dmi;
+pub mod efi;
+pub mod home;
+pub mod image;
 pub mod install;
+pub mod lvm;
+pub mod os_release;
 pub mod partition;
+pub mod payload;
+pub mod resume;
+pub mod systemd;
 
 use anyhow::Result;
 
@@ -0,0 +1,199 @@
+//! os-release branding for the target root
+//!
+//! The payload's `/usr/lib/os-release` is rewritten in place: only the fields set in the
+//! config change, everything else (e.g. `VERSION_CODENAME`, `HOME_URL`) is kept.
+
+use super::install::MountPlan;
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use std::io;
+use std::os::unix::fs as unix_fs;
+
+const OS_RELEASE: &str = "usr/lib/os-release";
+const ETC_OS_RELEASE: &str = "etc/os-release";
+
+/// Fields to override; unset ones keep the payload's value.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OsReleaseConfig {
+    pub name: Option<String>,
+    pub id: Option<String>,
+    pub version: Option<String>,
+    pub pretty_name: Option<String>,
+}
+
+impl OsReleaseConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(id) = &self.id {
+            let valid = !id.is_empty()
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c));
+            if !valid {
+                return Err(anyhow!(
+                    "Invalid os_release id '{id}' (lowercase a-z, 0-9, '.', '_', '-')"
+                ));
+            }
+        }
+        for (key, value) in self.fields() {
+            if value.contains(['\n', '\r']) {
+                return Err(anyhow!("os_release {key} must be a single line"));
+            }
+        }
+        Ok(())
+    }
+
+    fn fields(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("NAME", &self.name),
+            ("ID", &self.id),
+            ("VERSION", &self.version),
+            ("PRETTY_NAME", &self.pretty_name),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.as_deref().map(|value| (key, value)))
+    }
+}
+
+/// Write the merged `/usr/lib/os-release` and make sure `/etc/os-release` shows it: a missing
+/// `/etc/os-release` becomes the usual symlink, a regular file gets the same contents.
+pub fn configure_os_release(plan: &MountPlan, config: &OsReleaseConfig) -> Result<()> {
+    let usr_lib = plan.target_root.join(OS_RELEASE);
+    let etc = plan.target_root.join(ETC_OS_RELEASE);
+
+    let existing = match std::fs::read_to_string(&usr_lib) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            std::fs::read_to_string(&etc).unwrap_or_default()
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", usr_lib.display())),
+    };
+    let merged = merge_os_release(&existing, config);
+
+    if let Some(parent) = usr_lib.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    std::fs::write(&usr_lib, &merged)
+        .with_context(|| format!("Failed to write {}", usr_lib.display()))?;
+
+    match std::fs::symlink_metadata(&etc) {
+        Ok(meta) if meta.file_type().is_symlink() => Ok(()),
+        Ok(_) => std::fs::write(&etc, &merged)
+            .with_context(|| format!("Failed to write {}", etc.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            unix_fs::symlink("../usr/lib/os-release", &etc)
+                .with_context(|| format!("Failed to symlink {}", etc.display()))
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to stat {}", etc.display())),
+    }
+}
+
+/// Replace the overridden keys where they appear and append the ones that don't.
+fn merge_os_release(existing: &str, config: &OsReleaseConfig) -> String {
+    let overrides: Vec<_> = config.fields().collect();
+    let mut out = String::new();
+    let mut written = Vec::new();
+
+    for line in existing.lines() {
+        let key = line.split_once('=').map(|(key, _)| key.trim());
+        match overrides.iter().find(|(name, _)| Some(*name) == key) {
+            Some(&(name, value)) => {
+                out.push_str(&format!("{name}={}\n", quote(value)));
+                written.push(name);
+            }
+            None => {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+    }
+    for (name, value) in overrides {
+        if !written.contains(&name) {
+            out.push_str(&format!("{name}={}\n", quote(value)));
+        }
+    }
+    out
+}
+
+/// os-release values use shell-style double quotes.
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const DEBIAN: &str = "\
+PRETTY_NAME=\"Debian GNU/Linux 12 (bookworm)\"
+NAME=\"Debian GNU/Linux\"
+VERSION_ID=\"12\"
+VERSION=\"12 (bookworm)\"
+VERSION_CODENAME=bookworm
+ID=debian
+HOME_URL=\"https://www.debian.org/\"
+";
+
+    fn branding() -> OsReleaseConfig {
+        OsReleaseConfig {
+            name: Some("TruthDB".into()),
+            id: Some("truthdb".into()),
+            version: None,
+            pretty_name: Some("TruthDB Appliance \"1.0\"".into()),
+        }
+    }
+
+    #[test]
+    fn merge_keeps_unset_fields() {
+        let mut config = branding();
+        config.version = Some("1.0".into());
+        let merged = merge_os_release("NAME=Debian\nVERSION_CODENAME=bookworm\n", &config);
+        assert_eq!(
+            merged,
+            "NAME=\"TruthDB\"\nVERSION_CODENAME=bookworm\nID=\"truthdb\"\nVERSION=\"1.0\"\n\
+             PRETTY_NAME=\"TruthDB Appliance \\\"1.0\\\"\"\n"
+        );
+    }
+
+    #[test]
+    fn writes_usr_lib_and_links_etc() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan = MountPlan { target_root: dir.path().to_path_buf(), ..MountPlan::default() };
+        fs::create_dir_all(dir.path().join("usr/lib")).unwrap();
+        fs::create_dir_all(dir.path().join("etc")).unwrap();
+        fs::write(dir.path().join(OS_RELEASE), DEBIAN).unwrap();
+
+        configure_os_release(&plan, &branding()).unwrap();
+
+        let written = fs::read_to_string(dir.path().join(OS_RELEASE)).unwrap();
+        assert!(written.starts_with("PRETTY_NAME=\"TruthDB Appliance"));
+        assert!(written.contains("\nID=\"truthdb\"\n"));
+        assert!(written.contains("VERSION=\"12 (bookworm)\""));
+        assert!(written.contains("HOME_URL=\"https://www.debian.org/\""));
+        assert_eq!(
+            fs::read_link(dir.path().join(ETC_OS_RELEASE)).unwrap(),
+            std::path::PathBuf::from("../usr/lib/os-release")
+        );
+    }
+
+    #[test]
+    fn id_must_be_lowercase_without_spaces() {
+        assert!(branding().validate().is_ok());
+        for id in ["TruthDB", "truth db", ""] {
+            let config = OsReleaseConfig { id: Some(id.into()), ..Default::default() };
+            assert!(config.validate().is_err(), "{id:?}");
+        }
+        let config = OsReleaseConfig { name: Some("a\nb".into()), ..Default::default() };
+        assert!(config.validate().is_err());
+    }
+}
@@ -1,26 +1,134 @@
+use super::command::{
+    CommandRunner, RetryPolicy, SystemRunner, command, command_exists, run, run_async,
+};
 use anyhow::{Context, Result, anyhow};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::Stdio;
+use std::sync::mpsc::Receiver;
 
-const DEFAULT_PATH: &str = "/bin:/sbin:/usr/bin:/usr/sbin";
-
-const EFI_SYSTEM_PARTITION_GUID: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+pub const EFI_SYSTEM_PARTITION_GUID: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
 const LINUX_FILESYSTEM_GUID: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+const LINUX_LVM_GUID: &str = "E6D6D379-F507-44C2-A23C-238F2A3DF928";
+const LINUX_SWAP_GUID: &str = "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F";
+/// Discoverable Partitions Specification: root partition for x86-64.
+const DPS_ROOT_X86_64_GUID: &str = "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709";
+/// Protective MBR, GPT header and a 128-entry table: 34 sectors, sized for 4 KiB sectors so
+/// 4Kn disks are covered too.
+const GPT_AREA_BYTES: u64 = 34 * 4096;
 
 #[derive(Debug, Clone, Copy)]
 pub struct PartitionPlan {
     pub esp_size_mib: u64,
+    /// Cap the root partition; `None` gives it the rest of the disk. Anything past the cap is
+    /// left unallocated (e.g. for LVM later).
+    pub root_size_mib: Option<u64>,
+    /// Type the second partition as an LVM PV instead of a plain Linux filesystem.
+    pub lvm: bool,
+    /// Type the root partition with the DPS x86-64 root GUID so systemd can find it without
+    /// `root=` (systemd-gpt-auto-generator).
+    pub discoverable_root: bool,
+    /// Add a swap partition of this size between the ESP and root. It is numbered 3 so the ESP
+    /// and root keep their usual device names.
+    pub swap_size_mib: Option<u64>,
 }
 
 impl Default for PartitionPlan {
     fn default() -> Self {
-        Self { esp_size_mib: 512 }
+        Self {
+            esp_size_mib: 512,
+            root_size_mib: None,
+            lvm: false,
+            discoverable_root: false,
+            swap_size_mib: None,
+        }
+    }
+}
+
+/// Device paths of the partitions a [`PartitionPlan`] creates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionDevices {
+    pub esp: PathBuf,
+    pub root: PathBuf,
+    pub swap: Option<PathBuf>,
+}
+
+impl PartitionPlan {
+    /// GPT type GUID of the second partition.
+    pub fn root_type_guid(&self) -> &'static str {
+        if self.lvm {
+            LINUX_LVM_GUID
+        } else if self.discoverable_root {
+            DPS_ROOT_X86_64_GUID
+        } else {
+            LINUX_FILESYSTEM_GUID
+        }
+    }
+
+    /// Check the layout fits a disk of `disk_size_bytes`, including the 1 MiB alignment gap
+    /// in front and room for the backup GPT at the end.
+    pub fn check_fits(&self, disk_size_bytes: u64) -> Result<()> {
+        if self.root_size_mib.is_none() && self.swap_size_mib.is_none() {
+            return Ok(());
+        }
+        // An uncapped root still needs some room after the swap partition.
+        let root_mib = self.root_size_mib.unwrap_or(1);
+        let swap_mib = self.swap_size_mib.unwrap_or(0);
+        let needed_mib = 1 + self.esp_size_mib + swap_mib + root_mib + 1;
+        let disk_mib = disk_size_bytes / (1024 * 1024);
+        if needed_mib > disk_mib {
+            return Err(anyhow!(
+                "Root size {root_mib} MiB and swap size {swap_mib} MiB do not fit: layout needs {needed_mib} MiB, disk has {disk_mib} MiB"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Where root starts, in MiB: after the alignment gap, the ESP and any swap partition.
+    fn root_start_mib(&self) -> u64 {
+        1 + self.esp_size_mib + self.swap_size_mib.unwrap_or(0)
     }
 }
 
-pub fn wipefs_all(disk: &Path) -> Result<()> {
-    run("wipefs", &["-a", &disk.display().to_string()])
-        .with_context(|| format!("wipefs failed for {}", disk.display()))
+/// Start `wipefs -a` on `disk` in the background; it can take a while on large disks.
+pub fn spawn_wipefs_all(disk: &Path) -> Receiver<Result<()>> {
+    run_async("wipefs", &["-a", &disk.display().to_string()])
+}
+
+/// Clear the primary and backup GPT (and any MBR) so stale headers can't confuse firmware.
+/// Uses `sgdisk --zap-all` when available, otherwise zeroes both ends of the disk.
+pub fn zap_gpt(disk: &Path) -> Result<()> {
+    if command_exists("sgdisk") {
+        return run("sgdisk", &["--zap-all", &disk.display().to_string()])
+            .with_context(|| format!("sgdisk --zap-all failed for {}", disk.display()));
+    }
+    zero_gpt_areas(disk)
+}
+
+fn zero_gpt_areas(disk: &Path) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(disk)
+        .with_context(|| format!("Failed to open {} for writing", disk.display()))?;
+    let size = file
+        .seek(SeekFrom::End(0))
+        .with_context(|| format!("Failed to get the size of {}", disk.display()))?;
+    // The two areas must not overlap or run past the end of the device.
+    if size < 2 * GPT_AREA_BYTES {
+        return Err(anyhow!(
+            "{} is too small ({size} bytes) to hold a GPT; not touching it",
+            disk.display()
+        ));
+    }
+
+    let zeros = vec![0u8; GPT_AREA_BYTES as usize];
+    for offset in [0, size - GPT_AREA_BYTES] {
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(&zeros))
+            .with_context(|| format!("Failed to zero {} at byte {offset}", disk.display()))?;
+    }
+    file.sync_all().with_context(|| format!("Failed to sync {}", disk.display()))
 }
 
 pub fn partition_gpt_esp_root(disk: &Path, plan: PartitionPlan) -> Result<()> {
@@ -39,7 +147,19 @@ pub fn partition_gpt_esp_root(disk: &Path, plan: PartitionPlan) -> Result<()> {
 /// Examples:
 /// - `/dev/sda` -> `/dev/sda1`, `/dev/sda2`
 /// - `/dev/nvme0n1` -> `/dev/nvme0n1p1`, `/dev/nvme0n1p2`
+/// - `/dev/cciss/c0d0` -> `/dev/cciss/c0d0p1`, `/dev/cciss/c0d0p2`
 pub fn expected_esp_and_root_partitions(disk: &Path) -> Result<(PathBuf, PathBuf)> {
+    Ok((partition_path(disk, 1)?, partition_path(disk, 2)?))
+}
+
+/// Like [`expected_esp_and_root_partitions`], plus the swap partition when `plan` has one.
+pub fn expected_partitions(disk: &Path, plan: &PartitionPlan) -> Result<PartitionDevices> {
+    let (esp, root) = expected_esp_and_root_partitions(disk)?;
+    let swap = plan.swap_size_mib.map(|_| partition_path(disk, 3)).transpose()?;
+    Ok(PartitionDevices { esp, root, swap })
+}
+
+fn partition_path(disk: &Path, number: u32) -> Result<PathBuf> {
     let name = disk
         .file_name()
         .ok_or_else(|| anyhow!("Invalid disk path: {}", disk.display()))?
@@ -50,9 +170,7 @@ pub fn expected_esp_and_root_partitions(disk: &Path) -> Result<(PathBuf, PathBuf
     let needs_p = name.chars().last().is_some_and(|c| c.is_ascii_digit());
     let sep = if needs_p { "p" } else { "" };
 
-    let esp = PathBuf::from("/dev").join(format!("{name}{sep}1"));
-    let root = PathBuf::from("/dev").join(format!("{name}{sep}2"));
-    Ok((esp, root))
+    Ok(disk.with_file_name(format!("{name}{sep}{number}")))
 }
 
 fn partition_with_sfdisk(disk: &Path, plan: PartitionPlan) -> Result<()> {
@@ -69,7 +187,6 @@ fn partition_with_sfdisk(disk: &Path, plan: PartitionPlan) -> Result<()> {
         .with_context(|| format!("Failed to spawn sfdisk for {}", disk.display()))?;
 
     {
-        use std::io::Write;
         let stdin =
             child.stdin.as_mut().ok_or_else(|| anyhow!("Failed to open stdin for sfdisk"))?;
         stdin.write_all(script.as_bytes()).context("Failed to write sfdisk script")?;
@@ -91,39 +208,52 @@ fn partition_with_parted(disk: &Path, plan: PartitionPlan) -> Result<()> {
     // Use MiB-aligned boundaries. Start at 1MiB, ESP spans [1, 1+esp].
     let esp_start = "1MiB".to_string();
     let esp_end = format!("{}MiB", 1 + plan.esp_size_mib);
-    let root_start = esp_end.clone();
-
-    run(
-        "parted",
-        &[
-            "-s",
-            &disk.display().to_string(),
-            "mklabel",
-            "gpt",
-            "mkpart",
-            "ESP",
-            "fat32",
-            &esp_start,
-            &esp_end,
-            "set",
-            "1",
-            "esp",
-            "on",
-            "mkpart",
-            "root",
-            "ext4",
-            &root_start,
-            "100%",
-        ],
-    )
-    .with_context(|| format!("parted failed for {}", disk.display()))?;
+    let root_start = format!("{}MiB", plan.root_start_mib());
+    let root_end = match plan.root_size_mib {
+        Some(root_mib) => format!("{}MiB", plan.root_start_mib() + root_mib),
+        None => "100%".to_string(),
+    };
+
+    let disk_arg = disk.display().to_string();
+    let mut args = vec![
+        "-s",
+        &disk_arg,
+        "mklabel",
+        "gpt",
+        "mkpart",
+        "ESP",
+        "fat32",
+        &esp_start,
+        &esp_end,
+        "set",
+        "1",
+        "esp",
+        "on",
+        "mkpart",
+        "root",
+        "ext4",
+        &root_start,
+        &root_end,
+    ];
+    if plan.lvm {
+        args.extend(["set", "2", "lvm", "on"]);
+    } else if plan.discoverable_root {
+        // `type` needs parted 3.5 or newer.
+        args.extend(["type", "2", DPS_ROOT_X86_64_GUID]);
+    }
+    // Created last so it becomes partition 3, in the gap left between the ESP and root.
+    if plan.swap_size_mib.is_some() {
+        args.extend(["mkpart", "swap", "linux-swap", &esp_end, &root_start]);
+    }
+    run("parted", &args).with_context(|| format!("parted failed for {}", disk.display()))?;
 
     reread_partition_table(disk)
 }
 
 fn reread_partition_table(disk: &Path) -> Result<()> {
     if command_exists("partprobe") {
-        return run("partprobe", &[&disk.display().to_string()])
+        return SystemRunner
+            .run_with_policy("partprobe", &[&disk.display().to_string()], RetryPolicy::racy())
             .with_context(|| format!("partprobe failed for {}", disk.display()));
     }
 
@@ -134,59 +264,145 @@ fn reread_partition_table(disk: &Path) -> Result<()> {
 
 fn sfdisk_gpt_script(plan: PartitionPlan) -> String {
     // sfdisk script syntax accepts key/value pairs.
-    // We keep it minimal: create ESP (fixed size) then root (remainder).
+    // We keep it minimal: create ESP (fixed size) then root (capped, or the remainder).
+    let root_size = match plan.root_size_mib {
+        Some(root_mib) => format!("size={root_mib}MiB, "),
+        None => String::new(),
+    };
+    let Some(swap_mib) = plan.swap_size_mib else {
+        return format!(
+            "label: gpt\n\nsize={}MiB, type={}\n{}type={}\n",
+            plan.esp_size_mib,
+            EFI_SYSTEM_PARTITION_GUID,
+            root_size,
+            plan.root_type_guid()
+        );
+    };
+    // Swap sits between the ESP and root but is listed last so it becomes partition 3; that
+    // needs explicit starts.
     format!(
-        "label: gpt\n\nsize={}MiB, type={}\ntype={}\n",
-        plan.esp_size_mib, EFI_SYSTEM_PARTITION_GUID, LINUX_FILESYSTEM_GUID
+        "label: gpt\n\nstart=1MiB, size={}MiB, type={}\n\
+         start={}MiB, {}type={}\n\
+         start={}MiB, size={swap_mib}MiB, type={LINUX_SWAP_GUID}\n",
+        plan.esp_size_mib,
+        EFI_SYSTEM_PARTITION_GUID,
+        plan.root_start_mib(),
+        root_size,
+        plan.root_type_guid(),
+        1 + plan.esp_size_mib
     )
 }
 
-fn command_exists(program: &str) -> bool {
-    command(program)
-        .arg("--version")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .map(|s| s.success())
-        .unwrap_or(false)
-}
-
-fn run(program: &str, args: &[&str]) -> Result<()> {
-    let output = command(program)
-        .args(args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .with_context(|| format!("Failed to execute {program}"))?;
-
-    if output.status.success() {
-        return Ok(());
-    }
-
-    Err(anyhow!(
-        "{program} failed: stdout='{}' stderr='{}'",
-        String::from_utf8_lossy(&output.stdout),
-        String::from_utf8_lossy(&output.stderr)
-    ))
-}
-
-fn command(program: &str) -> Command {
-    let mut cmd = Command::new(program);
-    cmd.env("PATH", DEFAULT_PATH);
-    cmd
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn sfdisk_script_contains_expected_types() {
-        let script = sfdisk_gpt_script(PartitionPlan { esp_size_mib: 512 });
+        let script = sfdisk_gpt_script(PartitionPlan::default());
         assert!(script.contains("label: gpt"));
         assert!(script.contains(EFI_SYSTEM_PARTITION_GUID));
         assert!(script.contains(LINUX_FILESYSTEM_GUID));
         assert!(script.contains("size=512MiB"));
+        assert!(script.ends_with(&format!("\ntype={LINUX_FILESYSTEM_GUID}\n")));
+    }
+
+    #[test]
+    fn sfdisk_script_caps_root_when_sized() {
+        let script = sfdisk_gpt_script(PartitionPlan {
+            root_size_mib: Some(20480),
+            ..PartitionPlan::default()
+        });
+        assert!(script.ends_with(&format!("\nsize=20480MiB, type={LINUX_FILESYSTEM_GUID}\n")));
+    }
+
+    #[test]
+    fn sfdisk_script_types_lvm_partition() {
+        let script = sfdisk_gpt_script(PartitionPlan { lvm: true, ..PartitionPlan::default() });
+        assert!(script.ends_with(&format!("\ntype={LINUX_LVM_GUID}\n")));
+        assert!(!script.contains(LINUX_FILESYSTEM_GUID));
+    }
+
+    #[test]
+    fn sfdisk_script_uses_dps_root_type_when_requested() {
+        let script = sfdisk_gpt_script(PartitionPlan {
+            discoverable_root: true,
+            ..PartitionPlan::default()
+        });
+        assert!(script.ends_with(&format!("\ntype={DPS_ROOT_X86_64_GUID}\n")));
+        assert!(!script.contains(LINUX_FILESYSTEM_GUID));
+        assert!(script.contains(EFI_SYSTEM_PARTITION_GUID));
+    }
+
+    #[test]
+    fn sfdisk_script_places_swap_between_esp_and_root() {
+        let script = sfdisk_gpt_script(PartitionPlan {
+            swap_size_mib: Some(4096),
+            root_size_mib: Some(20480),
+            ..PartitionPlan::default()
+        });
+        assert_eq!(
+            script,
+            format!(
+                "label: gpt\n\n\
+                 start=1MiB, size=512MiB, type={EFI_SYSTEM_PARTITION_GUID}\n\
+                 start=4609MiB, size=20480MiB, type={LINUX_FILESYSTEM_GUID}\n\
+                 start=513MiB, size=4096MiB, type={LINUX_SWAP_GUID}\n"
+            )
+        );
+
+        let uncapped = sfdisk_gpt_script(PartitionPlan {
+            swap_size_mib: Some(1024),
+            ..PartitionPlan::default()
+        });
+        assert!(uncapped.contains(&format!("\nstart=1537MiB, type={LINUX_FILESYSTEM_GUID}\n")));
+    }
+
+    #[test]
+    fn swap_partition_is_number_three() {
+        let plan = PartitionPlan { swap_size_mib: Some(4096), ..PartitionPlan::default() };
+        let devices = expected_partitions(Path::new("/dev/nvme0n1"), &plan).unwrap();
+        assert_eq!(devices.esp, PathBuf::from("/dev/nvme0n1p1"));
+        assert_eq!(devices.root, PathBuf::from("/dev/nvme0n1p2"));
+        assert_eq!(devices.swap, Some(PathBuf::from("/dev/nvme0n1p3")));
+        let devices = expected_partitions(Path::new("/dev/sda"), &PartitionPlan::default());
+        assert_eq!(devices.unwrap().swap, None);
+    }
+
+    #[test]
+    fn root_size_must_fit_on_disk() {
+        let gib = 1024 * 1024 * 1024;
+        let unbounded = PartitionPlan::default();
+        assert!(unbounded.check_fits(8 * gib).is_ok());
+
+        let capped = PartitionPlan { root_size_mib: Some(4096), ..PartitionPlan::default() };
+        assert!(capped.check_fits(8 * gib).is_ok());
+        assert!(capped.check_fits(4 * gib).is_err());
+
+        let swap = PartitionPlan { swap_size_mib: Some(16384), ..PartitionPlan::default() };
+        assert!(swap.check_fits(32 * gib).is_ok());
+        assert!(swap.check_fits(16 * gib).is_err());
+    }
+
+    #[test]
+    fn gpt_areas_are_zeroed_at_both_ends() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk = dir.path().join("disk.img");
+        let size = 1024 * 1024;
+        std::fs::write(&disk, vec![0xffu8; size]).unwrap();
+        zero_gpt_areas(&disk).unwrap();
+
+        let contents = std::fs::read(&disk).unwrap();
+        let area = GPT_AREA_BYTES as usize;
+        assert_eq!(contents.len(), size);
+        assert!(contents[..area].iter().all(|&b| b == 0));
+        assert!(contents[size - area..].iter().all(|&b| b == 0));
+        assert!(contents[area..size - area].iter().all(|&b| b == 0xff));
+
+        // Too small to hold both areas: left untouched.
+        std::fs::write(&disk, vec![0xffu8; area]).unwrap();
+        assert!(zero_gpt_areas(&disk).is_err());
+        assert!(std::fs::read(&disk).unwrap().iter().all(|&b| b == 0xff));
     }
 
     #[test]
@@ -202,4 +418,11 @@ mod tests {
         assert_eq!(esp, PathBuf::from("/dev/nvme0n1p1"));
         assert_eq!(root, PathBuf::from("/dev/nvme0n1p2"));
     }
+
+    #[test]
+    fn expected_partition_paths_for_cciss() {
+        let (esp, root) = expected_esp_and_root_partitions(Path::new("/dev/cciss/c0d0")).unwrap();
+        assert_eq!(esp, PathBuf::from("/dev/cciss/c0d0p1"));
+        assert_eq!(root, PathBuf::from("/dev/cciss/c0d0p2"));
+    }
 }
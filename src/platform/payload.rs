@@ -0,0 +1,150 @@
+//! Rootfs payload source
+//!
+//! The payload normally ships on the install media. With `--payload-url` a small netboot ISO
+//! fetches it over HTTP(S) instead: `curl` (or BusyBox `wget`) downloads into a `.part` file in
+//! RAM, which a retried install resumes, and the file is renamed into place once complete.
+
+use super::command::{command_exists, run};
+use anyhow::{Context, Result, anyhow};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+
+pub const LOCAL_PAYLOAD: &str = "/payload/debian-minbase-amd64-bookworm.tar.zst";
+/// Where a downloaded payload is kept (the initramfs root lives in RAM).
+pub const DOWNLOAD_PATH: &str = "/run/truthdb-installer/payload.tar.zst";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Downloader {
+    Curl,
+    Wget,
+}
+
+pub fn validate_url(url: &str) -> Result<()> {
+    let rest = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"));
+    let valid = rest.is_some_and(|rest| {
+        !rest.is_empty() && !rest.starts_with('/') && !rest.contains(char::is_whitespace)
+    });
+    if !valid {
+        return Err(anyhow!("Invalid payload URL '{url}' (expected http:// or https://)"));
+    }
+    Ok(())
+}
+
+pub fn validate_sha256(hex: &str) -> Result<()> {
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("Invalid payload SHA-256 '{hex}' (expected 64 hex digits)"));
+    }
+    Ok(())
+}
+
+/// Start downloading `url` to `dest` on a background thread (see `console::wait_with_spinner`).
+///
+/// Redirects are followed. A `.part` file left by an interrupted attempt is resumed, and `dest`
+/// only appears once the download has completed.
+pub fn fetch_payload(url: &str, dest: &Path) -> Result<Receiver<Result<()>>> {
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let downloader = if command_exists("curl") { Downloader::Curl } else { Downloader::Wget };
+    let part = part_path(dest);
+    let dest = dest.to_path_buf();
+    let args = download_args(downloader, url, &part);
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let program = match downloader {
+            Downloader::Curl => "curl",
+            Downloader::Wget => "wget",
+        };
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let result = run(program, &args).and_then(|()| {
+            std::fs::rename(&part, &dest)
+                .with_context(|| format!("Failed to move {} into place", part.display()))
+        });
+        let _ = tx.send(result);
+    });
+    Ok(rx)
+}
+
+/// Check `path` against the expected SHA-256. A mismatching file is deleted so the next
+/// attempt downloads it again.
+pub fn verify_sha256(path: &Path, expected: &str) -> Result<()> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let actual: String = hasher.finalize().iter().map(|b| format!("{b:02x}")).collect();
+
+    if !actual.eq_ignore_ascii_case(expected) {
+        let _ = std::fs::remove_file(path);
+        return Err(anyhow!("Payload checksum mismatch: expected {expected}, got {actual}"));
+    }
+    Ok(())
+}
+
+fn part_path(dest: &Path) -> PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    dest.with_file_name(name)
+}
+
+fn download_args(downloader: Downloader, url: &str, part: &Path) -> Vec<String> {
+    let part = part.display().to_string();
+    let args: &[&str] = match downloader {
+        // -f: HTTP errors fail instead of saving the error page; -C -: resume from the .part size.
+        Downloader::Curl => &["-fsSL", "--retry", "3", "-C", "-", "-o", &part, url],
+        // BusyBox wget follows redirects on its own.
+        Downloader::Wget => &["-q", "-c", "-O", &part, url],
+    };
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_commands_resume_into_part_file() {
+        let dest = Path::new("/run/truthdb-installer/payload.tar.zst");
+        let part = part_path(dest);
+        assert_eq!(part, PathBuf::from("/run/truthdb-installer/payload.tar.zst.part"));
+        assert_eq!(
+            download_args(Downloader::Curl, "https://example.com/p.tar.zst", &part).join(" "),
+            "-fsSL --retry 3 -C - -o /run/truthdb-installer/payload.tar.zst.part \
+             https://example.com/p.tar.zst"
+        );
+        assert_eq!(
+            download_args(Downloader::Wget, "http://10.0.0.1/p", &part).join(" "),
+            "-q -c -O /run/truthdb-installer/payload.tar.zst.part http://10.0.0.1/p"
+        );
+    }
+
+    #[test]
+    fn checksum_mismatch_removes_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("payload");
+        std::fs::write(&path, "abc").unwrap();
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        verify_sha256(&path, &abc.to_uppercase()).unwrap();
+
+        let err = verify_sha256(&path, &"0".repeat(64)).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn url_and_checksum_validation() {
+        validate_url("https://mirror.example.com/truthdb/payload.tar.zst").unwrap();
+        validate_url("http://10.0.0.1:8080/p").unwrap();
+        for url in ["ftp://host/p", "https://", "https:///p", "http://host/a b", "/payload"] {
+            assert!(validate_url(url).is_err(), "{url}");
+        }
+        validate_sha256(&"a".repeat(64)).unwrap();
+        assert!(validate_sha256("abc").is_err());
+        assert!(validate_sha256(&"g".repeat(64)).is_err());
+    }
+}
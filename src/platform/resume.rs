@@ -0,0 +1,212 @@
+//! Skipping finished steps on retry
+//!
+//! A retried install starts from the top, but partitioning and formatting only need to run
+//! again if the last attempt did not get through them. A step is skipped only when an earlier
+//! attempt in this session completed it *and* the disk still looks the way that step leaves
+//! it (checked with `blkid -p`), so stale on-disk state alone never causes a skip.
+
+use super::command::run_output;
+use super::install::FormatOptions;
+use super::partition::{EFI_SYSTEM_PARTITION_GUID, PartitionPlan};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+/// `PART_ENTRY_SIZE` is always in 512-byte sectors.
+const SECTORS_PER_MIB: u64 = 2048;
+
+/// Install phases, in the order they run. Progress reports and the status endpoint name the
+/// current one; a retry may skip `Partition` and `Format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallStep {
+    Partition,
+    Format,
+    Extract,
+    Configure,
+    Boot,
+    Finalize,
+}
+
+impl InstallStep {
+    pub fn name(self) -> &'static str {
+        match self {
+            InstallStep::Partition => "partition",
+            InstallStep::Format => "format",
+            InstallStep::Extract => "extract",
+            InstallStep::Configure => "configure",
+            InstallStep::Boot => "boot",
+            InstallStep::Finalize => "finalize",
+        }
+    }
+
+    /// Whether the disk already looks the way this step leaves it. Only partitioning and
+    /// formatting can be judged from the disk; the other steps always run again.
+    pub fn is_complete(
+        self,
+        plan: &PartitionPlan,
+        format: &FormatOptions,
+        esp: &Probe,
+        root: &Probe,
+    ) -> bool {
+        match self {
+            InstallStep::Partition => partitioned_as(plan, esp, root),
+            InstallStep::Format => formatted_as(format, esp, root),
+            InstallStep::Extract
+            | InstallStep::Configure
+            | InstallStep::Boot
+            | InstallStep::Finalize => false,
+        }
+    }
+}
+
+impl fmt::Display for InstallStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// Steps finished by earlier attempts on the same disk.
+#[derive(Debug, Default)]
+pub struct Progress {
+    done: Vec<InstallStep>,
+}
+
+impl Progress {
+    pub fn completed(&mut self, step: InstallStep) {
+        if !self.done.contains(&step) {
+            self.done.push(step);
+        }
+    }
+
+    /// Mark `step` as needing to run again, e.g. once the filesystems have been written to.
+    pub fn forget(&mut self, step: InstallStep) {
+        self.done.retain(|&done| done != step);
+    }
+
+    pub fn was_completed(&self, step: InstallStep) -> bool {
+        self.done.contains(&step)
+    }
+}
+
+/// `blkid -p -o export` output for one device.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Probe(HashMap<String, String>);
+
+impl Probe {
+    /// Low-level probe of `dev`; a device blkid can't read yields an empty probe.
+    pub fn of(dev: &Path) -> Probe {
+        run_output("blkid", &["-p", "-o", "export", &dev.display().to_string()])
+            .map(|stdout| Probe::parse(&stdout))
+            .unwrap_or_default()
+    }
+
+    pub fn parse(stdout: &str) -> Probe {
+        Probe(
+            stdout
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    pub fn has_part_type(&self, guid: &str) -> bool {
+        self.get("PART_ENTRY_TYPE").is_some_and(|t| t.eq_ignore_ascii_case(guid))
+    }
+
+    fn part_size_mib(&self) -> Option<u64> {
+        let sectors: u64 = self.get("PART_ENTRY_SIZE")?.parse().ok()?;
+        sectors.is_multiple_of(SECTORS_PER_MIB).then_some(sectors / SECTORS_PER_MIB)
+    }
+
+    fn has_filesystem(&self, fstype: &str, label: &str) -> bool {
+        self.get("TYPE") == Some(fstype) && self.get("LABEL") == Some(label)
+    }
+}
+
+/// Whether the ESP and root partitions already match what `plan` would create.
+fn partitioned_as(plan: &PartitionPlan, esp: &Probe, root: &Probe) -> bool {
+    esp.has_part_type(EFI_SYSTEM_PARTITION_GUID)
+        && esp.part_size_mib() == Some(plan.esp_size_mib)
+        && root.has_part_type(plan.root_type_guid())
+        && plan.root_size_mib.is_none_or(|mib| root.part_size_mib() == Some(mib))
+}
+
+/// Whether both filesystems carry the type and label `format_partitions` gives them.
+fn formatted_as(format: &FormatOptions, esp: &Probe, root: &Probe) -> bool {
+    esp.has_filesystem("vfat", format.esp_label())
+        && root.has_filesystem(format.root_fs.fstype(), format.root_label())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn esp() -> Probe {
+        Probe::parse(
+            "DEVNAME=/dev/vda1\nLABEL=EFI\nTYPE=vfat\nUSAGE=filesystem\n\
+             PART_ENTRY_TYPE=c12a7328-f81f-11d2-ba4b-00a0c93ec93b\nPART_ENTRY_SIZE=1048576\n",
+        )
+    }
+
+    fn root(size_sectors: u64, label: &str) -> Probe {
+        Probe::parse(&format!(
+            "DEVNAME=/dev/vda2\nLABEL={label}\nTYPE=ext4\n\
+             PART_ENTRY_TYPE=0fc63daf-8483-4772-8e79-3d69d8477de4\nPART_ENTRY_SIZE={size_sectors}\n"
+        ))
+    }
+
+    #[test]
+    fn already_partitioned_is_skippable() {
+        let plan = PartitionPlan::default();
+        assert!(InstallStep::Partition.is_complete(
+            &plan,
+            &FormatOptions::default(),
+            &esp(),
+            &root(40_000_000, "root")
+        ));
+
+        let capped = PartitionPlan { root_size_mib: Some(20480), ..plan };
+        assert!(partitioned_as(&capped, &esp(), &root(20480 * 2048, "root")));
+        assert!(!partitioned_as(&capped, &esp(), &root(40_000_000, "root")));
+
+        let lvm = PartitionPlan { lvm: true, ..plan };
+        assert!(!partitioned_as(&lvm, &esp(), &root(40_000_000, "root")));
+        let dps = PartitionPlan { discoverable_root: true, ..plan };
+        assert!(!partitioned_as(&dps, &esp(), &root(40_000_000, "root")));
+        let small_esp = PartitionPlan { esp_size_mib: 256, ..plan };
+        assert!(!partitioned_as(&small_esp, &esp(), &root(40_000_000, "root")));
+        assert!(!partitioned_as(&plan, &Probe::default(), &Probe::default()));
+    }
+
+    #[test]
+    fn already_formatted_is_skippable() {
+        let plan = PartitionPlan::default();
+        let format = FormatOptions::default();
+        assert!(InstallStep::Format.is_complete(&plan, &format, &esp(), &root(1, "root")));
+        assert!(!formatted_as(&format, &esp(), &root(1, "data")));
+        assert!(!formatted_as(&format, &Probe::parse("TYPE=vfat\n"), &root(1, "root")));
+        let relabelled = FormatOptions { esp_label: Some("TRUTHDB".into()), ..format.clone() };
+        assert!(!formatted_as(&relabelled, &esp(), &root(1, "root")));
+        let named = FormatOptions { root_label: Some("db07".into()), ..format };
+        assert!(formatted_as(&named, &esp(), &root(1, "db07")));
+        assert!(!formatted_as(&named, &esp(), &root(1, "root")));
+    }
+
+    #[test]
+    fn progress_tracks_and_forgets_steps() {
+        let mut progress = Progress::default();
+        assert!(!progress.was_completed(InstallStep::Partition));
+        progress.completed(InstallStep::Partition);
+        progress.completed(InstallStep::Format);
+        progress.forget(InstallStep::Format);
+        assert!(progress.was_completed(InstallStep::Partition));
+        assert!(!progress.was_completed(InstallStep::Format));
+    }
+}
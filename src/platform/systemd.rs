@@ -0,0 +1,328 @@
+//! Offline systemd unit management for the target root
+//!
+//! Nothing runs inside the target here: enabling/disabling/masking is done by creating or
+//! removing the same symlinks `systemctl` would.
+
+use super::install::{MountPlan, path_in_target_root};
+use anyhow::{Context, Result, anyhow};
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use std::os::unix::fs as unix_fs;
+
+pub fn enable_systemd_unit_optional(plan: &MountPlan, unit_name: &str) -> Result<bool> {
+    match find_systemd_unit_file(&plan.target_root, unit_name) {
+        Ok(_) => {
+            enable_systemd_unit(plan, unit_name)?;
+            Ok(true)
+        }
+        Err(e) => {
+            eprintln!("WARN: skipping enable of {unit_name}: {e:#}");
+            Ok(false)
+        }
+    }
+}
+
+pub fn enable_systemd_unit(plan: &MountPlan, unit_name: &str) -> Result<()> {
+    let unit_src = find_systemd_unit_file(&plan.target_root, unit_name)?;
+
+    let wants_dir = plan.target_root.join("etc/systemd/system/multi-user.target.wants");
+    std::fs::create_dir_all(&wants_dir)
+        .with_context(|| format!("Failed to create {}", wants_dir.display()))?;
+
+    let link_path = wants_dir.join(unit_name);
+    // Use symlink_metadata: the payload's links are absolute and dangle from the installer's view.
+    if std::fs::symlink_metadata(&link_path).is_ok() {
+        // If it's already enabled, keep it.
+        return Ok(());
+    }
+
+    let link_target = path_in_target_root(&plan.target_root, &unit_src)?;
+
+    #[cfg(unix)]
+    {
+        unix_fs::symlink(&link_target, &link_path).with_context(|| {
+            format!("Failed to create symlink {} -> {}", link_path.display(), link_target)
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Whether the payload ships `unit_name`.
+pub fn has_systemd_unit(target_root: &Path, unit_name: &str) -> bool {
+    find_systemd_unit_file(target_root, unit_name).is_ok()
+}
+
+fn find_systemd_unit_file(target_root: &Path, unit_name: &str) -> Result<PathBuf> {
+    // Debian typically uses /lib/systemd/system; some distros use /usr/lib/systemd/system.
+    let candidates = [
+        target_root.join("lib/systemd/system").join(unit_name),
+        target_root.join("usr/lib/systemd/system").join(unit_name),
+    ];
+    for candidate in candidates {
+        if candidate.exists() {
+            return Ok(candidate);
+        }
+    }
+    Err(anyhow!(
+        "Missing systemd unit file '{}' in target root (expected under /lib/systemd/system or /usr/lib/systemd/system). Ensure payload includes systemd.",
+        unit_name
+    ))
+}
+
+/// Operator-requested unit changes, applied after the installer's own configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnitOverrides {
+    pub enable: Vec<String>,
+    pub disable: Vec<String>,
+    pub mask: Vec<String>,
+}
+
+impl UnitOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.enable.is_empty() && self.disable.is_empty() && self.mask.is_empty()
+    }
+}
+
+/// Apply enables, then disables, then masks (so masking always wins).
+pub fn apply_unit_overrides(plan: &MountPlan, overrides: &UnitOverrides) -> Result<()> {
+    for unit in &overrides.enable {
+        enable_systemd_unit(plan, unit).with_context(|| format!("Failed to enable {unit}"))?;
+    }
+    for unit in &overrides.disable {
+        disable_systemd_unit(plan, unit).with_context(|| format!("Failed to disable {unit}"))?;
+    }
+    for unit in &overrides.mask {
+        mask_systemd_unit(plan, unit).with_context(|| format!("Failed to mask {unit}"))?;
+    }
+    Ok(())
+}
+
+/// Remove every `*.wants`/`*.requires` link to `unit_name` under `/etc/systemd/system`.
+pub fn disable_systemd_unit(plan: &MountPlan, unit_name: &str) -> Result<()> {
+    find_systemd_unit_file(&plan.target_root, unit_name)?;
+
+    let system_dir = plan.target_root.join("etc/systemd/system");
+    let entries = match std::fs::read_dir(&system_dir) {
+        Ok(entries) => entries,
+        // Nothing has ever been enabled.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", system_dir.display()));
+        }
+    };
+
+    for entry in entries {
+        let dir = entry?.path();
+        let is_dep_dir = dir
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ext == "wants" || ext == "requires");
+        if !is_dep_dir {
+            continue;
+        }
+
+        let link = dir.join(unit_name);
+        if std::fs::symlink_metadata(&link).is_ok() {
+            std::fs::remove_file(&link)
+                .with_context(|| format!("Failed to remove {}", link.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Mask `unit_name` by pointing `/etc/systemd/system/<unit>` at `/dev/null`.
+///
+/// Unlike enable/disable this does not require the unit to exist in the payload.
+pub fn mask_systemd_unit(plan: &MountPlan, unit_name: &str) -> Result<()> {
+    let system_dir = plan.target_root.join("etc/systemd/system");
+    std::fs::create_dir_all(&system_dir)
+        .with_context(|| format!("Failed to create {}", system_dir.display()))?;
+
+    let link_path = system_dir.join(unit_name);
+    match std::fs::symlink_metadata(&link_path) {
+        Ok(meta) if meta.is_dir() => {
+            return Err(anyhow!("Cannot mask {unit_name}: {} is a directory", link_path.display()));
+        }
+        Ok(_) => std::fs::remove_file(&link_path)
+            .with_context(|| format!("Failed to remove {}", link_path.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to stat {}", link_path.display())),
+    }
+
+    #[cfg(unix)]
+    {
+        unix_fs::symlink("/dev/null", &link_path).with_context(|| {
+            format!("Failed to create symlink {} -> /dev/null", link_path.display())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Point `/etc/systemd/system/default.target` at `target` (e.g. `graphical.target`), which
+/// must exist in the payload.
+pub fn set_default_target(plan: &MountPlan, target: &str) -> Result<()> {
+    validate_target_name(target)?;
+    let unit_src = find_systemd_unit_file(&plan.target_root, target)?;
+    let link_target = path_in_target_root(&plan.target_root, &unit_src)?;
+
+    let system_dir = plan.target_root.join("etc/systemd/system");
+    std::fs::create_dir_all(&system_dir)
+        .with_context(|| format!("Failed to create {}", system_dir.display()))?;
+
+    let link_path = system_dir.join("default.target");
+    match std::fs::symlink_metadata(&link_path) {
+        Ok(_) => std::fs::remove_file(&link_path)
+            .with_context(|| format!("Failed to remove {}", link_path.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to stat {}", link_path.display())),
+    }
+
+    #[cfg(unix)]
+    {
+        unix_fs::symlink(&link_target, &link_path).with_context(|| {
+            format!("Failed to create symlink {} -> {}", link_path.display(), link_target)
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Like [`validate_unit_name`], restricted to `.target` units.
+pub fn validate_target_name(target: &str) -> Result<()> {
+    validate_unit_name(target)?;
+    if !target.ends_with(".target") {
+        return Err(anyhow!("Invalid default target '{target}' (expected a .target unit)"));
+    }
+    Ok(())
+}
+
+/// Reject anything that is not a plain unit file name (e.g. paths or missing suffixes).
+pub fn validate_unit_name(unit_name: &str) -> Result<()> {
+    const SUFFIXES: [&str; 8] =
+        [".service", ".socket", ".timer", ".target", ".mount", ".path", ".slice", ".swap"];
+
+    if unit_name.contains('/') || unit_name.starts_with('.') {
+        return Err(anyhow!("Invalid unit name '{unit_name}'"));
+    }
+    if !SUFFIXES.iter().any(|suffix| unit_name.ends_with(suffix)) {
+        return Err(anyhow!(
+            "Invalid unit name '{unit_name}' (expected a suffix like .service, .socket, or .timer)"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn make_plan(root: &Path) -> MountPlan {
+        MountPlan { target_root: root.to_path_buf(), ..MountPlan::default() }
+    }
+
+    fn add_unit(root: &Path, unit_name: &str) {
+        let dir = root.join("lib/systemd/system");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(unit_name), "[Unit]\n").unwrap();
+    }
+
+    #[test]
+    fn enable_creates_wants_symlink() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        add_unit(root, "cockpit.socket");
+
+        enable_systemd_unit(&make_plan(root), "cockpit.socket").unwrap();
+
+        let link = root.join("etc/systemd/system/multi-user.target.wants/cockpit.socket");
+        assert_eq!(
+            fs::read_link(link).unwrap(),
+            PathBuf::from("/lib/systemd/system/cockpit.socket")
+        );
+    }
+
+    #[test]
+    fn enable_keeps_existing_absolute_symlink() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        add_unit(root, "ssh.service");
+        let wants = root.join("etc/systemd/system/multi-user.target.wants");
+        fs::create_dir_all(&wants).unwrap();
+        unix_fs::symlink("/lib/systemd/system/ssh.service", wants.join("ssh.service")).unwrap();
+
+        enable_systemd_unit(&make_plan(root), "ssh.service").unwrap();
+    }
+
+    #[test]
+    fn enable_missing_unit_is_error() {
+        let temp = tempfile::tempdir().unwrap();
+        assert!(enable_systemd_unit(&make_plan(temp.path()), "nope.service").is_err());
+    }
+
+    #[test]
+    fn disable_removes_wants_symlinks() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        add_unit(root, "foo.service");
+        let plan = make_plan(root);
+        enable_systemd_unit(&plan, "foo.service").unwrap();
+        let other = root.join("etc/systemd/system/network-online.target.wants");
+        fs::create_dir_all(&other).unwrap();
+        unix_fs::symlink("/lib/systemd/system/foo.service", other.join("foo.service")).unwrap();
+
+        disable_systemd_unit(&plan, "foo.service").unwrap();
+
+        let multi_user = root.join("etc/systemd/system/multi-user.target.wants/foo.service");
+        assert!(fs::symlink_metadata(multi_user).is_err());
+        assert!(fs::symlink_metadata(other.join("foo.service")).is_err());
+    }
+
+    #[test]
+    fn mask_points_unit_at_dev_null_without_unit_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let plan = make_plan(root);
+
+        mask_systemd_unit(&plan, "systemd-networkd-wait-online.service").unwrap();
+        // Masking twice replaces the existing link.
+        mask_systemd_unit(&plan, "systemd-networkd-wait-online.service").unwrap();
+
+        let link = root.join("etc/systemd/system/systemd-networkd-wait-online.service");
+        assert_eq!(fs::read_link(link).unwrap(), PathBuf::from("/dev/null"));
+    }
+
+    #[test]
+    fn default_target_replaces_existing_link() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        add_unit(root, "graphical.target");
+        let system = root.join("etc/systemd/system");
+        fs::create_dir_all(&system).unwrap();
+        unix_fs::symlink("/lib/systemd/system/multi-user.target", system.join("default.target"))
+            .unwrap();
+
+        let plan = make_plan(root);
+        set_default_target(&plan, "graphical.target").unwrap();
+        assert_eq!(
+            fs::read_link(system.join("default.target")).unwrap(),
+            PathBuf::from("/lib/systemd/system/graphical.target")
+        );
+
+        assert!(set_default_target(&plan, "rescue.target").is_err());
+        assert!(set_default_target(&plan, "ssh.service").is_err());
+    }
+
+    #[test]
+    fn unit_name_validation() {
+        assert!(validate_unit_name("cockpit.socket").is_ok());
+        assert!(validate_unit_name("getty@tty1.service").is_ok());
+        assert!(validate_unit_name("../etc/passwd.service").is_err());
+        assert!(validate_unit_name("cockpit").is_err());
+    }
+}
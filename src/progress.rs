@@ -0,0 +1,231 @@
+//! Progress reporting
+//!
+//! Install steps report what they are doing through [`ProgressReporter`] instead of printing
+//! directly, so the console, the `--status-port` endpoint and (unattended) the kernel log are
+//! fed from the same calls.
+
+use crate::console;
+use crate::platform::resume::InstallStep;
+use crate::status::StatusBoard;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Kernel log device; what is written here shows up on the serial console and in `dmesg`.
+pub const KMSG_PATH: &str = "/dev/kmsg";
+
+pub trait ProgressReporter: std::fmt::Debug {
+    /// A new install phase has started.
+    fn step(&self, step: InstallStep);
+    /// What the current phase is doing right now.
+    fn message(&self, msg: &str);
+    /// How far the current phase has got, from 0.0 to 1.0. Only reported by phases that can
+    /// tell.
+    fn sub_progress(&self, frac: f32);
+}
+
+/// `[..]` lines on stdout; sub-progress redraws a progress bar in place.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConsoleReporter;
+
+impl ProgressReporter for ConsoleReporter {
+    fn step(&self, _step: InstallStep) {
+        // The messages that follow already say what is happening.
+    }
+
+    fn message(&self, msg: &str) {
+        println!("[..] {msg}");
+        let _ = std::io::stdout().flush();
+    }
+
+    fn sub_progress(&self, frac: f32) {
+        let percent = percent(frac);
+        print!("\r[..]   {}", console::progress_bar(percent, console::PROGRESS_BAR_CELLS));
+        if percent == 100 {
+            println!();
+        }
+        let _ = std::io::stdout().flush();
+    }
+}
+
+impl ProgressReporter for StatusBoard {
+    fn step(&self, step: InstallStep) {
+        self.update(|status| {
+            status.step = Some(step);
+            status.message = None;
+            status.percent = None;
+        });
+    }
+
+    fn message(&self, msg: &str) {
+        self.update(|status| status.message = Some(msg.to_string()));
+    }
+
+    fn sub_progress(&self, frac: f32) {
+        self.update(|status| status.percent = Some(percent(frac)));
+    }
+}
+
+/// One `truthdb-installer: ...` kernel log record per report, for unattended installs that
+/// nobody watches on the local console. Sub-progress is only logged every 25% to keep the log
+/// readable.
+#[derive(Debug)]
+pub struct KmsgReporter {
+    kmsg: File,
+    last_quarter: AtomicU8,
+}
+
+impl KmsgReporter {
+    /// `None` when `path` cannot be opened for writing (not root, no `/dev/kmsg`); the
+    /// kernel log is a convenience, never a reason to stop.
+    pub fn open(path: &Path) -> Option<Self> {
+        let kmsg = OpenOptions::new().append(true).open(path).ok()?;
+        Some(KmsgReporter { kmsg, last_quarter: AtomicU8::new(0) })
+    }
+
+    fn log(&self, line: &str) {
+        // Each write() is one record; `<6>` is KERN_INFO.
+        let _ = (&self.kmsg).write_all(format!("<6>truthdb-installer: {line}\n").as_bytes());
+    }
+}
+
+impl ProgressReporter for KmsgReporter {
+    fn step(&self, step: InstallStep) {
+        self.last_quarter.store(0, Ordering::Relaxed);
+        self.log(&format!("step {step}"));
+    }
+
+    fn message(&self, msg: &str) {
+        self.log(msg);
+    }
+
+    fn sub_progress(&self, frac: f32) {
+        let percent = percent(frac);
+        let quarter = percent / 25;
+        if quarter > self.last_quarter.swap(quarter, Ordering::Relaxed) {
+            self.log(&format!("{percent}%"));
+        }
+    }
+}
+
+/// Forwards every report to each reporter in turn.
+#[derive(Debug)]
+pub struct Fanout<'a>(pub Vec<&'a dyn ProgressReporter>);
+
+impl ProgressReporter for Fanout<'_> {
+    fn step(&self, step: InstallStep) {
+        self.0.iter().for_each(|reporter| reporter.step(step));
+    }
+
+    fn message(&self, msg: &str) {
+        self.0.iter().for_each(|reporter| reporter.message(msg));
+    }
+
+    fn sub_progress(&self, frac: f32) {
+        self.0.iter().for_each(|reporter| reporter.sub_progress(frac));
+    }
+}
+
+fn percent(frac: f32) -> u8 {
+    (frac.clamp(0.0, 1.0) * 100.0).round() as u8
+}
+
+#[cfg(test)]
+pub mod testing {
+    use super::ProgressReporter;
+    use crate::platform::resume::InstallStep;
+    use std::sync::Mutex;
+
+    /// Records reports as `step <name>`, `message <text>` and `progress <percent>` instead of
+    /// showing them.
+    #[derive(Debug, Default)]
+    pub struct RecordingReporter {
+        reports: Mutex<Vec<String>>,
+    }
+
+    impl RecordingReporter {
+        pub fn reports(&self) -> Vec<String> {
+            self.reports.lock().unwrap().clone()
+        }
+
+        fn record(&self, report: String) {
+            self.reports.lock().unwrap().push(report);
+        }
+    }
+
+    impl ProgressReporter for RecordingReporter {
+        fn step(&self, step: InstallStep) {
+            self.record(format!("step {step}"));
+        }
+
+        fn message(&self, msg: &str) {
+            self.record(format!("message {msg}"));
+        }
+
+        fn sub_progress(&self, frac: f32) {
+            self.record(format!("progress {}", super::percent(frac)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::RecordingReporter;
+    use super::*;
+
+    #[test]
+    fn fanout_feeds_every_reporter() {
+        let first = RecordingReporter::default();
+        let second = RecordingReporter::default();
+        let fanout = Fanout(vec![&first, &second]);
+        fanout.step(InstallStep::Extract);
+        fanout.message("Extracting");
+        fanout.sub_progress(0.426);
+        let expected = vec!["step extract", "message Extracting", "progress 43"];
+        assert_eq!(first.reports(), expected);
+        assert_eq!(second.reports(), expected);
+    }
+
+    #[test]
+    fn kmsg_gets_one_record_per_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kmsg");
+        std::fs::write(&path, "").unwrap();
+        let kmsg = KmsgReporter::open(&path).unwrap();
+        kmsg.step(InstallStep::Extract);
+        kmsg.message("Extracting rootfs");
+        for frac in [0.1, 0.26, 0.3, 0.5, 1.0] {
+            kmsg.sub_progress(frac);
+        }
+        kmsg.step(InstallStep::Boot);
+        kmsg.sub_progress(0.25);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "<6>truthdb-installer: step extract\n\
+             <6>truthdb-installer: Extracting rootfs\n\
+             <6>truthdb-installer: 26%\n\
+             <6>truthdb-installer: 50%\n\
+             <6>truthdb-installer: 100%\n\
+             <6>truthdb-installer: step boot\n\
+             <6>truthdb-installer: 25%\n"
+        );
+        assert!(KmsgReporter::open(&dir.path().join("missing/kmsg")).is_none());
+    }
+
+    #[test]
+    fn status_board_tracks_the_current_step() {
+        let board = StatusBoard::default();
+        board.message("Formatting");
+        board.sub_progress(1.5);
+        board.step(InstallStep::Extract);
+        board.sub_progress(0.25);
+        board.update(|status| {
+            assert_eq!(status.step, Some(InstallStep::Extract));
+            assert_eq!(status.message, None);
+            assert_eq!(status.percent, Some(25));
+        });
+        assert_eq!(percent(-1.0), 0);
+        assert_eq!(percent(1.5), 100);
+    }
+}
@@ -0,0 +1,200 @@
+//! Remote status endpoint
+//!
+//! `--status-port` lets a provisioning dashboard poll unattended installs. A hand-rolled
+//! HTTP/1.0 responder answers every request on the port with the current [`Status`] as JSON,
+//! whatever the path or method.
+
+use crate::platform::resume::InstallStep;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often the accept loop checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    #[default]
+    Starting,
+    SelectingDisk,
+    Confirming,
+    Installing,
+    Failed,
+    Cancelled,
+    Complete,
+}
+
+/// What the endpoint reports.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Status {
+    pub state: State,
+    pub disk: Option<String>,
+    /// Current install phase, serialized lowercase (`partition`, `format`, `extract`, ...).
+    pub step: Option<InstallStep>,
+    /// Latest progress message within the phase.
+    pub message: Option<String>,
+    /// Completion of the phase, for phases that can tell (payload extraction).
+    pub percent: Option<u8>,
+    /// Install attempts so far, counting retries.
+    pub attempt: u32,
+    pub error: Option<String>,
+}
+
+/// Shared, always-current status; cheap to clone into the server thread.
+#[derive(Debug, Clone)]
+pub struct StatusBoard {
+    status: Arc<Mutex<Status>>,
+    started: Instant,
+}
+
+impl Default for StatusBoard {
+    fn default() -> Self {
+        Self { status: Arc::default(), started: Instant::now() }
+    }
+}
+
+impl StatusBoard {
+    pub fn update(&self, f: impl FnOnce(&mut Status)) {
+        if let Ok(mut status) = self.status.lock() {
+            f(&mut status);
+        }
+    }
+
+    pub fn set_state(&self, state: State) {
+        self.update(|status| status.state = state);
+    }
+
+    fn to_json(&self) -> String {
+        let status = self.status.lock().map(|status| status.clone()).unwrap_or_default();
+        let mut value = serde_json::to_value(status).unwrap_or_default();
+        value["elapsed_secs"] = self.started.elapsed().as_secs().into();
+        value.to_string()
+    }
+}
+
+/// The running endpoint; dropping it stops the server thread.
+#[derive(Debug)]
+pub struct StatusServer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for StatusServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Serve `board` on `port` (all interfaces) until the returned server is dropped.
+pub fn serve(port: u16, board: StatusBoard) -> Result<StatusServer> {
+    let listener = TcpListener::bind(("0.0.0.0", port))
+        .with_context(|| format!("Failed to listen on port {port}"))?;
+    listener.set_nonblocking(true).context("Failed to configure the status listener")?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let stop = Arc::clone(&stop);
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let _ = respond(stream, &board.to_json());
+                    }
+                    Err(_) => std::thread::sleep(POLL_INTERVAL),
+                }
+            }
+        })
+    };
+    Ok(StatusServer { stop, thread: Some(thread) })
+}
+
+fn respond(mut stream: TcpStream, body: &str) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    // The request itself doesn't matter; read what arrived so the client sees a clean close.
+    let mut request = [0u8; 1024];
+    let _ = stream.read(&mut request);
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// Whether any non-loopback interface under `net_class` (`/sys/class/net`) reports `up`.
+pub fn network_link_up(net_class: &Path) -> bool {
+    let Ok(entries) = std::fs::read_dir(net_class) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        entry.file_name() != "lo"
+            && std::fs::read_to_string(entry.path().join("operstate"))
+                .is_ok_and(|state| state.trim() == "up")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_current_status_as_json() {
+        let board = StatusBoard::default();
+        board.update(|status| {
+            status.state = State::Installing;
+            status.disk = Some("/dev/vda".into());
+            status.step = Some(InstallStep::Extract);
+            status.attempt = 2;
+        });
+        // Find a free port, then hand it to the server.
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let _server = serve(port, board.clone()).unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(b"GET /status HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.0 200 OK"));
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(json["state"], "installing");
+        assert_eq!(json["disk"], "/dev/vda");
+        assert_eq!(json["step"], "extract");
+        assert_eq!(json["attempt"], 2);
+        assert!(json["percent"].is_null());
+        assert!(json["error"].is_null());
+        assert!(json["elapsed_secs"].is_u64());
+    }
+
+    #[test]
+    fn link_detection_ignores_loopback() {
+        let dir = tempfile::tempdir().unwrap();
+        let write_state = |name: &str, state: &str| {
+            std::fs::create_dir_all(dir.path().join(name)).unwrap();
+            std::fs::write(dir.path().join(name).join("operstate"), state).unwrap();
+        };
+        write_state("lo", "unknown\n");
+        write_state("eth0", "down\n");
+        assert!(!network_link_up(dir.path()));
+        write_state("eth1", "up\n");
+        assert!(network_link_up(dir.path()));
+        assert!(!network_link_up(&dir.path().join("missing")));
+    }
+}